@@ -0,0 +1,113 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum ContentCategory {
+    #[serde(rename = "NONE")]
+    None,
+    #[serde(rename = "LANDSCAPES")]
+    Landscapes,
+    #[serde(rename = "RECEIPTS")]
+    Receipts,
+    #[serde(rename = "CITYSCAPES")]
+    Cityscapes,
+    #[serde(rename = "LANDMARKS")]
+    Landmarks,
+    #[serde(rename = "SELFIES")]
+    Selfies,
+    #[serde(rename = "PEOPLE")]
+    People,
+    #[serde(rename = "PETS")]
+    Pets,
+    #[serde(rename = "WEDDINGS")]
+    Weddings,
+    #[serde(rename = "BIRTHDAYS")]
+    Birthdays,
+    #[serde(rename = "DOCUMENTS")]
+    Documents,
+    #[serde(rename = "TRAVEL")]
+    Travel,
+    #[serde(rename = "ANIMALS")]
+    Animals,
+    #[serde(rename = "FOOD")]
+    Food,
+    #[serde(rename = "SPORT")]
+    Sport,
+    #[serde(rename = "NIGHT")]
+    Night,
+    #[serde(rename = "PERFORMANCES")]
+    Performances,
+    #[serde(rename = "WHITEBOARDS")]
+    Whiteboards,
+    #[serde(rename = "SCREENSHOTS")]
+    Screenshots,
+    #[serde(rename = "UTILITY")]
+    Utility,
+    #[serde(rename = "ARTS")]
+    Arts,
+    #[serde(rename = "CRAFTS")]
+    Crafts,
+    #[serde(rename = "FASHION")]
+    Fashion,
+    #[serde(rename = "HOUSES")]
+    Houses,
+    #[serde(rename = "GARDENS")]
+    Gardens,
+    #[serde(rename = "FLOWERS")]
+    Flowers,
+    #[serde(rename = "HOLIDAYS")]
+    Holidays,
+
+}
+
+impl std::fmt::Display for ContentCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "NONE"),
+            Self::Landscapes => write!(f, "LANDSCAPES"),
+            Self::Receipts => write!(f, "RECEIPTS"),
+            Self::Cityscapes => write!(f, "CITYSCAPES"),
+            Self::Landmarks => write!(f, "LANDMARKS"),
+            Self::Selfies => write!(f, "SELFIES"),
+            Self::People => write!(f, "PEOPLE"),
+            Self::Pets => write!(f, "PETS"),
+            Self::Weddings => write!(f, "WEDDINGS"),
+            Self::Birthdays => write!(f, "BIRTHDAYS"),
+            Self::Documents => write!(f, "DOCUMENTS"),
+            Self::Travel => write!(f, "TRAVEL"),
+            Self::Animals => write!(f, "ANIMALS"),
+            Self::Food => write!(f, "FOOD"),
+            Self::Sport => write!(f, "SPORT"),
+            Self::Night => write!(f, "NIGHT"),
+            Self::Performances => write!(f, "PERFORMANCES"),
+            Self::Whiteboards => write!(f, "WHITEBOARDS"),
+            Self::Screenshots => write!(f, "SCREENSHOTS"),
+            Self::Utility => write!(f, "UTILITY"),
+            Self::Arts => write!(f, "ARTS"),
+            Self::Crafts => write!(f, "CRAFTS"),
+            Self::Fashion => write!(f, "FASHION"),
+            Self::Houses => write!(f, "HOUSES"),
+            Self::Gardens => write!(f, "GARDENS"),
+            Self::Flowers => write!(f, "FLOWERS"),
+            Self::Holidays => write!(f, "HOLIDAYS"),
+        }
+    }
+}
+
+impl Default for ContentCategory {
+    fn default() -> ContentCategory {
+        Self::None
+    }
+}
+
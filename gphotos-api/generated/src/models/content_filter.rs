@@ -0,0 +1,32 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// ContentFilter : Matches media items by what is in them, as classified by Google Photos.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ContentFilter {
+    #[serde(rename = "includedContentCategories", skip_serializing_if = "Option::is_none")]
+    pub included_content_categories: Option<Vec<models::ContentCategory>>,
+    #[serde(rename = "excludedContentCategories", skip_serializing_if = "Option::is_none")]
+    pub excluded_content_categories: Option<Vec<models::ContentCategory>>,
+}
+
+impl ContentFilter {
+    /// Matches media items by what is in them, as classified by Google Photos.
+    pub fn new() -> ContentFilter {
+        ContentFilter {
+            included_content_categories: None,
+            excluded_content_categories: None,
+        }
+    }
+}
+
@@ -0,0 +1,38 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// Date : A full or partial date. A zero year, month or day matches any.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Date {
+    /// Year, from 1 to 9999, or 0 for any year.
+    #[serde(rename = "year", skip_serializing_if = "Option::is_none")]
+    pub year: Option<i32>,
+    /// Month, from 1 to 12, or 0 for any month.
+    #[serde(rename = "month", skip_serializing_if = "Option::is_none")]
+    pub month: Option<i32>,
+    /// Day of the month, from 1 to 31, or 0 for any day.
+    #[serde(rename = "day", skip_serializing_if = "Option::is_none")]
+    pub day: Option<i32>,
+}
+
+impl Date {
+    /// A full or partial date. A zero year, month or day matches any.
+    pub fn new() -> Date {
+        Date {
+            year: None,
+            month: None,
+            day: None,
+        }
+    }
+}
+
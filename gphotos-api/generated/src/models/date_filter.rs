@@ -0,0 +1,32 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// DateFilter : Matches media items created on any of the dates or in any of the ranges, at most 5 of each.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DateFilter {
+    #[serde(rename = "dates", skip_serializing_if = "Option::is_none")]
+    pub dates: Option<Vec<models::Date>>,
+    #[serde(rename = "ranges", skip_serializing_if = "Option::is_none")]
+    pub ranges: Option<Vec<models::DateRange>>,
+}
+
+impl DateFilter {
+    /// Matches media items created on any of the dates or in any of the ranges, at most 5 of each.
+    pub fn new() -> DateFilter {
+        DateFilter {
+            dates: None,
+            ranges: None,
+        }
+    }
+}
+
@@ -0,0 +1,32 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// DateRange : Range of dates, both ends included.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DateRange {
+    #[serde(rename = "startDate")]
+    pub start_date: Box<models::Date>,
+    #[serde(rename = "endDate")]
+    pub end_date: Box<models::Date>,
+}
+
+impl DateRange {
+    /// Range of dates, both ends included.
+    pub fn new(start_date: Box<models::Date>, end_date: Box<models::Date>) -> DateRange {
+        DateRange {
+            start_date,
+            end_date,
+        }
+    }
+}
+
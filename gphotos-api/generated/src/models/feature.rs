@@ -0,0 +1,38 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum Feature {
+    #[serde(rename = "NONE")]
+    None,
+    #[serde(rename = "FAVORITES")]
+    Favorites,
+
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "NONE"),
+            Self::Favorites => write!(f, "FAVORITES"),
+        }
+    }
+}
+
+impl Default for Feature {
+    fn default() -> Feature {
+        Self::None
+    }
+}
+
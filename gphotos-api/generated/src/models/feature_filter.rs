@@ -0,0 +1,29 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// FeatureFilter : Matches media items with the given features.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeatureFilter {
+    #[serde(rename = "includedFeatures", skip_serializing_if = "Option::is_none")]
+    pub included_features: Option<Vec<models::Feature>>,
+}
+
+impl FeatureFilter {
+    /// Matches media items with the given features.
+    pub fn new() -> FeatureFilter {
+        FeatureFilter {
+            included_features: None,
+        }
+    }
+}
+
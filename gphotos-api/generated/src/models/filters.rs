@@ -0,0 +1,46 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// Filters : Filters for searching the library. Can't be set together with an album ID.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Filters {
+    #[serde(rename = "dateFilter", skip_serializing_if = "Option::is_none")]
+    pub date_filter: Option<Box<models::DateFilter>>,
+    #[serde(rename = "contentFilter", skip_serializing_if = "Option::is_none")]
+    pub content_filter: Option<Box<models::ContentFilter>>,
+    #[serde(rename = "mediaTypeFilter", skip_serializing_if = "Option::is_none")]
+    pub media_type_filter: Option<Box<models::MediaTypeFilter>>,
+    #[serde(rename = "featureFilter", skip_serializing_if = "Option::is_none")]
+    pub feature_filter: Option<Box<models::FeatureFilter>>,
+    /// Include archived media items, which are left out by default.
+    #[serde(rename = "includeArchivedMedia", skip_serializing_if = "Option::is_none")]
+    pub include_archived_media: Option<bool>,
+    /// Only include media items created by this app.
+    #[serde(rename = "excludeNonAppCreatedData", skip_serializing_if = "Option::is_none")]
+    pub exclude_non_app_created_data: Option<bool>,
+}
+
+impl Filters {
+    /// Filters for searching the library. Can't be set together with an album ID.
+    pub fn new() -> Filters {
+        Filters {
+            date_filter: None,
+            content_filter: None,
+            media_type_filter: None,
+            feature_filter: None,
+            include_archived_media: None,
+            exclude_non_app_created_data: None,
+        }
+    }
+}
+
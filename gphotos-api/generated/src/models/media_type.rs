@@ -0,0 +1,41 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum MediaType {
+    #[serde(rename = "ALL_MEDIA")]
+    AllMedia,
+    #[serde(rename = "VIDEO")]
+    Video,
+    #[serde(rename = "PHOTO")]
+    Photo,
+
+}
+
+impl std::fmt::Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::AllMedia => write!(f, "ALL_MEDIA"),
+            Self::Video => write!(f, "VIDEO"),
+            Self::Photo => write!(f, "PHOTO"),
+        }
+    }
+}
+
+impl Default for MediaType {
+    fn default() -> MediaType {
+        Self::AllMedia
+    }
+}
+
@@ -0,0 +1,29 @@
+/*
+ * Google Photos API
+ *
+ * API for accessing Google Photos functionalities.
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+use crate::models;
+use serde::{Deserialize, Serialize};
+
+/// MediaTypeFilter : Matches media items of the given type. Only one type can be given.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MediaTypeFilter {
+    #[serde(rename = "mediaTypes", skip_serializing_if = "Option::is_none")]
+    pub media_types: Option<Vec<models::MediaType>>,
+}
+
+impl MediaTypeFilter {
+    /// Matches media items of the given type. Only one type can be given.
+    pub fn new() -> MediaTypeFilter {
+        MediaTypeFilter {
+            media_types: None,
+        }
+    }
+}
+
@@ -2,6 +2,22 @@ pub mod album;
 pub use self::album::Album;
 pub mod album_shared_album_options;
 pub use self::album_shared_album_options::AlbumSharedAlbumOptions;
+pub mod content_category;
+pub use self::content_category::ContentCategory;
+pub mod content_filter;
+pub use self::content_filter::ContentFilter;
+pub mod date;
+pub use self::date::Date;
+pub mod date_filter;
+pub use self::date_filter::DateFilter;
+pub mod date_range;
+pub use self::date_range::DateRange;
+pub mod feature;
+pub use self::feature::Feature;
+pub mod feature_filter;
+pub use self::feature_filter::FeatureFilter;
+pub mod filters;
+pub use self::filters::Filters;
 pub mod list_albums_response;
 pub use self::list_albums_response::ListAlbumsResponse;
 pub mod list_media_items_response;
@@ -18,5 +34,9 @@ pub mod media_item_media_metadata_photo;
 pub use self::media_item_media_metadata_photo::MediaItemMediaMetadataPhoto;
 pub mod media_item_media_metadata_video;
 pub use self::media_item_media_metadata_video::MediaItemMediaMetadataVideo;
+pub mod media_type;
+pub use self::media_type::MediaType;
+pub mod media_type_filter;
+pub use self::media_type_filter::MediaTypeFilter;
 pub mod search_media_items_request;
 pub use self::search_media_items_request::SearchMediaItemsRequest;
@@ -13,9 +13,11 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SearchMediaItemsRequest {
-    /// ID of the album to search media items in.
-    #[serde(rename = "albumId")]
-    pub album_id: String,
+    /// ID of the album to search media items in. Can't be set together with filters.
+    #[serde(rename = "albumId", skip_serializing_if = "Option::is_none")]
+    pub album_id: Option<String>,
+    #[serde(rename = "filters", skip_serializing_if = "Option::is_none")]
+    pub filters: Option<Box<models::Filters>>,
     /// Maximum number of media items to return.
     #[serde(rename = "pageSize", skip_serializing_if = "Option::is_none")]
     pub page_size: Option<i32>,
@@ -25,9 +27,10 @@ pub struct SearchMediaItemsRequest {
 }
 
 impl SearchMediaItemsRequest {
-    pub fn new(album_id: String) -> SearchMediaItemsRequest {
+    pub fn new() -> SearchMediaItemsRequest {
         SearchMediaItemsRequest {
-            album_id,
+            album_id: None,
+            filters: None,
             page_size: None,
             page_token: None,
         }
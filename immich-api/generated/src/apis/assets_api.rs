@@ -675,7 +675,7 @@ pub async fn play_asset_video(
 pub async fn replace_asset(
     configuration: &configuration::Configuration,
     id: &str,
-    _asset_data: std::path::PathBuf,
+    asset_data: reqwest::multipart::Part,
     device_asset_id: &str,
     device_id: &str,
     file_created_at: String,
@@ -722,6 +722,7 @@ pub async fn replace_asset(
     }
     local_var_form = local_var_form.text("fileCreatedAt", file_created_at.to_string());
     local_var_form = local_var_form.text("fileModifiedAt", file_modified_at.to_string());
+    local_var_form = local_var_form.part("assetData", asset_data);
     local_var_req_builder = local_var_req_builder.multipart(local_var_form);
 
     let local_var_req = local_var_req_builder.build()?;
@@ -1046,7 +1047,7 @@ pub async fn view_asset(
     id: &str,
     key: Option<&str>,
     size: Option<models::AssetMediaSize>,
-) -> Result<std::path::PathBuf, Error<ViewAssetError>> {
+) -> Result<bytes::Bytes, Error<ViewAssetError>> {
     let local_var_configuration = configuration;
 
     let local_var_client = &local_var_configuration.client;
@@ -1086,11 +1087,11 @@ pub async fn view_asset(
     let local_var_resp = local_var_client.execute(local_var_req).await?;
 
     let local_var_status = local_var_resp.status();
-    let local_var_content = local_var_resp.text().await?;
 
     if !local_var_status.is_client_error() && !local_var_status.is_server_error() {
-        serde_json::from_str(&local_var_content).map_err(Error::from)
+        Ok(local_var_resp.bytes().await?)
     } else {
+        let local_var_content = local_var_resp.text().await?;
         let local_var_entity: Option<ViewAssetError> =
             serde_json::from_str(&local_var_content).ok();
         let local_var_error = ResponseContent {
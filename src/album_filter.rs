@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use gphotos_api::models::Album;
+use regex::Regex;
+use std::collections::HashSet;
+
+// Picks which gphoto albums to sync when going through all shared or own albums. An album is
+// synced if it matches one of the includes (or there are none) and none of the excludes.
+#[derive(Debug, Default)]
+pub struct AlbumFilter {
+    include_title: Option<Regex>,
+    exclude_title: Option<Regex>,
+    include_ids: HashSet<String>,
+    exclude_ids: HashSet<String>,
+}
+
+impl AlbumFilter {
+    pub fn new(
+        include_title: Option<&str>,
+        exclude_title: Option<&str>,
+        include_ids: &[String],
+        exclude_ids: &[String],
+    ) -> Result<Self> {
+        let parse = |re: Option<&str>| {
+            re.map(|re| Regex::new(re).with_context(|| format!("bad album title regex {re:?}")))
+                .transpose()
+        };
+        Ok(AlbumFilter {
+            include_title: parse(include_title)?,
+            exclude_title: parse(exclude_title)?,
+            include_ids: include_ids.iter().cloned().collect(),
+            exclude_ids: exclude_ids.iter().cloned().collect(),
+        })
+    }
+
+    pub fn matches(&self, album: &Album) -> bool {
+        let id = album.id.as_deref().unwrap_or_default();
+        let title = album.title.as_deref().unwrap_or_default();
+        if self.exclude_ids.contains(id)
+            || self
+                .exclude_title
+                .as_ref()
+                .is_some_and(|re| re.is_match(title))
+        {
+            return false;
+        }
+        if self.include_title.is_none() && self.include_ids.is_empty() {
+            return true;
+        }
+        self.include_ids.contains(id)
+            || self
+                .include_title
+                .as_ref()
+                .is_some_and(|re| re.is_match(title))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn album(id: &str, title: &str) -> Album {
+        Album {
+            id: Some(id.to_string()),
+            title: Some(title.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let all = AlbumFilter::default();
+        assert!(all.matches(&album("a", "Holidays 2019")));
+
+        let filter = AlbumFilter::new(
+            Some("^Holidays"),
+            Some("(?i)draft"),
+            &["b".to_string()],
+            &["c".to_string()],
+        )
+        .unwrap();
+        assert!(filter.matches(&album("a", "Holidays 2019")));
+        assert!(filter.matches(&album("b", "Birthday")));
+        assert!(!filter.matches(&album("a", "Birthday")));
+        assert!(!filter.matches(&album("a", "Holidays 2020 (Draft)")));
+        assert!(!filter.matches(&album("c", "Holidays 2021")));
+
+        assert!(AlbumFilter::new(Some("("), None, &[], &[]).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::Result;
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::{HashMap, HashSet};
+
+use crate::now;
+use crate::types::{GPhotoAlbumId, GPhotoItemId, ImmichAlbumId, ImmichItemId};
+
+// Immich album memberships made by this tool, in the album_item_links table, with the gphoto
+// album and item they mirror. Removals only touch these, and comparing them with the immich
+// album shows what was changed there by hand.
+
+// Records that the assets were put into the immich album because of the gphoto items. Each pair
+// is (gphoto item, immich asset), a live photo has both of its gphoto items on the same asset.
+pub async fn save_album_item_links(
+    pool: &Pool<Sqlite>,
+    immich_album_id: &ImmichAlbumId,
+    gphoto_album_id: &GPhotoAlbumId,
+    links: &[(&GPhotoItemId, &ImmichItemId)],
+) -> Result<()> {
+    let now = now();
+    let mut tx = pool.begin().await?;
+    for (gphoto_id, immich_id) in links {
+        sqlx::query(
+            r#"
+INSERT OR IGNORE INTO album_item_links (immich_album_id, immich_id, gphoto_album_id, gphoto_id, insert_time)
+VALUES ($1, $2, $3, $4, $5)"#,
+        )
+        .bind(&immich_album_id.0)
+        .bind(&immich_id.0)
+        .bind(&gphoto_album_id.0)
+        .bind(&gphoto_id.0)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// The assets this tool put into the immich album, with the gphoto items behind each.
+pub async fn load_album_item_links(
+    pool: &Pool<Sqlite>,
+    immich_album_id: &ImmichAlbumId,
+) -> Result<HashMap<ImmichItemId, HashSet<GPhotoItemId>>> {
+    let mut links: HashMap<_, HashSet<_>> = HashMap::new();
+    for row in sqlx::query(
+        r#"SELECT immich_id, gphoto_id FROM album_item_links WHERE immich_album_id = $1"#,
+    )
+    .bind(&immich_album_id.0)
+    .fetch_all(pool)
+    .await?
+    {
+        links
+            .entry(ImmichItemId(row.get("immich_id")))
+            .or_default()
+            .insert(GPhotoItemId(row.get("gphoto_id")));
+    }
+    Ok(links)
+}
+
+pub async fn delete_album_item_links(
+    pool: &Pool<Sqlite>,
+    immich_album_id: &ImmichAlbumId,
+    immich_id: &ImmichItemId,
+) -> Result<()> {
+    sqlx::query(r#"DELETE FROM album_item_links WHERE immich_album_id = $1 AND immich_id = $2"#)
+        .bind(&immich_album_id.0)
+        .bind(&immich_id.0)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Drift {
+    // Put into the album by this tool, no longer there.
+    pub removed_by_hand: Vec<ImmichItemId>,
+    // In the album, not put there by this tool.
+    pub added_by_hand: Vec<ImmichItemId>,
+}
+
+impl Drift {
+    pub fn is_empty(&self) -> bool {
+        self.removed_by_hand.is_empty() && self.added_by_hand.is_empty()
+    }
+}
+
+// Compares what this tool put into an immich album with what is in it now.
+pub fn find_drift<T>(
+    recorded: &HashMap<ImmichItemId, T>,
+    album_assets: &HashSet<ImmichItemId>,
+) -> Drift {
+    let mut drift = Drift {
+        removed_by_hand: recorded
+            .keys()
+            .filter(|id| !album_assets.contains(id))
+            .cloned()
+            .collect(),
+        added_by_hand: album_assets
+            .iter()
+            .filter(|id| !recorded.contains_key(id))
+            .cloned()
+            .collect(),
+    };
+    drift.removed_by_hand.sort_by(|a, b| a.0.cmp(&b.0));
+    drift.added_by_hand.sort_by(|a, b| a.0.cmp(&b.0));
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_pool;
+
+    fn immich(id: &str) -> ImmichItemId {
+        ImmichItemId(id.to_string())
+    }
+    fn gphoto(id: &str) -> GPhotoItemId {
+        GPhotoItemId(id.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_album_item_links() {
+        let pool = test_pool().await;
+
+        let album = ImmichAlbumId("album".to_string());
+        let gphoto_album = GPhotoAlbumId("gphoto-album".to_string());
+        let (still, video, other) = (gphoto("still"), gphoto("video"), gphoto("other"));
+        let (a, b) = (immich("a"), immich("b"));
+        save_album_item_links(
+            &pool,
+            &album,
+            &gphoto_album,
+            &[(&still, &a), (&video, &a), (&other, &b)],
+        )
+        .await
+        .unwrap();
+        // Adding the same items again changes nothing.
+        save_album_item_links(&pool, &album, &gphoto_album, &[(&other, &b)])
+            .await
+            .unwrap();
+        assert_eq!(
+            load_album_item_links(&pool, &album).await.unwrap(),
+            HashMap::from([
+                (a.clone(), HashSet::from([still.clone(), video.clone()])),
+                (b.clone(), HashSet::from([other.clone()])),
+            ])
+        );
+
+        delete_album_item_links(&pool, &album, &a).await.unwrap();
+        assert_eq!(
+            load_album_item_links(&pool, &album).await.unwrap(),
+            HashMap::from([(b, HashSet::from([other]))])
+        );
+        assert!(
+            load_album_item_links(&pool, &ImmichAlbumId("other".to_string()))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_find_drift() {
+        let recorded = HashMap::from([(immich("a"), ()), (immich("b"), ())]);
+        assert!(find_drift(&recorded, &HashSet::from([immich("a"), immich("b")])).is_empty());
+        assert_eq!(
+            find_drift(&recorded, &HashSet::from([immich("b"), immich("c")])),
+            Drift {
+                removed_by_hand: vec![immich("a")],
+                added_by_hand: vec![immich("c")],
+            }
+        );
+    }
+}
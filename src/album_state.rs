@@ -0,0 +1,114 @@
+use anyhow::Result;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use sqlx::{Pool, Row, Sqlite};
+
+use crate::now;
+use crate::types::{GPhotoAlbumId, GPhotoItemId};
+
+// What an album looked like the last time it was synced completely (all items linked and added
+// to the immich album), in the album_scan_state table. An album whose item count hasn't changed
+// since doesn't need to be listed again, and one whose items are the same needs no work. Only
+// the count is known without listing the album, so when as many items were removed as were added
+// the change goes unnoticed until the album is listed with --full-scan, items_hash only helps
+// once it is listed.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlbumState {
+    // As reported by gphoto, None if it didn't say.
+    pub media_items_count: Option<i64>,
+    pub items_hash: String,
+}
+
+impl AlbumState {
+    pub fn new<'a>(
+        media_items_count: Option<&String>,
+        items: impl IntoIterator<Item = &'a GPhotoItemId>,
+    ) -> Self {
+        AlbumState {
+            media_items_count: media_items_count.and_then(|c| c.parse().ok()),
+            items_hash: items_hash(items),
+        }
+    }
+}
+
+// Hash of the item IDs, independent of their order.
+pub fn items_hash<'a>(items: impl IntoIterator<Item = &'a GPhotoItemId>) -> String {
+    let mut ids: Vec<_> = items.into_iter().map(|id| id.0.as_str()).collect();
+    ids.sort_unstable();
+    let mut hasher = Sha1::new();
+    for id in ids {
+        hasher.input_str(id);
+        hasher.input(b"\n");
+    }
+    hasher.result_str()
+}
+
+pub async fn load_album_state(
+    pool: &Pool<Sqlite>,
+    gphoto_album_id: &GPhotoAlbumId,
+) -> Result<Option<AlbumState>> {
+    Ok(sqlx::query(
+        r#"SELECT media_items_count, items_hash FROM album_scan_state WHERE gphoto_album_id = $1"#,
+    )
+    .bind(&gphoto_album_id.0)
+    .fetch_optional(pool)
+    .await?
+    .map(|row| AlbumState {
+        media_items_count: row.get("media_items_count"),
+        items_hash: row.get("items_hash"),
+    }))
+}
+
+pub async fn save_album_state(
+    pool: &Pool<Sqlite>,
+    gphoto_album_id: &GPhotoAlbumId,
+    state: &AlbumState,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+INSERT OR REPLACE INTO album_scan_state (gphoto_album_id, media_items_count, items_hash, scan_time)
+VALUES ($1, $2, $3, $4)"#,
+    )
+    .bind(&gphoto_album_id.0)
+    .bind(state.media_items_count)
+    .bind(&state.items_hash)
+    .bind(now())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_pool;
+
+    fn ids(ids: &[&str]) -> Vec<GPhotoItemId> {
+        ids.iter().map(|id| GPhotoItemId(id.to_string())).collect()
+    }
+
+    #[test]
+    fn test_items_hash() {
+        assert_eq!(items_hash(&ids(&["a", "b"])), items_hash(&ids(&["b", "a"])));
+        assert_ne!(items_hash(&ids(&["a", "b"])), items_hash(&ids(&["ab"])));
+        assert_ne!(items_hash(&ids(&["a"])), items_hash(&ids(&[])));
+    }
+
+    #[tokio::test]
+    async fn test_album_state() {
+        let pool = test_pool().await;
+
+        let album = GPhotoAlbumId("album".to_string());
+        assert_eq!(load_album_state(&pool, &album).await.unwrap(), None);
+
+        let state = AlbumState::new(Some(&"2".to_string()), &ids(&["a", "b"]));
+        assert_eq!(state.media_items_count, Some(2));
+        save_album_state(&pool, &album, &state).await.unwrap();
+        assert_eq!(load_album_state(&pool, &album).await.unwrap(), Some(state));
+
+        let state = AlbumState::new(None, &ids(&["a"]));
+        save_album_state(&pool, &album, &state).await.unwrap();
+        assert_eq!(load_album_state(&pool, &album).await.unwrap(), Some(state));
+    }
+}
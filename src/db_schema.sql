@@ -13,9 +13,103 @@ CREATE TABLE IF NOT EXISTS "created_albums" (
 CREATE TABLE IF NOT EXISTS "item_item_links" (
    [gphoto_id] TEXT NOT NULL,
    [immich_id] TEXT NOT NULL,
-   [link_type] TEXT,  -- type of the link, see LookupResult
+   [link_type] TEXT,  -- type of the link, see LookupResult. MatchedChecksum: same SHA1 found on upload, LivePhotoVideo: hidden video of a live photo
    [insert_time] INTEGER,
    UNIQUE(gphoto_id),
    UNIQUE(immich_id),
    PRIMARY KEY (gphoto_id)
 ) STRICT;
+CREATE TABLE IF NOT EXISTS "takeout_backfills" (
+   [immich_id] TEXT PRIMARY KEY NOT NULL,
+   [gphoto_id] TEXT NOT NULL,
+   [takeout_id] TEXT NOT NULL,  -- id of the matching takeout item, see takeout.rs
+   [patched_fields] TEXT,  -- comma separated list of fields set on the immich asset
+   [insert_time] INTEGER
+) STRICT;
+CREATE TABLE IF NOT EXISTS "pending_uploads" (
+   [gphoto_id] TEXT PRIMARY KEY NOT NULL,
+   [media_item] TEXT NOT NULL,  -- gphoto MediaItem as json
+   [gphoto_album_ids] TEXT NOT NULL,  -- json list of gphoto albums the item goes into
+   [takeout_archive] TEXT,  -- set for items read from a takeout archive
+   [takeout_entry] TEXT,
+   [state] TEXT NOT NULL,  -- planned, downloading, uploaded, linked or failed, see UploadState
+   [immich_id] TEXT,  -- set once uploaded
+   [link_type] TEXT,  -- link type to record, see item_item_links. Set with immich_id once uploaded
+   [attempts] INTEGER NOT NULL DEFAULT 0,
+   [last_error] TEXT,
+   [insert_time] INTEGER,
+   [update_time] INTEGER
+) STRICT;
+CREATE TABLE IF NOT EXISTS "gphoto_quota" (
+   [client_id] TEXT NOT NULL,
+   [day] TEXT NOT NULL,  -- UTC date, YYYY-MM-DD
+   [api_requests] INTEGER NOT NULL DEFAULT 0,
+   [downloads] INTEGER NOT NULL DEFAULT 0,
+   PRIMARY KEY (client_id, day)
+) STRICT;
+CREATE TABLE IF NOT EXISTS "review_queue" (
+   [gphoto_id] TEXT PRIMARY KEY NOT NULL,
+   [lookup_result] TEXT NOT NULL,  -- why the item needs review, see LookupResult
+   [gphoto_metadata] TEXT NOT NULL,  -- gphoto MediaItem as json
+   [immich_metadata] TEXT NOT NULL,  -- json list of the candidate immich assets
+   [candidate_ids] TEXT NOT NULL,  -- json list of the candidate immich ids
+   [decision] TEXT,  -- link, create or skip, see ReviewDecision. NULL until reviewed
+   [decision_immich_id] TEXT,  -- candidate picked for link
+   [insert_time] INTEGER,
+   [decision_time] INTEGER
+) STRICT;
+CREATE TABLE IF NOT EXISTS "immich_phashes" (
+   [immich_id] TEXT PRIMARY KEY NOT NULL,
+   [phash] INTEGER NOT NULL,  -- dHash of the immich thumbnail, see phash.rs
+   [insert_time] INTEGER
+) STRICT;
+CREATE TABLE IF NOT EXISTS "immich_index" (
+   [immich_id] TEXT PRIMARY KEY NOT NULL,
+   [original_file_name] TEXT NOT NULL,
+   [checksum] TEXT NOT NULL,  -- hex SHA1, like the ones computed on upload
+   [taken_at] INTEGER,  -- unix time, from dateTimeOriginal or fileCreatedAt
+   [asset_type] TEXT NOT NULL,  -- IMAGE, VIDEO, ...
+   [asset] TEXT NOT NULL,  -- immich AssetResponseDto as json
+   [update_time] INTEGER
+) STRICT;
+CREATE INDEX IF NOT EXISTS immich_index_file_name ON immich_index (original_file_name COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS immich_index_checksum ON immich_index (checksum);
+CREATE INDEX IF NOT EXISTS immich_index_taken_at ON immich_index (taken_at);
+CREATE TABLE IF NOT EXISTS "immich_index_sync" (
+   [id] INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),  -- single row
+   [user_id] TEXT NOT NULL,
+   [synced_at] TEXT NOT NULL  -- RFC 3339, when the last (full or delta) sync started
+) STRICT;
+CREATE TABLE IF NOT EXISTS "lower_quality_copies" (
+   [immich_id] TEXT PRIMARY KEY NOT NULL,
+   [gphoto_id] TEXT NOT NULL,
+   [reason] TEXT NOT NULL,  -- why the immich copy is worse, e.g. a transcoded video download
+   [insert_time] INTEGER,
+   [replaced_time] INTEGER  -- NULL until replaced with the original
+) STRICT;
+CREATE TABLE IF NOT EXISTS "live_photo_links" (
+   [gphoto_video_id] TEXT PRIMARY KEY NOT NULL,
+   [gphoto_photo_id] TEXT NOT NULL,
+   [immich_id] TEXT NOT NULL,  -- the still, which has the video as its livePhotoVideoId
+   [insert_time] INTEGER
+) STRICT;
+CREATE TABLE IF NOT EXISTS "album_scan_state" (
+   [gphoto_album_id] TEXT PRIMARY KEY NOT NULL,
+   [media_items_count] INTEGER,  -- as reported by gphoto when the album was last synced completely
+   [items_hash] TEXT NOT NULL,  -- sha1 of the sorted gphoto item IDs
+   [scan_time] INTEGER
+) STRICT;
+CREATE TABLE IF NOT EXISTS "album_item_links" (
+   [immich_album_id] TEXT NOT NULL,
+   [immich_id] TEXT NOT NULL,
+   [gphoto_album_id] TEXT NOT NULL,
+   [gphoto_id] TEXT NOT NULL,  -- a live photo has both of its gphoto items on the same asset
+   [insert_time] INTEGER,  -- when this tool added the asset to the immich album
+   PRIMARY KEY (immich_album_id, immich_id, gphoto_id)
+) STRICT;
+CREATE TABLE IF NOT EXISTS "checksum_duplicates" (
+   [gphoto_id] TEXT PRIMARY KEY NOT NULL,
+   [immich_id] TEXT NOT NULL,  -- the asset with the same SHA1, linked to linked_gphoto_id in item_item_links
+   [linked_gphoto_id] TEXT NOT NULL,
+   [insert_time] INTEGER
+) STRICT;
@@ -1,18 +1,27 @@
+use crate::media_buffer::{MediaBuffers, StagedMedia};
+use crate::quota::{QuotaExhausted, QuotaKind, QuotaLedger};
+use crate::retry::{RetryPolicy, Retryable};
+use crate::throttle::Throttle;
 use crate::types::*;
 use anyhow::{anyhow, Context};
 use async_stream::try_stream;
-use bytes::Bytes;
+use chrono::Utc;
+use derive_more::Display;
 use futures_core::stream::Stream;
-use log::{debug, info};
+use log::{debug, info, warn};
 use oauth2::basic::BasicClient;
 use oauth2::reqwest;
 use oauth2::{
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, RedirectUrl,
     RefreshToken, RevocationUrl, Scope, StandardTokenResponse, TokenResponse, TokenUrl,
 };
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
 use std::fs;
+use std::future::Future;
 use std::io::{BufRead, BufReader, Write};
 use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time;
 use tokio::sync::Mutex;
@@ -64,7 +73,7 @@ impl AuthToken {
             // Following redirects opens the client up to SSRF vulnerabilities.
             .redirect(reqwest::redirect::Policy::none())
             .build()
-            .with_context(|| format!("Client should build"))?;
+            .with_context(|| "Client should build".to_string())?;
 
         let client = BasicClient::new(google_client_id)
             .set_token_uri(token_url)
@@ -73,7 +82,7 @@ impl AuthToken {
             .exchange_refresh_token(&RefreshToken::new(self.refresh_token.clone()))
             .request_async(&http_client)
             .await
-            .with_context(|| format!("refresh token failed"))?;
+            .with_context(|| "refresh token failed".to_string())?;
         debug!("refresh response: {:?}", refresh_r);
         self.token = refresh_r.access_token().secret().clone();
         self.expires_at = time::Instant::now()
@@ -85,60 +94,349 @@ impl AuthToken {
     }
 }
 
+fn read_client_id(client_secret: &str) -> anyhow::Result<String> {
+    let js = fs::read_to_string(client_secret)?;
+    Ok(serde_json::from_str::<InstalledJs>(&js)?
+        .installed
+        .client_id)
+}
+
+// How long a client is left alone after it kept getting 429s through all retries.
+const RATE_LIMIT_COOLDOWN: time::Duration = time::Duration::from_secs(60);
+
+// Why google answered with 429. Calls that go too fast are worth retrying after a while, a used
+// up daily quota only comes back the next day.
+#[derive(Debug, Display, Clone, Copy, PartialEq)]
+enum RateLimited {
+    #[display(fmt = "too many requests")]
+    TooManyRequests,
+    #[display(fmt = "daily quota is used up")]
+    DailyQuota,
+}
+impl std::error::Error for RateLimited {}
+
+fn rate_limited(status: u16, body: &str) -> Option<RateLimited> {
+    if status != 429 {
+        return None;
+    }
+    // The details name the limit that was hit, e.g. "...per day" or "...PerDay...".
+    if body.contains("RESOURCE_EXHAUSTED") && (body.contains("per day") || body.contains("PerDay"))
+    {
+        Some(RateLimited::DailyQuota)
+    } else {
+        Some(RateLimited::TooManyRequests)
+    }
+}
+
+fn api_rate_limited<T>(e: &gphotos_api::apis::Error<T>) -> Option<RateLimited> {
+    match e {
+        gphotos_api::apis::Error::ResponseError(r) => rate_limited(r.status.as_u16(), &r.content),
+        _ => None,
+    }
+}
+
+// One OAuth client (cloud project) with its own token and quota.
+struct Client {
+    client_id: String,
+    token: Mutex<AuthToken>,
+    quota: Option<QuotaLedger>,
+    // Kinds of calls this client is skipped for until the given time, after it kept getting 429s.
+    cooldown: std::sync::Mutex<HashMap<QuotaKind, time::Instant>>,
+    // Kinds of calls for which google said the daily quota is used up, with the UTC day.
+    exhausted: std::sync::Mutex<HashMap<QuotaKind, String>>,
+}
+
+// Google Photos API client. Can hold several OAuth clients, in which case calls go to the first
+// one that has quota left. Item and album IDs are the same across clients.
 #[derive(Clone)]
 pub struct GPClient {
-    token: Arc<Mutex<AuthToken>>,
+    clients: Arc<Vec<Client>>,
+    // Index of the client currently in use.
+    current: Arc<AtomicUsize>,
     api_config: gphotos_api::apis::configuration::Configuration,
+    retry: RetryPolicy,
+    // Limits for API calls and for media downloads.
+    listing: Throttle,
+    downloads: Throttle,
 }
 impl GPClient {
     pub async fn new_from_file(client_secret: &str, auth_file: &str) -> anyhow::Result<Self> {
-        // We only need the refresh token.
-        let saved_token: StandardTokenResponse<
-            oauth2::EmptyExtraTokenFields,
-            oauth2::basic::BasicTokenType,
-        > = serde_json::from_str(&(fs::read_to_string(auth_file)?))?;
-        let token = AuthToken::new(
-            saved_token
-                .refresh_token()
-                .ok_or(anyhow!("can't find refresh token"))?
-                .secret(),
-            client_secret,
-        );
-
-        let api_config = gphotos_api::apis::configuration::Configuration {
-            oauth_access_token: Some(token.token.clone()),
-            ..Default::default()
-        };
+        Self::new_from_files(&[(client_secret.to_string(), auth_file.to_string())]).await
+    }
+    // Takes (client secret, auth token) file pairs, one per OAuth client.
+    pub async fn new_from_files(files: &[(String, String)]) -> anyhow::Result<Self> {
+        if files.is_empty() {
+            return Err(anyhow!("need at least one gphoto client"));
+        }
+        let mut clients = vec![];
+        for (client_secret, auth_file) in files {
+            // We only need the refresh token.
+            let saved_token: StandardTokenResponse<
+                oauth2::EmptyExtraTokenFields,
+                oauth2::basic::BasicTokenType,
+            > = serde_json::from_str(
+                &(fs::read_to_string(auth_file)
+                    .with_context(|| format!("failed to read {auth_file}"))?),
+            )?;
+            let token = AuthToken::new(
+                saved_token
+                    .refresh_token()
+                    .ok_or(anyhow!("can't find refresh token in {auth_file}"))?
+                    .secret(),
+                client_secret,
+            );
+            clients.push(Client {
+                client_id: read_client_id(client_secret)
+                    .with_context(|| format!("failed to read {client_secret}"))?,
+                token: Mutex::new(token),
+                quota: None,
+                cooldown: Default::default(),
+                exhausted: Default::default(),
+            });
+        }
 
         Ok(GPClient {
-            token: Arc::new(Mutex::new(token)),
-            api_config,
+            clients: Arc::new(clients),
+            current: Arc::new(AtomicUsize::new(0)),
+            api_config: Default::default(),
+            retry: Default::default(),
+            listing: Throttle::new(10, None),
+            downloads: Throttle::new(10, None),
+        })
+    }
+    // Counts all calls against a per client quota ledger, clients that used up their budget are
+    // skipped. Must be called before the client is cloned.
+    pub async fn with_quota(
+        self,
+        pool: &Pool<Sqlite>,
+        max_api_requests: u64,
+        max_downloads: u64,
+        read_only: bool,
+    ) -> anyhow::Result<Self> {
+        let clients = Arc::try_unwrap(self.clients)
+            .map_err(|_| anyhow!("with_quota called on a shared client"))?;
+        let mut with_quota = vec![];
+        for mut client in clients {
+            client.quota = Some(
+                QuotaLedger::new(
+                    pool.clone(),
+                    &client.client_id,
+                    max_api_requests,
+                    max_downloads,
+                    read_only,
+                )
+                .await?,
+            );
+            with_quota.push(client);
+        }
+        Ok(GPClient {
+            clients: Arc::new(with_quota),
+            ..self
         })
     }
-    async fn get_config(&self) -> anyhow::Result<gphotos_api::apis::configuration::Configuration> {
-        let mut t = self.token.lock().await;
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        GPClient { retry, ..self }
+    }
+    pub fn with_throttles(self, listing: Throttle, downloads: Throttle) -> Self {
+        GPClient {
+            listing,
+            downloads,
+            ..self
+        }
+    }
+    pub fn quotas(&self) -> impl Iterator<Item = &QuotaLedger> {
+        self.clients.iter().filter_map(|c| c.quota.as_ref())
+    }
+    // Picks the client for the next call and counts the call against its quota. Starts with the
+    // current client and moves on to the next one when it's out of quota or cooling down. Waits
+    // for the first cooldown to end if that's all that is left.
+    async fn pick_client(&self, kind: QuotaKind) -> anyhow::Result<usize> {
+        loop {
+            let start = self.current.load(Ordering::Relaxed);
+            let n = self.clients.len();
+            let today = Utc::now().format("%Y-%m-%d").to_string();
+            let mut err = None;
+            let mut cooldown_end: Option<time::Instant> = None;
+            for i in 0..n {
+                let idx = (start + i) % n;
+                let client = &self.clients[idx];
+                if client.exhausted.lock().unwrap().get(&kind) == Some(&today) {
+                    continue;
+                }
+                if let Some(&end) = client.cooldown.lock().unwrap().get(&kind) {
+                    if end > time::Instant::now() {
+                        cooldown_end = Some(cooldown_end.map_or(end, |e| e.min(end)));
+                        continue;
+                    }
+                }
+                if let Some(quota) = client.quota.as_ref() {
+                    match quota.acquire(kind).await {
+                        Ok(()) => {}
+                        Err(e) if e.is::<QuotaExhausted>() => {
+                            err = Some(e);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                if idx != start {
+                    info!("switching to gphoto client {}", client.client_id);
+                    self.current.store(idx, Ordering::Relaxed);
+                }
+                return Ok(idx);
+            }
+            if let Some(end) = cooldown_end {
+                info!("all gphoto clients are rate limited for {kind}s, waiting");
+                tokio::time::sleep_until(end.into()).await;
+                continue;
+            }
+            return Err(err.unwrap_or(
+                QuotaExhausted {
+                    kind,
+                    client_id: self.clients[start].client_id.clone(),
+                }
+                .into(),
+            ));
+        }
+    }
+    // Counts a retry against the quota of the client picked for the first attempt, google counts
+    // every request.
+    async fn acquire_retry(&self, idx: usize, kind: QuotaKind) -> anyhow::Result<()> {
+        match self.clients[idx].quota.as_ref() {
+            Some(quota) => quota.acquire(kind).await,
+            None => Ok(()),
+        }
+    }
+    async fn set_rate_limited(&self, idx: usize, kind: QuotaKind, why: RateLimited) {
+        let client = &self.clients[idx];
+        warn!(
+            "gphoto client {} is rate limited for {kind}s: {why}",
+            client.client_id
+        );
+        match why {
+            RateLimited::TooManyRequests => {
+                client
+                    .cooldown
+                    .lock()
+                    .unwrap()
+                    .insert(kind, time::Instant::now() + RATE_LIMIT_COOLDOWN);
+            }
+            RateLimited::DailyQuota => {
+                client
+                    .exhausted
+                    .lock()
+                    .unwrap()
+                    .insert(kind, Utc::now().format("%Y-%m-%d").to_string());
+                if let Some(quota) = client.quota.as_ref() {
+                    quota.set_exhausted(kind).await;
+                }
+            }
+        }
+    }
+    async fn get_config(
+        &self,
+        idx: usize,
+    ) -> anyhow::Result<gphotos_api::apis::configuration::Configuration> {
+        let mut t = self.clients[idx].token.lock().await;
         t.check_token().await?;
         Ok(gphotos_api::apis::configuration::Configuration {
             oauth_access_token: Some(t.token.clone()),
             ..self.api_config.clone()
         })
     }
+    // Makes an API call with the config of a client that has quota left. Transient errors,
+    // including 429s, are retried with the same client, every attempt is counted against its
+    // quota. Clients are switched when the retries didn't help or the quota is used up.
+    async fn call<T, E, F, Fut>(&self, what: &str, f: F) -> anyhow::Result<T>
+    where
+        F: Fn(gphotos_api::apis::configuration::Configuration) -> Fut,
+        Fut: Future<Output = Result<T, gphotos_api::apis::Error<E>>>,
+        E: std::fmt::Debug + Send + Sync + 'static,
+    {
+        let _permit = self.listing.permit().await;
+        loop {
+            let idx = self.pick_client(QuotaKind::ApiRequest).await?;
+            let config = self.get_config(idx).await?;
+            let f = &f;
+            // The first attempt was counted by pick_client.
+            let mut first = true;
+            let r = self
+                .retry
+                .retry_if(
+                    what,
+                    || {
+                        let retry = !std::mem::replace(&mut first, false);
+                        let config = config.clone();
+                        async move {
+                            if retry {
+                                self.acquire_retry(idx, QuotaKind::ApiRequest).await?;
+                            }
+                            Ok(f(config).await?)
+                        }
+                    },
+                    |e: &anyhow::Error| {
+                        e.downcast_ref::<gphotos_api::apis::Error<E>>()
+                            .is_some_and(|e| {
+                                e.is_retryable()
+                                    && api_rate_limited(e) != Some(RateLimited::DailyQuota)
+                            })
+                    },
+                )
+                .await;
+            match r {
+                Err(e) if e.is::<QuotaExhausted>() => continue,
+                Err(e) => match e
+                    .downcast_ref::<gphotos_api::apis::Error<E>>()
+                    .and_then(api_rate_limited)
+                {
+                    Some(why) => self.set_rate_limited(idx, QuotaKind::ApiRequest, why).await,
+                    None => return Err(e),
+                },
+                r => return r,
+            }
+        }
+    }
     pub fn album_items_stream(
         &self,
         album_id: &GPhotoAlbumId,
     ) -> impl Stream<Item = anyhow::Result<gphotos_api::models::MediaItem>> + '_ {
-        let album_id = album_id.0.clone();
+        let search_req = gphotos_api::models::SearchMediaItemsRequest {
+            album_id: Some(album_id.0.clone()),
+            ..Default::default()
+        };
+        self.search_stream("list album items", search_req)
+    }
+
+    // Items in the library (not only in albums) that pass `filters`.
+    pub fn filtered_items_stream(
+        &self,
+        filters: gphotos_api::models::Filters,
+    ) -> impl Stream<Item = anyhow::Result<gphotos_api::models::MediaItem>> + '_ {
+        let search_req = gphotos_api::models::SearchMediaItemsRequest {
+            filters: Some(Box::new(filters)),
+            ..Default::default()
+        };
+        self.search_stream("search library items", search_req)
+    }
+
+    fn search_stream(
+        &self,
+        what: &'static str,
+        search_req: gphotos_api::models::SearchMediaItemsRequest,
+    ) -> impl Stream<Item = anyhow::Result<gphotos_api::models::MediaItem>> + '_ {
         try_stream! {
             let mut token: Option<String> = None;
             loop {
-                let config = self.get_config().await?;
                 let search_req = gphotos_api::models::SearchMediaItemsRequest{
-                    album_id: album_id.to_string(),
                     page_size: Some(100),
                     page_token: token,
+                    ..search_req.clone()
                 };
                 debug!("requesting new page");
-                let r = gphotos_api::apis::default_api::search_media_items(&config, Some(search_req)).await?;
+                let r = self.call(what, |config| {
+                    let search_req = search_req.clone();
+                    async move { gphotos_api::apis::default_api::search_media_items(&config, Some(search_req)).await }
+                }).await?;
                 match r.media_items {
                     Some(media_items) => {
                         for media_item in media_items {
@@ -161,8 +459,10 @@ impl GPClient {
         try_stream! {
             let mut token: Option<String> = None;
             loop {
-                let config = self.get_config().await?;
-                let r = gphotos_api::apis::default_api::list_albums(&config, Some(50), token.as_deref()).await?;
+                let r = self.call("list albums", |config| {
+                    let token = token.clone();
+                    async move { gphotos_api::apis::default_api::list_albums(&config, Some(50), token.as_deref()).await }
+                }).await?;
                 match r.albums {
                     Some(albums) => {
                         for album in albums {
@@ -185,8 +485,10 @@ impl GPClient {
         try_stream! {
             let mut token: Option<String> = None;
             loop {
-                let config = self.get_config().await?;
-                let r = gphotos_api::apis::default_api::list_shared_albums(&config, Some(50), token.as_deref()).await?;
+                let r = self.call("list shared albums", |config| {
+                    let token = token.clone();
+                    async move { gphotos_api::apis::default_api::list_shared_albums(&config, Some(50), token.as_deref()).await }
+                }).await?;
                 match r.shared_albums {
                     Some(albums) => {
                         for album in albums {
@@ -209,8 +511,10 @@ impl GPClient {
         try_stream! {
             let mut token: Option<String> = None;
             loop {
-                let config = self.get_config().await?;
-                let r = gphotos_api::apis::default_api::list_media_items(&config, Some(100), token.as_deref()).await?;
+                let r = self.call("list media items", |config| {
+                    let token = token.clone();
+                    async move { gphotos_api::apis::default_api::list_media_items(&config, Some(100), token.as_deref()).await }
+                }).await?;
                 match r.media_items {
                     Some(media_items) => {
                         for media_item in media_items {
@@ -226,11 +530,13 @@ impl GPClient {
             }
         }
     }
+    // Downloads the original into a buffer from `buffers`. The download is streamed, so large
+    // videos don't have to fit in memory.
     pub async fn fetch_media_item(
         &self,
         media_item: &gphotos_api::models::MediaItem,
-    ) -> anyhow::Result<Bytes> {
-        let config = self.get_config().await?;
+        buffers: &MediaBuffers,
+    ) -> anyhow::Result<StagedMedia> {
         let metadata = media_item
             .media_metadata
             .as_ref()
@@ -248,25 +554,111 @@ impl GPClient {
             .ok_or(anyhow!(format!("missing base url")))?;
         let fetch_url = format!("{}{}", base_url, suffix);
 
-        let bytes = config
-            .client
-            .get(fetch_url)
-            .timeout(time::Duration::from_secs(300))
-            .send()
-            .await?
-            .bytes()
-            .await?;
-        Ok(bytes)
+        self.download("download", &fetch_url, |mut resp| async move {
+            let mut buffer = buffers.buffer();
+            while let Some(chunk) = resp.chunk().await? {
+                self.downloads.consume(chunk.len()).await;
+                buffer = buffer.write_chunk(chunk).await?;
+            }
+            Ok(buffer.finish()?)
+        })
+        .await
+    }
+    // Fetches a thumbnail that fits in a `size` x `size` box.
+    pub async fn fetch_thumbnail(
+        &self,
+        media_item: &gphotos_api::models::MediaItem,
+        size: u32,
+    ) -> anyhow::Result<bytes::Bytes> {
+        let base_url = media_item
+            .base_url
+            .as_ref()
+            .ok_or(anyhow!(format!("missing base url")))?;
+        let fetch_url = format!("{base_url}=w{size}-h{size}");
+        self.download("download thumbnail", &fetch_url, |resp| async move {
+            let bytes = resp.bytes().await?;
+            self.downloads.consume(bytes.len()).await;
+            Ok(bytes)
+        })
+        .await
+    }
+    // GETs a baseUrl. Every attempt is counted against the download quota, 429s are retried like
+    // in `call` before moving on to the next client. `read` gets the response when it is a success and is called
+    // again if reading it fails in a way that is worth retrying.
+    async fn download<T, F, Fut>(&self, what: &str, url: &str, read: F) -> anyhow::Result<T>
+    where
+        F: Fn(reqwest::Response) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let _permit = self.downloads.permit().await;
+        loop {
+            let idx = self.pick_client(QuotaKind::Download).await?;
+            let mut first = true;
+            let r = self
+                .retry
+                .retry_if(
+                    what,
+                    || {
+                        let retry = !std::mem::replace(&mut first, false);
+                        let read = &read;
+                        async move {
+                            if retry {
+                                self.acquire_retry(idx, QuotaKind::Download).await?;
+                            }
+                            let resp = self
+                                .api_config
+                                .client
+                                .get(url)
+                                .timeout(time::Duration::from_secs(300))
+                                .send()
+                                .await?;
+                            let status = resp.status().as_u16();
+                            if status == 429 {
+                                let body = resp.text().await.unwrap_or_default();
+                                return Err(rate_limited(status, &body).unwrap().into());
+                            }
+                            read(resp.error_for_status()?).await
+                        }
+                    },
+                    |e: &anyhow::Error| {
+                        e.downcast_ref::<reqwest::Error>()
+                            .is_some_and(|e| e.is_retryable())
+                            || e.downcast_ref::<RateLimited>()
+                                == Some(&RateLimited::TooManyRequests)
+                    },
+                )
+                .await;
+            match r {
+                Err(e) if e.is::<QuotaExhausted>() => continue,
+                Err(e) => match e.downcast_ref::<RateLimited>() {
+                    Some(&why) => self.set_rate_limited(idx, QuotaKind::Download, why).await,
+                    None => return Err(e),
+                },
+                r => return r,
+            }
+        }
+    }
+
+    pub async fn get_media_item(
+        &self,
+        item_id: &GPhotoItemId,
+    ) -> anyhow::Result<gphotos_api::models::MediaItem> {
+        self.call("get media item", |config| async move {
+            gphotos_api::apis::default_api::get_media_item(&config, &item_id.0).await
+        })
+        .await
+        .with_context(|| format!("failed to get media item id {}", item_id))
     }
 
     pub async fn get_album(
         &self,
         album_id: &GPhotoAlbumId,
     ) -> anyhow::Result<gphotos_api::models::Album> {
-        let config = self.get_config().await?;
-        gphotos_api::apis::default_api::get_album(&config, &album_id.0)
-            .await
-            .with_context(|| format!("failed to get album id {}", album_id))
+        self.call("get album", |config| async move {
+            gphotos_api::apis::default_api::get_album(&config, &album_id.0).await
+        })
+        .await
+        .with_context(|| format!("failed to get album id {}", album_id))
     }
 }
 
@@ -339,13 +731,13 @@ pub async fn get_auth(client_secret: &str, auth_file: &str) -> anyhow::Result<()
             .query_pairs()
             .find(|(key, _)| key == "code")
             .map(|(_, code)| AuthorizationCode::new(code.into_owned()))
-            .with_context(|| format!("failed to extract code from the url"))?;
+            .with_context(|| "failed to extract code from the url".to_string())?;
 
         let state = url
             .query_pairs()
             .find(|(key, _)| key == "state")
             .map(|(_, state)| CsrfToken::new(state.into_owned()))
-            .with_context(|| format!("failed to extract state from the url"))?;
+            .with_context(|| "failed to extract state from the url".to_string())?;
 
         let message = "Go back to your terminal :)";
         let response = format!(
@@ -374,3 +766,31 @@ pub async fn get_auth(client_secret: &str, auth_file: &str) -> anyhow::Result<()
     info!("auth token saved to {}", auth_file);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limited() {
+        assert_eq!(rate_limited(500, "RESOURCE_EXHAUSTED per day"), None);
+        assert_eq!(
+            rate_limited(429, "Too many requests"),
+            Some(RateLimited::TooManyRequests)
+        );
+        assert_eq!(
+            rate_limited(
+                429,
+                r#"{"error": {"status": "RESOURCE_EXHAUSTED", "message": "Quota exceeded for quota metric 'Read requests' and limit 'Read requests per minute per user'"}}"#
+            ),
+            Some(RateLimited::TooManyRequests)
+        );
+        assert_eq!(
+            rate_limited(
+                429,
+                r#"{"error": {"status": "RESOURCE_EXHAUSTED", "message": "Quota exceeded for quota metric 'All requests' and limit 'All requests per day'"}}"#
+            ),
+            Some(RateLimited::DailyQuota)
+        );
+    }
+}
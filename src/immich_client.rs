@@ -1,117 +1,231 @@
 use log::debug;
 use std::{
+    fmt::Debug,
+    future::Future,
     ops::Deref,
-    sync::{Arc, Condvar, Mutex},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use immich_api::apis::configuration::{ApiKey, Configuration};
 
+use crate::immich_index::ImmichIndex;
+use crate::retry::{RetryPolicy, Retryable};
+use crate::throttle::Throttle;
 use crate::types::{ImmichAlbumId, ImmichItemId};
 
-// ImmichClient takes care of keeping a limited set of ImmichApi clients and
-// handing them out using ApiConfigWrapper objects.
+// ImmichClient limits how many ImmichApi requests are in flight and hands out the api config
+// using ApiConfigWrapper objects. All requests share one reqwest::Client (and its connections).
 #[derive(Clone, Debug)]
 pub struct ImmichClient {
-    api_configs: Arc<Mutex<Vec<Box<Configuration>>>>,
-    configs_empty: Arc<Condvar>,
+    api_config: Configuration,
+    slots: Arc<Semaphore>,
+    metrics: Arc<PoolMetrics>,
     pub read_only: bool,
     base_url: String,
+    retry: RetryPolicy,
+    uploads: Throttle,
+    index: Option<ImmichIndex>,
+}
+
+#[derive(Debug, Default)]
+struct PoolMetrics {
+    requests: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+// Snapshot of how the immich api config pool was used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStats {
+    pub requests: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    pub in_flight: usize,
+    pub max_in_flight: usize,
 }
 
 pub struct ApiConfigWrapper<'a> {
-    api_config: Option<Box<Configuration>>,
-    return_to: &'a ImmichClient,
+    _permit: SemaphorePermit<'a>,
+    api_config: &'a Configuration,
+    metrics: &'a PoolMetrics,
 }
 
 impl<'a> Drop for ApiConfigWrapper<'a> {
     fn drop(&mut self) {
-        let ic = self.return_to;
-
-        {
-            let mut g = ic.api_configs.lock().unwrap();
-            g.push(self.api_config.take().unwrap());
-            debug!("returned immich api config, remaining: {}", g.len());
-        }
-        ic.configs_empty.notify_one();
+        let in_flight = self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed) - 1;
+        debug!("returned immich api config, in flight: {in_flight}");
     }
 }
 
 impl<'a> Deref for ApiConfigWrapper<'a> {
     type Target = Configuration;
     fn deref(&self) -> &Self::Target {
-        self.api_config.as_ref().unwrap()
+        self.api_config
     }
 }
 
 impl ImmichClient {
-    pub fn new(n: usize, immich_url: &str, api_key: Option<ApiKey>, read_only: bool) -> Self {
-        ImmichClient {
-            api_configs: Arc::new(Mutex::new(vec![
-                {
-                    Box::new(Configuration {
-                        api_key: api_key,
-                        base_path: immich_url.to_string(),
-                        ..Default::default()
-                    })
-                };
-                n
-            ])),
-            configs_empty: Arc::new(Condvar::new()),
+    // `n` is the max number of requests in flight. Requests (including reading the response) that
+    // stall for longer than `timeout` fail and can be retried.
+    pub fn new(
+        n: usize,
+        immich_url: &str,
+        api_key: Option<ApiKey>,
+        read_only: bool,
+        timeout: Duration,
+    ) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(30))
+            .read_timeout(timeout)
+            .pool_max_idle_per_host(n)
+            .build()?;
+        Ok(ImmichClient {
+            api_config: Configuration {
+                api_key,
+                base_path: immich_url.to_string(),
+                client,
+                ..Default::default()
+            },
+            slots: Arc::new(Semaphore::new(n.max(1))),
+            metrics: Default::default(),
             read_only,
             base_url: immich_url.strip_suffix("/api").unwrap().to_string(),
+            retry: Default::default(),
+            uploads: Throttle::new(n, None),
+            index: None,
+        })
+    }
+    pub fn with_upload_throttle(self, uploads: Throttle) -> Self {
+        ImmichClient { uploads, ..self }
+    }
+    pub fn uploads(&self) -> &Throttle {
+        &self.uploads
+    }
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        ImmichClient { retry, ..self }
+    }
+    // Lookups go to the local index instead of the search API.
+    pub fn with_index(self, index: ImmichIndex) -> Self {
+        ImmichClient {
+            index: Some(index),
+            ..self
         }
     }
+    pub fn index(&self) -> Option<&ImmichIndex> {
+        self.index.as_ref()
+    }
+    // Runs an API call, retrying transient failures.
+    pub async fn retry<T, E, F, Fut>(&self, what: &str, f: F) -> Result<T, E>
+    where
+        E: Retryable + Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.retry.retry(what, f).await
+    }
+    // Runs an API call that writes, retrying transient failures. `f` gets a config for each
+    // attempt, so no slot is held while waiting to try again.
+    pub async fn retry_writing<'a, T, E, F, Fut>(&'a self, what: &str, f: F) -> anyhow::Result<T>
+    where
+        E: Retryable + std::error::Error + Send + Sync + 'static,
+        F: Fn(ApiConfigWrapper<'a>) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        if self.read_only {
+            return Err(anyhow::anyhow!("asked for writing with a read-only config"));
+        }
+        Ok(self
+            .retry
+            .retry(what, || {
+                let f = &f;
+                async move { f(self.get_config().await).await }
+            })
+            .await?)
+    }
     pub fn item_url(&self, item_id: &ImmichItemId) -> String {
         format!("{}/photos/{}", self.base_url, item_id.0)
     }
     pub fn album_url(&self, album_id: &ImmichAlbumId) -> String {
         format!("{}/albums/{}", self.base_url, album_id.0)
     }
-    pub fn get_config(&self) -> ApiConfigWrapper {
-        let api_config = {
-            let mut g = self.api_configs.lock().unwrap();
-            loop {
-                match g.pop() {
-                    Some(api_config) => {
-                        debug!("took immich api config, remaining: {}", g.len());
-                        break api_config;
-                    }
-                    None => {
-                        debug!("ran out of immich api configs, {}", g.len());
-                        g = self.configs_empty.wait(g).unwrap();
-                    }
-                }
-            }
-        };
+    pub fn stats(&self) -> PoolStats {
+        let m = &self.metrics;
+        PoolStats {
+            requests: m.requests.load(Ordering::Relaxed),
+            total_wait: Duration::from_micros(m.wait_micros.load(Ordering::Relaxed)),
+            max_wait: Duration::from_micros(m.max_wait_micros.load(Ordering::Relaxed)),
+            in_flight: m.in_flight.load(Ordering::Relaxed),
+            max_in_flight: m.max_in_flight.load(Ordering::Relaxed),
+        }
+    }
+    // Waits (without blocking the runtime) until fewer than `n` requests are in flight.
+    pub async fn get_config(&self) -> ApiConfigWrapper<'_> {
+        let start = Instant::now();
+        let permit = self.slots.acquire().await.unwrap();
+        let wait = start.elapsed().as_micros() as u64;
+
+        let m = &self.metrics;
+        m.requests.fetch_add(1, Ordering::Relaxed);
+        m.wait_micros.fetch_add(wait, Ordering::Relaxed);
+        m.max_wait_micros.fetch_max(wait, Ordering::Relaxed);
+        let in_flight = m.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        m.max_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+        debug!("took immich api config after {wait}us, in flight: {in_flight}");
 
         ApiConfigWrapper {
-            api_config: Some(api_config),
-            return_to: &self,
+            _permit: permit,
+            api_config: &self.api_config,
+            metrics: m,
         }
     }
-    pub fn get_config_for_writing(&self) -> anyhow::Result<ApiConfigWrapper> {
-        if self.read_only {
-            return Err(anyhow::anyhow!("asked for writing with a read-only config"));
-        }
-        let api_config = {
-            let mut g = self.api_configs.lock().unwrap();
-            loop {
-                match g.pop() {
-                    Some(api_config) => {
-                        debug!("took immich api config, remaining: {}", g.len());
-                        break api_config;
-                    }
-                    None => {
-                        debug!("ran out of immich api configs, {}", g.len());
-                        g = self.configs_empty.wait(g).unwrap();
-                    }
-                }
-            }
-        };
+}
 
-        Ok(ApiConfigWrapper {
-            api_config: Some(api_config),
-            return_to: &self,
-        })
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pool() {
+        let client =
+            ImmichClient::new(2, "http://immich/api", None, false, Duration::from_secs(1)).unwrap();
+        // The slot is taken for the call only.
+        client
+            .retry_writing("write", |_| async {
+                Ok::<_, immich_api::apis::Error<()>>(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(client.stats().in_flight, 0);
+        let a = client.get_config().await;
+        let _b = client.get_config().await;
+        assert_eq!(client.stats().in_flight, 2);
+        // Pool is empty, this waits without blocking the runtime.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), client.get_config())
+                .await
+                .is_err()
+        );
+        drop(a);
+        let _c = client.get_config().await;
+        let stats = client.stats();
+        assert_eq!(stats.requests, 4);
+        assert_eq!(stats.in_flight, 2);
+        assert_eq!(stats.max_in_flight, 2);
+
+        let read_only =
+            ImmichClient::new(1, "http://immich/api", None, true, Duration::from_secs(1)).unwrap();
+        assert!(read_only
+            .retry_writing("write", |_| async {
+                Ok::<_, immich_api::apis::Error<()>>(())
+            })
+            .await
+            .is_err());
     }
 }
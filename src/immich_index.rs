@@ -0,0 +1,363 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use immich_api::apis::{audit_api, sync_api, users_api};
+use immich_api::models::{self, AssetResponseDto};
+use log::info;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Pool, Row, Sqlite, SqliteConnection};
+
+use crate::immich_client::ImmichClient;
+use crate::now;
+use crate::types::ImmichItemId;
+
+// Local copy of what immich has, in the immich_index table, so that items can be matched by
+// filename, checksum and time without a search request per item. The first refresh does a full
+// sync, later ones only fetch what changed since the previous one (and fall back to a full sync
+// when immich says that is needed).
+
+// Assets per full sync request.
+const FULL_SYNC_PAGE: i32 = 1000;
+
+#[derive(Clone, Debug)]
+pub struct ImmichIndex {
+    pool: Pool<Sqlite>,
+}
+
+fn parse_time(t: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(t).ok().map(|t| t.timestamp())
+}
+
+// Immich checksums are base64, ours are hex.
+fn hex_checksum(checksum: &str) -> Result<String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(checksum)
+        .with_context(|| format!("bad checksum {checksum:?}"))?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+// Roughly what immich calls takenAt.
+fn taken_at(asset: &AssetResponseDto) -> Option<i64> {
+    asset
+        .exif_info
+        .as_ref()
+        .and_then(|exif| exif.date_time_original.clone().flatten())
+        .and_then(|t| parse_time(&t))
+        .or_else(|| parse_time(&asset.file_created_at))
+}
+
+fn parse_assets(rows: &[SqliteRow]) -> Result<Vec<AssetResponseDto>> {
+    rows.iter()
+        .map(|row| Ok(serde_json::from_str(row.get("asset"))?))
+        .collect()
+}
+
+async fn upsert(conn: &mut SqliteConnection, assets: &[AssetResponseDto]) -> Result<()> {
+    for asset in assets {
+        if asset.is_trashed {
+            delete(conn, std::slice::from_ref(&asset.id)).await?;
+            continue;
+        }
+        sqlx::query(
+            r#"
+INSERT OR REPLACE INTO immich_index
+    (immich_id, original_file_name, checksum, taken_at, asset_type, asset, update_time)
+VALUES ($1, $2, $3, $4, $5, $6, $7)"#,
+        )
+        .bind(&asset.id)
+        .bind(&asset.original_file_name)
+        .bind(hex_checksum(&asset.checksum)?)
+        .bind(taken_at(asset))
+        .bind(asset.r#type.to_string())
+        .bind(serde_json::to_string(asset)?)
+        .bind(now())
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn delete(conn: &mut SqliteConnection, ids: &[String]) -> Result<()> {
+    for id in ids {
+        sqlx::query(r#"DELETE FROM immich_index WHERE immich_id = $1"#)
+            .bind(id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+impl ImmichIndex {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        ImmichIndex { pool }
+    }
+
+    pub async fn len(&self) -> Result<i64> {
+        Ok(sqlx::query(r#"SELECT COUNT(*) AS n FROM immich_index"#)
+            .fetch_one(&self.pool)
+            .await?
+            .get("n"))
+    }
+
+    // Brings the index up to date with immich.
+    pub async fn refresh(&self, immich_client: &ImmichClient) -> Result<()> {
+        let started = Utc::now().to_rfc3339();
+        let last = sqlx::query(r#"SELECT user_id, synced_at FROM immich_index_sync WHERE id = 0"#)
+            .fetch_optional(&self.pool)
+            .await?;
+        let user_id = match &last {
+            Some(row) => row.get("user_id"),
+            None => {
+                immich_client
+                    .retry("get my user", || async {
+                        users_api::get_my_user(&*immich_client.get_config().await).await
+                    })
+                    .await?
+                    .id
+            }
+        };
+        let synced = match last {
+            Some(row) => {
+                self.delta_sync(immich_client, &user_id, row.get("synced_at"))
+                    .await?
+            }
+            None => false,
+        };
+        if !synced {
+            self.full_sync(immich_client, &user_id, &started).await?;
+        }
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO immich_index_sync (id, user_id, synced_at) VALUES (0, $1, $2)"#,
+        )
+        .bind(&user_id)
+        .bind(&started)
+        .execute(&self.pool)
+        .await?;
+        info!("immich index has {} assets", self.len().await?);
+        Ok(())
+    }
+
+    async fn full_sync(
+        &self,
+        immich_client: &ImmichClient,
+        user_id: &str,
+        updated_until: &str,
+    ) -> Result<()> {
+        info!("doing a full sync of the immich index");
+        let user_id = Some(user_id.parse()?);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM immich_index"#)
+            .execute(&mut *tx)
+            .await?;
+        let mut last_id = None;
+        loop {
+            let req = models::AssetFullSyncDto {
+                last_id,
+                limit: FULL_SYNC_PAGE,
+                updated_until: updated_until.to_string(),
+                user_id,
+            };
+            let page = immich_client
+                .retry("full sync", || {
+                    let req = req.clone();
+                    async move {
+                        sync_api::get_full_sync_for_user(&*immich_client.get_config().await, req)
+                            .await
+                    }
+                })
+                .await?;
+            upsert(&mut tx, &page).await?;
+            match page.last() {
+                Some(last) if page.len() as i32 >= FULL_SYNC_PAGE => {
+                    last_id = Some(last.id.parse()?)
+                }
+                _ => break,
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Applies what changed since `synced_at`. Returns false if immich says a full sync is needed.
+    async fn delta_sync(
+        &self,
+        immich_client: &ImmichClient,
+        user_id: &str,
+        synced_at: String,
+    ) -> Result<bool> {
+        let req = models::AssetDeltaSyncDto {
+            updated_after: synced_at.clone(),
+            user_ids: vec![user_id.parse()?],
+        };
+        let delta =
+            immich_client
+                .retry("delta sync", || {
+                    let req = req.clone();
+                    async move {
+                        sync_api::get_delta_sync(&*immich_client.get_config().await, req).await
+                    }
+                })
+                .await?;
+        if delta.needs_full_sync {
+            return Ok(false);
+        }
+        let deletes = immich_client
+            .retry("get audit deletes", || {
+                let synced_at = synced_at.clone();
+                async move {
+                    audit_api::get_audit_deletes(
+                        &*immich_client.get_config().await,
+                        synced_at,
+                        models::EntityType::Asset,
+                        Some(user_id),
+                    )
+                    .await
+                }
+            })
+            .await?;
+        if deletes.needs_full_sync {
+            return Ok(false);
+        }
+        info!(
+            "immich index: {} assets changed, {} deleted",
+            delta.upserted.len(),
+            delta.deleted.len() + deletes.ids.len()
+        );
+        let mut tx = self.pool.begin().await?;
+        upsert(&mut tx, &delta.upserted).await?;
+        delete(&mut tx, &delta.deleted).await?;
+        delete(&mut tx, &deletes.ids).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    // Same as a search by original_file_name, but case doesn't matter.
+    pub async fn by_filename(&self, filename: &str) -> Result<Vec<AssetResponseDto>> {
+        let rows = sqlx::query(
+            r#"SELECT asset FROM immich_index WHERE original_file_name = $1 COLLATE NOCASE"#,
+        )
+        .bind(filename)
+        .fetch_all(&self.pool)
+        .await?;
+        parse_assets(&rows)
+    }
+
+    // `checksum` is a hex SHA1.
+    pub async fn by_checksum(&self, checksum: &str) -> Result<Option<ImmichItemId>> {
+        Ok(
+            sqlx::query(r#"SELECT immich_id FROM immich_index WHERE checksum = $1"#)
+                .bind(checksum.to_lowercase())
+                .fetch_optional(&self.pool)
+                .await?
+                .map(|row| ImmichItemId(row.get("immich_id"))),
+        )
+    }
+
+    pub async fn taken_between(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        asset_type: models::AssetTypeEnum,
+    ) -> Result<Vec<AssetResponseDto>> {
+        let rows = sqlx::query(
+            r#"SELECT asset FROM immich_index WHERE taken_at BETWEEN $1 AND $2 AND asset_type = $3"#,
+        )
+        .bind(from.timestamp())
+        .bind(to.timestamp())
+        .bind(asset_type.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+        parse_assets(&rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_pool;
+
+    fn asset(id: &str, name: &str, checksum: &str, created: &str) -> AssetResponseDto {
+        AssetResponseDto {
+            id: id.to_string(),
+            original_file_name: name.to_string(),
+            checksum: checksum.to_string(),
+            file_created_at: created.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_index() {
+        let pool = test_pool().await;
+        let index = ImmichIndex::new(pool.clone());
+
+        let mut conn = pool.acquire().await.unwrap();
+        upsert(
+            &mut conn,
+            &[
+                // SHA1 of "abcdefgh".
+                asset(
+                    "a",
+                    "IMG_1.jpg",
+                    "QlrxKgdDUCsyLpOgFbz4aOMk1Wo=",
+                    "2024-06-27T19:35:56Z",
+                ),
+                asset(
+                    "b",
+                    "IMG_2.jpg",
+                    "AAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+                    "2024-06-27T21:00:00Z",
+                ),
+                asset(
+                    "c",
+                    "IMG_3.jpg",
+                    "AQEBAQEBAQEBAQEBAQEBAQEBAQE=",
+                    "2024-06-27T19:40:00Z",
+                ),
+            ],
+        )
+        .await
+        .unwrap();
+        drop(conn);
+
+        let ids =
+            |assets: Vec<AssetResponseDto>| assets.into_iter().map(|a| a.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(index.by_filename("img_1.JPG").await.unwrap()),
+            vec!["a"]
+        );
+        assert_eq!(
+            index
+                .by_checksum("425af12a0743502b322e93a015bcf868e324d56a")
+                .await
+                .unwrap(),
+            Some(ImmichItemId("a".to_string()))
+        );
+        let from = DateTime::parse_from_rfc3339("2024-06-27T19:30:00Z")
+            .unwrap()
+            .into();
+        let to = DateTime::parse_from_rfc3339("2024-06-27T20:00:00Z")
+            .unwrap()
+            .into();
+        let mut in_window = ids(index
+            .taken_between(from, to, models::AssetTypeEnum::Image)
+            .await
+            .unwrap());
+        in_window.sort();
+        assert_eq!(in_window, vec!["a", "c"]);
+
+        // Deleted and trashed assets go away.
+        let mut conn = pool.acquire().await.unwrap();
+        delete(&mut conn, &["a".to_string()]).await.unwrap();
+        let mut trashed = asset(
+            "c",
+            "IMG_3.jpg",
+            "AQEBAQEBAQEBAQEBAQEBAQEBAQE=",
+            "2024-06-27T19:40:00Z",
+        );
+        trashed.is_trashed = true;
+        upsert(&mut conn, &[trashed]).await.unwrap();
+        drop(conn);
+        assert_eq!(index.len().await.unwrap(), 1);
+    }
+}
@@ -11,6 +11,49 @@ pub mod types {
     pub struct GPhotoAlbumId(pub String);
 }
 
+// Seconds since the epoch, as times are stored in the db.
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+pub mod album_filter;
+pub mod album_links;
+pub mod album_state;
 pub mod gpclient;
 pub mod immich_client;
+pub mod immich_index;
+pub mod library_filter;
+pub mod live_photos;
 pub mod match_metadata;
+pub mod media_buffer;
+pub mod phash;
+pub mod quota;
+pub mod report;
+pub mod retry;
+pub mod review;
+pub mod takeout;
+pub mod throttle;
+pub mod video;
+
+#[cfg(test)]
+pub(crate) mod test_util {
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::{Pool, Sqlite};
+
+    // An empty in-memory db with the schema. One connection, each one would be its own db.
+    pub async fn test_pool() -> Pool<Sqlite> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::raw_sql(include_str!("db_schema.sql"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool
+    }
+}
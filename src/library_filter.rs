@@ -0,0 +1,205 @@
+use anyhow::{anyhow, Context, Result};
+use gphotos_api::models::{
+    ContentCategory, ContentFilter, Date, DateFilter, DateRange, Feature, FeatureFilter, Filters,
+    MediaType, MediaTypeFilter,
+};
+
+// Builds the mediaItems:search filters for mirroring the library (rather than albums). Google
+// allows at most 5 dates, 5 ranges and 10 included or excluded categories.
+
+const MAX_DATES: usize = 5;
+const MAX_CATEGORIES: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct LibraryFilter {
+    // YYYY, YYYY-MM or YYYY-MM-DD, items taken on any of these.
+    pub dates: Vec<String>,
+    // YYYY-MM-DD, both ends included. A missing end is open.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub media_type: MediaType,
+    // Category names as google spells them (LANDSCAPES, PETS, ...), case doesn't matter.
+    pub include_categories: Vec<String>,
+    pub exclude_categories: Vec<String>,
+    pub favorites: bool,
+    pub include_archived: bool,
+}
+
+// Parses YYYY, YYYY-MM or YYYY-MM-DD into a date, zero for the parts not given.
+pub fn parse_date(s: &str) -> Result<Date> {
+    let parts = s
+        .split('-')
+        .map(|p| p.parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("bad date {s:?}, expected YYYY, YYYY-MM or YYYY-MM-DD"))?;
+    let (year, month, day) = match parts[..] {
+        [y] => (y, 0, 0),
+        [y, m] => (y, m, 0),
+        [y, m, d] => (y, m, d),
+        _ => {
+            return Err(anyhow!(
+                "bad date {s:?}, expected YYYY, YYYY-MM or YYYY-MM-DD"
+            ))
+        }
+    };
+    if !(1..=9999).contains(&year) || !(0..=12).contains(&month) || !(0..=31).contains(&day) {
+        return Err(anyhow!("date {s:?} is out of range"));
+    }
+    Ok(Date {
+        year: Some(year),
+        month: Some(month),
+        day: Some(day),
+    })
+}
+
+// Range ends have to be full dates.
+fn parse_full_date(s: &str) -> Result<Date> {
+    let date = parse_date(s)?;
+    if date.month == Some(0) || date.day == Some(0) {
+        return Err(anyhow!("{s:?} is not a full date (YYYY-MM-DD)"));
+    }
+    Ok(date)
+}
+
+pub fn parse_category(s: &str) -> Result<ContentCategory> {
+    serde_json::from_value(serde_json::Value::String(s.to_uppercase()))
+        .map_err(|_| anyhow!("unknown content category {s:?}"))
+}
+
+fn parse_categories(categories: &[String]) -> Result<Option<Vec<ContentCategory>>> {
+    if categories.len() > MAX_CATEGORIES {
+        return Err(anyhow!(
+            "at most {MAX_CATEGORIES} content categories can be given"
+        ));
+    }
+    if categories.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(
+        categories
+            .iter()
+            .map(|c| parse_category(c))
+            .collect::<Result<_>>()?,
+    ))
+}
+
+impl LibraryFilter {
+    pub fn to_filters(&self) -> Result<Filters> {
+        let mut filters = Filters::new();
+
+        if self.dates.len() > MAX_DATES {
+            return Err(anyhow!("at most {MAX_DATES} dates can be given"));
+        }
+        let dates: Vec<Date> = self
+            .dates
+            .iter()
+            .map(|d| parse_date(d))
+            .collect::<Result<_>>()?;
+        let range = if self.from.is_some() || self.to.is_some() {
+            let start_date = match &self.from {
+                Some(from) => parse_full_date(from)?,
+                None => parse_full_date("0001-01-01")?,
+            };
+            let end_date = match &self.to {
+                Some(to) => parse_full_date(to)?,
+                None => parse_full_date("9999-12-31")?,
+            };
+            Some(DateRange::new(Box::new(start_date), Box::new(end_date)))
+        } else {
+            None
+        };
+        if !dates.is_empty() || range.is_some() {
+            filters.date_filter = Some(Box::new(DateFilter {
+                dates: (!dates.is_empty()).then_some(dates),
+                ranges: range.map(|r| vec![r]),
+            }));
+        }
+
+        let included = parse_categories(&self.include_categories)?;
+        let excluded = parse_categories(&self.exclude_categories)?;
+        if included.is_some() || excluded.is_some() {
+            filters.content_filter = Some(Box::new(ContentFilter {
+                included_content_categories: included,
+                excluded_content_categories: excluded,
+            }));
+        }
+
+        if self.media_type != MediaType::AllMedia {
+            filters.media_type_filter = Some(Box::new(MediaTypeFilter {
+                media_types: Some(vec![self.media_type]),
+            }));
+        }
+        if self.favorites {
+            filters.feature_filter = Some(Box::new(FeatureFilter {
+                included_features: Some(vec![Feature::Favorites]),
+            }));
+        }
+        if self.include_archived {
+            filters.include_archived_media = Some(true);
+        }
+        Ok(filters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("2019").unwrap(),
+            Date {
+                year: Some(2019),
+                month: Some(0),
+                day: Some(0)
+            }
+        );
+        assert_eq!(parse_date("2019-07-14").unwrap().day, Some(14));
+        assert!(parse_date("2019-13").is_err());
+        assert!(parse_date("July 2019").is_err());
+        assert!(parse_full_date("2019-07").is_err());
+    }
+
+    #[test]
+    fn test_to_filters() {
+        assert_eq!(
+            LibraryFilter::default().to_filters().unwrap(),
+            Filters::new()
+        );
+
+        let filter = LibraryFilter {
+            dates: vec!["2019".to_string()],
+            from: Some("2021-03-01".to_string()),
+            media_type: MediaType::Video,
+            include_categories: vec!["pets".to_string()],
+            exclude_categories: vec!["SCREENSHOTS".to_string()],
+            favorites: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(filter.to_filters().unwrap()).unwrap(),
+            serde_json::json!({
+                "dateFilter": {
+                    "dates": [{"year": 2019, "month": 0, "day": 0}],
+                    "ranges": [{
+                        "startDate": {"year": 2021, "month": 3, "day": 1},
+                        "endDate": {"year": 9999, "month": 12, "day": 31},
+                    }],
+                },
+                "contentFilter": {
+                    "includedContentCategories": ["PETS"],
+                    "excludedContentCategories": ["SCREENSHOTS"],
+                },
+                "mediaTypeFilter": {"mediaTypes": ["VIDEO"]},
+                "featureFilter": {"includedFeatures": ["FAVORITES"]},
+            })
+        );
+
+        let filter = LibraryFilter {
+            include_categories: vec!["kittens".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.to_filters().is_err());
+    }
+}
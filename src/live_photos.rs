@@ -0,0 +1,95 @@
+use chrono::DateTime;
+use gphotos_api::models::MediaItem;
+use std::collections::HashMap;
+
+use crate::types::GPhotoItemId;
+
+// iPhone live photos (and some motion photos) come out of gphoto as two items: a still and a
+// short video with the same name apart from the extension, taken at the same time. Immich shows
+// them as one asset when the still is uploaded with the video's id as livePhotoVideoId.
+
+// The still and the video of a pair are at most this far apart.
+const MAX_PAIR_SECS: i64 = 3;
+
+// IMG_1234.HEIC and IMG_1234.MOV both give img_1234.
+fn stem(filename: &str) -> String {
+    filename
+        .rsplit_once('.')
+        .map(|(stem, _)| stem)
+        .unwrap_or(filename)
+        .to_lowercase()
+}
+
+fn creation_time(item: &MediaItem) -> Option<i64> {
+    let t = item.media_metadata.as_ref()?.creation_time.as_ref()?;
+    DateTime::parse_from_rfc3339(t).ok().map(|t| t.timestamp())
+}
+
+// Finds the live photo pairs among `items`, as a map from the still to the video. Names shared by
+// more than one still or video are left alone, there is no telling which goes with which.
+pub fn find_pairs(items: &HashMap<GPhotoItemId, MediaItem>) -> HashMap<GPhotoItemId, GPhotoItemId> {
+    let mut by_stem: HashMap<String, (Vec<&GPhotoItemId>, Vec<&GPhotoItemId>)> = HashMap::new();
+    for (id, item) in items {
+        let (Some(filename), Some(metadata)) = (&item.filename, &item.media_metadata) else {
+            continue;
+        };
+        let entry = by_stem.entry(stem(filename)).or_default();
+        if metadata.photo.is_some() {
+            entry.0.push(id);
+        } else if metadata.video.is_some() {
+            entry.1.push(id);
+        }
+    }
+    by_stem
+        .into_values()
+        .filter_map(|(photos, videos)| match (&photos[..], &videos[..]) {
+            ([photo], [video]) => {
+                let d = (creation_time(&items[*photo])? - creation_time(&items[*video])?).abs();
+                (d <= MAX_PAIR_SECS).then(|| ((*photo).clone(), (*video).clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, filename: &str, time: &str, video: bool) -> (GPhotoItemId, MediaItem) {
+        let kind = if video {
+            r#""video":{"fps":30.0}"#
+        } else {
+            r#""photo":{}"#
+        };
+        let item = serde_json::from_str(&format!(
+            r#"{{"id":"{id}","filename":"{filename}","mediaMetadata":{{"creationTime":"{time}",{kind}}}}}"#
+        ))
+        .unwrap();
+        (GPhotoItemId(id.to_string()), item)
+    }
+
+    #[test]
+    fn test_find_pairs() {
+        let items = HashMap::from([
+            item("still", "IMG_1.HEIC", "2024-07-14T14:44:38Z", false),
+            item("video", "IMG_1.MOV", "2024-07-14T14:44:39Z", true),
+            // Too far apart.
+            item("still-2", "IMG_2.JPG", "2024-07-14T14:44:38Z", false),
+            item("video-2", "IMG_2.MOV", "2024-07-14T15:44:38Z", true),
+            // Which one goes with the video?
+            item("still-3", "IMG_3.JPG", "2024-07-14T14:44:38Z", false),
+            item("still-3b", "img_3.heic", "2024-07-14T14:44:38Z", false),
+            item("video-3", "IMG_3.MOV", "2024-07-14T14:44:38Z", true),
+            // Just a video.
+            item("video-4", "IMG_4.MOV", "2024-07-14T14:44:38Z", true),
+        ]);
+        assert_eq!(
+            find_pairs(&items),
+            HashMap::from([(
+                GPhotoItemId("still".to_string()),
+                GPhotoItemId("video".to_string())
+            )])
+        );
+    }
+}
@@ -1,10 +1,9 @@
-use anyhow::{anyhow, Context, Result};
-use clap::{ArgAction, Parser};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgAction, Parser, Subcommand};
 use colored::Colorize;
-use crypto::digest::Digest;
-use crypto::sha1::Sha1;
+use derive_more::Display;
 use futures::pin_mut;
-use futures::stream::{self, StreamExt};
+use futures::stream::{self, Stream, StreamExt};
 use gphotos_api::models::{Album, MediaItem};
 use immich_api::apis::albums_api;
 use immich_api::apis::assets_api;
@@ -13,11 +12,31 @@ use immich_api::apis::search_api;
 use immich_api::models;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use lazy_static::lazy_static;
+use lib::album_filter::AlbumFilter;
+use lib::album_links::{
+    delete_album_item_links, find_drift, load_album_item_links, save_album_item_links,
+};
+use lib::album_state::{load_album_state, save_album_state, AlbumState};
 use lib::gpclient::get_auth;
 use lib::gpclient::GPClient;
 use lib::immich_client::ImmichClient;
-use lib::match_metadata::{compare_metadata, ImageData};
+use lib::immich_index::ImmichIndex;
+use lib::library_filter::LibraryFilter;
+use lib::live_photos;
+use lib::match_metadata::{
+    compare_metadata, pick_best, score_metadata, ImageData, MatchThresholds,
+};
+use lib::media_buffer::{MediaBuffers, StagedMedia};
+use lib::now;
+use lib::phash::{find_similar, PhashOptions};
+use lib::quota::QuotaExhausted;
+use lib::report::write_report;
+use lib::retry::RetryPolicy;
+use lib::review::{load_decisions, queue_for_review, review, ReviewDecision};
+use lib::takeout::{self, MediaLocation, TakeoutScan};
+use lib::throttle::Throttle;
 use lib::types::*;
+use lib::video;
 use log::Level::Warn;
 use log::{debug, error, info, log_enabled, warn};
 use sqlx::sqlite::SqlitePoolOptions;
@@ -26,7 +45,7 @@ use std::collections::{HashMap, HashSet};
 use std::env;
 use std::hash::Hash;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use unicode_normalization::UnicodeNormalization;
 
 /// Import google photo data into Immich.
@@ -50,24 +69,158 @@ struct Args {
     /// it is interpreted as usize limiting num of shared albums processed.
     #[arg(long, value_name = "shared_albums", action = ArgAction::Set)]
     shared_albums: Option<Option<String>>,
-    /// Goes together with --shared-albums. If set, will exit as soon as an album with no unseen items
-    /// is encountered.
+    /// Set to process the albums in the user's library (the albums tab). If a value is given, then
+    /// it is interpreted as usize limiting num of albums processed.
+    #[arg(long, value_name = "own_albums", action = ArgAction::Set)]
+    own_albums: Option<Option<String>>,
+    /// Goes together with --shared-albums and --own-albums. If set, will exit as soon as an album
+    /// with no unseen items is encountered.
     #[arg(long, default_value_t = false)]
     early_exit: bool,
+    /// Remove items from immich albums when they are removed from the gphoto album. Only items
+    /// that came from gphoto are removed, items added to the immich album by hand stay.
+    #[arg(long, default_value_t = false)]
+    mirror_removals: bool,
+    /// List every album again, even the ones that look unchanged since they were last synced
+    /// (same item count as then). Without it, an album where as many items were removed as were
+    /// added is not listed, so the change is missed; run with this now and then.
+    #[arg(long, default_value_t = false)]
+    full_scan: bool,
+    /// Goes together with --shared-albums and --own-albums. Only sync albums whose title matches
+    /// this regex (or that are listed in --album-id).
+    #[arg(long)]
+    album_title: Option<String>,
+    /// Goes together with --shared-albums and --own-albums. Skip albums whose title matches this
+    /// regex.
+    #[arg(long)]
+    exclude_album_title: Option<String>,
+    /// Goes together with --shared-albums and --own-albums. Only sync albums with this gphoto ID
+    /// (or whose title matches --album-title). Can be given multiple times.
+    #[arg(long)]
+    album_id: Vec<String>,
+    /// Goes together with --shared-albums and --own-albums. Skip the album with this gphoto ID.
+    /// Can be given multiple times.
+    #[arg(long)]
+    exclude_album_id: Vec<String>,
 
-    /// Google Photo API client ID.
+    /// Google Photo API client ID. Can be given several times to spread the load over multiple
+    /// clients, each with its own --auth-token in the same order. The next client is used once
+    /// the current one runs out of quota.
     #[arg(long, default_value = "client-secret.json")]
-    client_secret: String,
+    client_secret: Vec<String>,
 
     /// Google photo API token. Will be created if does not exist. Creation requires user
     /// interaction via a local web server that runs on http://localhost:8080.
     #[arg(long, default_value = "auth_token.json")]
-    auth_token: String,
+    auth_token: Vec<String>,
+
+    /// Daily budget of gphoto API requests per client ID. Usage is kept in the local db, so this
+    /// holds across runs. Google allows 10000.
+    #[arg(long, default_value_t = 10000)]
+    max_api_requests: u64,
+
+    /// Daily budget of gphoto media downloads per client ID. Google allows 75000.
+    #[arg(long, default_value_t = 75000)]
+    max_downloads: u64,
+
+    /// Memory in MiB that downloaded files may use while waiting for the upload, across all
+    /// concurrent copies. Files that don't fit go to a temporary file.
+    #[arg(long, default_value_t = 512)]
+    memory_budget_mb: usize,
+
+    /// Files larger than this (in MiB) always go to a temporary file instead of memory.
+    #[arg(long, default_value_t = 64)]
+    spill_threshold_mb: usize,
+
+    /// Immich requests fail (and are retried) when the server doesn't send anything for this long.
+    #[arg(long, default_value_t = 300)]
+    immich_timeout_secs: u64,
+
+    /// How many times to try a Google Photos or Immich call that fails with a transient error
+    /// (timeout, 429 or 5xx) before giving up.
+    #[arg(long, default_value_t = 5)]
+    max_attempts: u32,
+
+    /// Backoff before the first retry in milliseconds. Doubles with every attempt (with random
+    /// jitter) up to a minute.
+    #[arg(long, default_value_t = 500)]
+    retry_backoff_ms: u64,
+
+    /// Max concurrent gphoto API (listing and metadata) requests.
+    #[arg(long, default_value_t = 10)]
+    list_concurrency: usize,
 
     /// Max media items to download from gphoto concurrently.
     #[arg(long, default_value_t = 10)]
     download_concurrency: usize,
 
+    /// Max concurrent immich metadata searches (when linking items).
+    #[arg(long, default_value_t = 10)]
+    search_concurrency: usize,
+
+    /// Max media items to upload to immich concurrently.
+    #[arg(long, default_value_t = 10)]
+    upload_concurrency: usize,
+
+    /// Max immich albums to add items to concurrently.
+    #[arg(long, default_value_t = 1)]
+    album_concurrency: usize,
+
+    /// Cap on download bandwidth from gphoto, in bytes per second (shared by all downloads).
+    #[arg(long)]
+    download_bytes_per_sec: Option<u64>,
+
+    /// Cap on upload bandwidth to immich, in bytes per second (shared by all uploads).
+    #[arg(long)]
+    upload_bytes_per_sec: Option<u64>,
+
+    /// What to do with items that immich has by filename but not by metadata. strict: skip them.
+    /// filename-unique: copy the item when the single filename match has different metadata.
+    /// create-on-ambiguous: copy the item whenever the filename matches had different metadata.
+    /// link-on-filename-unique: link to the single filename match even if its metadata differs.
+    /// Links and copies made because of a lax policy are recorded as such in the local db.
+    #[arg(long, value_enum, default_value_t = MatchPolicy::Strict)]
+    match_policy: MatchPolicy,
+
+    /// Immich items with the same filename are scored against the gphoto metadata (time,
+    /// dimensions, camera, exposure, ISO, file size). Candidates scoring at least this much match.
+    #[arg(long, default_value_t = 0.9)]
+    match_min_confidence: f64,
+
+    /// When several candidates match, link to the best one if it scores at least this much more
+    /// than the second best.
+    #[arg(long, default_value_t = 0.05)]
+    match_margin: f64,
+
+    /// Times at most this many seconds apart count as the same. Times a whole or half number of
+    /// hours apart (give or take this skew) count as the same when the rest of the metadata agrees,
+    /// as that is usually a time zone mixup.
+    #[arg(long, default_value_t = 0)]
+    match_time_tolerance_secs: i64,
+
+    /// Past the tolerance, the time score drops to zero over this many seconds.
+    #[arg(long, default_value_t = 600)]
+    match_time_window_secs: i64,
+
+    /// Look up items whose filename is not in immich by comparing thumbnails with the immich
+    /// items taken around the same time. Catches renamed, edited and recompressed copies.
+    #[arg(long, default_value_t = false)]
+    phash_fallback: bool,
+
+    /// Immich items taken this many seconds before or after are compared by thumbnail. The
+    /// default allows for time zone mixups.
+    #[arg(long, default_value_t = 14 * 3600)]
+    phash_window_secs: i64,
+
+    /// Thumbnail hashes (64 bits) that differ in at most this many bits look the same.
+    #[arg(long, default_value_t = 6)]
+    phash_max_distance: u32,
+
+    /// Keep a local index of the immich assets (refreshed at the start of each run with the sync
+    /// API) and match items against it instead of searching immich for every item.
+    #[arg(long, default_value_t = false)]
+    immich_index: bool,
+
     /// Do not make any changes to Immich or the local db.
     #[arg(long, default_value_t = false)]
     read_only: bool,
@@ -76,9 +229,80 @@ struct Args {
     #[arg(long, default_value = None)]
     items: Option<usize>,
 
+    /// Mirror the library (not just albums): list every media item that passes the --library-*
+    /// filters and import it.
+    #[arg(long, default_value_t = false)]
+    library: bool,
+    /// Goes together with --library. Only items taken on this date: YYYY, YYYY-MM or YYYY-MM-DD.
+    /// Can be given up to 5 times.
+    #[arg(long, requires = "library")]
+    library_date: Vec<String>,
+    /// Goes together with --library. Only items taken on or after this date (YYYY-MM-DD).
+    #[arg(long, requires = "library")]
+    library_from: Option<String>,
+    /// Goes together with --library. Only items taken on or before this date (YYYY-MM-DD).
+    #[arg(long, requires = "library")]
+    library_to: Option<String>,
+    /// Goes together with --library. Only photos or only videos.
+    #[arg(long, value_enum, default_value_t = LibraryMediaType::All, requires = "library")]
+    library_media_type: LibraryMediaType,
+    /// Goes together with --library. Only items in this google content category (e.g. pets,
+    /// landscapes, travel). Can be given up to 10 times.
+    #[arg(long, requires = "library")]
+    library_category: Vec<String>,
+    /// Goes together with --library. Leave out items in this google content category (e.g.
+    /// screenshots, receipts). Can be given up to 10 times.
+    #[arg(long, requires = "library")]
+    library_exclude_category: Vec<String>,
+    /// Goes together with --library. Only items marked as favorite.
+    #[arg(long, default_value_t = false, requires = "library")]
+    library_favorites: bool,
+    /// Goes together with --library. Include archived items, which google leaves out by default.
+    #[arg(long, default_value_t = false, requires = "library")]
+    library_include_archived: bool,
+
+    /// Google Takeout archive (.zip or .tgz) to import. Can be given multiple times, all parts of
+    /// a split export should be passed together. Takeout keeps the photo location that the API
+    /// strips.
+    #[arg(long)]
+    takeout: Vec<String>,
+    /// Instead of importing, patch location, description and original time from the --takeout
+    /// sidecars onto immich assets that are already linked in the local db. Assets patched by a
+    /// previous run are skipped.
+    #[arg(long, default_value_t = false)]
+    backfill: bool,
+
+    /// Copy the items left over in the upload queue by an earlier run that crashed or was
+    /// interrupted, without listing google photos again.
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
     // File with the Immich API token.
     #[arg(long, default_value = ".env")]
     immich_auth: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Go through the items that could not be matched automatically (one at a time, in the
+    /// terminal) and decide whether to link, copy or skip them. Later runs do what was decided.
+    Review,
+    /// Write the items waiting for review to a self-contained HTML file, with gphoto metadata
+    /// next to each immich candidate and the fields that did not match highlighted.
+    Report {
+        #[arg(long, default_value = "report.html")]
+        output: String,
+    },
+    /// Replace immich copies that are worse than the original (e.g. videos copied from Google's
+    /// transcoded download, or other copies this tool made whose original is larger) with the
+    /// original from the --takeout archives, keeping the immich asset, its albums and links.
+    Upgrade,
+    /// Compare the immich albums with the items this tool put into them and report the items
+    /// that were added or removed by hand in immich.
+    CheckAlbums,
 }
 
 lazy_static! {
@@ -86,14 +310,59 @@ lazy_static! {
         Arc::new(Mutex::new(HashMap::new()));
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Display)]
+enum MatchPolicy {
+    #[display(fmt = "strict")]
+    Strict,
+    #[display(fmt = "filename-unique")]
+    FilenameUnique,
+    #[display(fmt = "create-on-ambiguous")]
+    CreateOnAmbiguous,
+    #[display(fmt = "link-on-filename-unique")]
+    LinkOnFilenameUnique,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Display)]
+enum LibraryMediaType {
+    #[display(fmt = "all")]
+    All,
+    #[display(fmt = "photo")]
+    Photo,
+    #[display(fmt = "video")]
+    Video,
+}
+impl From<LibraryMediaType> for gphotos_api::models::MediaType {
+    fn from(t: LibraryMediaType) -> Self {
+        match t {
+            LibraryMediaType::All => gphotos_api::models::MediaType::AllMedia,
+            LibraryMediaType::Photo => gphotos_api::models::MediaType::Photo,
+            LibraryMediaType::Video => gphotos_api::models::MediaType::Video,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 enum LookupResult {
-    NotFound,                      // Filename is not found in immich
+    NotFound,                        // Filename is not found in immich
     FoundMultiple, // Filename found multiple matches but none of the candidates had a matching metadata
     FoundUnique(ImmichItemId), // Filename found a single match but no matching metadata
     MatchedMultiple, // Metadata matched with multiple candidates
     MatchedUnique(ImmichItemId), // Metadata matched with exactly one candidate
     MatchedUniqueDB(ImmichItemId), // Matched an item from the local db.
+    MatchedPerceptual(ImmichItemId), // No filename match, but the thumbnail looked like a single item taken around the same time
+}
+impl LookupResult {
+    fn name(&self) -> &'static str {
+        match self {
+            LookupResult::NotFound => "NotFound",
+            LookupResult::FoundMultiple => "FoundMultiple",
+            LookupResult::FoundUnique(_) => "FoundUnique",
+            LookupResult::MatchedMultiple => "MatchedMultiple",
+            LookupResult::MatchedUnique(_) => "MatchedUnique",
+            LookupResult::MatchedUniqueDB(_) => "MatchedUniqueDB",
+            LookupResult::MatchedPerceptual(_) => "MatchedPerceptual",
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -101,16 +370,28 @@ struct ScanResult {
     media_items: HashMap<GPhotoItemId, MediaItem>,
     albums: HashMap<GPhotoAlbumId, Album>,
     associations: HashMap<GPhotoAlbumId, HashSet<GPhotoItemId>>,
+    // Items that come from a Takeout archive rather than the gphoto API.
+    takeout_files: HashMap<GPhotoItemId, MediaLocation>,
+    // State of each album that was listed in full, saved once the album is synced completely.
+    album_states: HashMap<GPhotoAlbumId, AlbumState>,
 }
 #[derive(Debug, Default)]
 struct SearchResult {
     media_items: HashMap<GPhotoItemId, ElementLinkResult<ImmichItemId>>,
     albums: HashMap<GPhotoAlbumId, ElementLinkResult<ImmichAlbumId>>,
+    // Link type to record for items that are linked or copied only because of --match-policy.
+    policy_link_types: HashMap<GPhotoItemId, String>,
+    // Lookup result and immich candidates for items that go to the review queue.
+    review_candidates: HashMap<GPhotoItemId, (&'static str, Vec<models::AssetResponseDto>)>,
 }
 impl SearchResult {
+    fn policy_link_type(&self, gphoto_id: &GPhotoItemId) -> Option<&str> {
+        self.policy_link_types.get(gphoto_id).map(String::as_str)
+    }
+
     fn log_summary(&self) {
         let mut items_summary: HashMap<String, usize> = HashMap::new();
-        for (_, e) in &self.media_items {
+        for e in self.media_items.values() {
             let group = match e {
                 ElementLinkResult::Unknown(_) => "item skipped - no good match",
                 ElementLinkResult::Found(_) => "found metadata",
@@ -120,7 +401,7 @@ impl SearchResult {
             *items_summary.entry(group.to_string()).or_default() += 1;
         }
         let mut albums_summary: HashMap<String, usize> = HashMap::new();
-        for (_, e) in &self.albums {
+        for e in self.albums.values() {
             let group = match e {
                 ElementLinkResult::Unknown(_) => "skipped",
                 ElementLinkResult::Found(_) => "found metadata",
@@ -136,6 +417,21 @@ impl SearchResult {
     }
 }
 
+// State of an item in the pending_uploads table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+enum UploadState {
+    #[display(fmt = "planned")]
+    Planned,
+    #[display(fmt = "downloading")]
+    Downloading,
+    #[display(fmt = "uploaded")]
+    Uploaded, // In immich, but the link is not saved yet.
+    #[display(fmt = "linked")]
+    Linked,
+    #[display(fmt = "failed")]
+    Failed,
+}
+
 #[derive(Debug)]
 enum ElementLinkResult<LinkedType> {
     ExistsInDB(LinkedType), // Element found in the db
@@ -144,49 +440,107 @@ enum ElementLinkResult<LinkedType> {
     Unknown(String),        // IDK!
 }
 
+// Lists the items of an album into `result`, unless the album is the same as when it was last
+// synced completely. Returns whether any of the items are not in the local db yet.
 async fn scan_one_album(
     pool: &Pool<Sqlite>,
+    args: &Args,
     gphoto_client: &GPClient,
     gphoto_album_id: GPhotoAlbumId,
     album: Album,
     result: &mut ScanResult,
 ) -> Result<bool> {
-    let album_items = gphoto_client
-        .album_items_stream(&gphoto_album_id)
-        .collect::<Vec<_>>()
-        .await
-        .into_iter()
-        .filter_map(|item| match item {
-            Ok(item) => Some((GPhotoItemId(item.id.clone().unwrap()), item)),
+    let saved_state = if args.full_scan {
+        None
+    } else {
+        load_album_state(pool, &gphoto_album_id).await?
+    };
+    if let Some(saved_state) = &saved_state {
+        let count = album
+            .media_items_count
+            .as_ref()
+            .and_then(|c| c.parse::<i64>().ok());
+        if count.is_some() && count == saved_state.media_items_count {
+            debug!(
+                "album {:?} has the same item count, not listing it",
+                album.title
+            );
+            (*STATS.lock().unwrap().entry("albums_unchanged").or_default()) += 1;
+            return Ok(false);
+        }
+    }
+
+    let mut album_items = HashMap::new();
+    let mut listed_all = true;
+    let s = gphoto_client.album_items_stream(&gphoto_album_id);
+    pin_mut!(s);
+    while let Some(item) = s.next().await {
+        match item {
+            Ok(item) => {
+                album_items.insert(GPhotoItemId(item.id.clone().unwrap()), item);
+            }
+            // Don't record a partial album when out of quota.
+            Err(e) if e.is::<QuotaExhausted>() => return Err(e),
             Err(e) => {
                 error!("failed listing items: {e:?}");
-                None
+                listed_all = false;
             }
-        })
-        .collect::<HashMap<_, _>>();
+        }
+    }
+
+    let state = AlbumState::new(album.media_items_count.as_ref(), album_items.keys());
+    if listed_all && saved_state.is_some_and(|s| s.items_hash == state.items_hash) {
+        // Same items, only the count gphoto reports was off. Remember the new count so the album
+        // is not listed again next time.
+        debug!("album {:?} has the same items, skipping it", album.title);
+        (*STATS.lock().unwrap().entry("albums_unchanged").or_default()) += 1;
+        if !args.read_only {
+            save_album_state(pool, &gphoto_album_id, &state).await?;
+        }
+        return Ok(false);
+    }
 
     let mut new_items = false;
-    for (gphoto_id, _) in &album_items {
+    for gphoto_id in album_items.keys() {
         // Early exit needs to know when there is at least one item that is not in the local db.
-        if sqlx::query(r#"SELECT immich_id FROM item_item_links WHERE gphoto_id = $1"#)
-            .bind(&gphoto_id.0)
-            .fetch_optional(pool)
-            .await?
-            .is_none()
+        if sqlx::query(
+            r#"
+SELECT immich_id FROM item_item_links WHERE gphoto_id = $1
+UNION ALL SELECT immich_id FROM checksum_duplicates WHERE gphoto_id = $1"#,
+        )
+        .bind(&gphoto_id.0)
+        .fetch_optional(pool)
+        .await?
+        .is_none()
         {
             new_items = true;
             break;
         }
     }
     result.albums.insert(gphoto_album_id.clone(), album);
+    // Only albums that were listed in full are remembered and have removals mirrored.
+    if listed_all {
+        result.album_states.insert(gphoto_album_id.clone(), state);
+    }
     result.associations.insert(
         gphoto_album_id.clone(),
-        album_items.iter().map(|(k, _)| k.clone()).collect(),
+        album_items.keys().cloned().collect(),
     );
     result.media_items.extend(album_items);
     Ok(new_items)
 }
 
+async fn read_takeout(args: &Args) -> Result<TakeoutScan> {
+    let archives = args
+        .takeout
+        .iter()
+        .map(std::path::PathBuf::from)
+        .collect::<Vec<_>>();
+    tokio::task::spawn_blocking(move || takeout::scan_archives(&archives))
+        .await?
+        .with_context(|| "failed to read takeout archives".to_string())
+}
+
 async fn scan(
     pool: &Pool<Sqlite>,
     args: &Args,
@@ -194,12 +548,103 @@ async fn scan(
     gphoto_client: &GPClient,
 ) -> Result<ScanResult> {
     let mut result = ScanResult::default();
+    // Running out of quota stops listing, what was found so far is still synced.
+    if let Err(e) = scan_gphoto(pool, args, multi, gphoto_client, &mut result).await {
+        if !e.is::<QuotaExhausted>() {
+            return Err(e);
+        }
+        warn!("stopped listing google photos: {e}");
+    }
+    if !args.takeout.is_empty() {
+        let takeout = read_takeout(args).await?;
+        result.media_items.extend(takeout.media_items);
+        result.albums.extend(takeout.albums);
+        result.associations.extend(takeout.associations);
+        result.takeout_files.extend(takeout.locations);
+    }
+
+    Ok(result)
+}
+
+// Scans up to `limit` albums from `albums` that pass `album_filter`, stopping early with
+// --early-exit at the first album with no unseen items.
+#[allow(clippy::too_many_arguments)]
+async fn scan_albums(
+    pool: &Pool<Sqlite>,
+    args: &Args,
+    multi: &MultiProgress,
+    gphoto_client: &GPClient,
+    albums: impl Stream<Item = Result<Album>>,
+    mut limit: usize,
+    album_filter: &AlbumFilter,
+    message: &'static str,
+    result: &mut ScanResult,
+) -> Result<()> {
+    if limit == 0 {
+        return Ok(());
+    }
+    let all_albums_pb = multi.add(ProgressBar::new(if limit == usize::MAX {
+        0
+    } else {
+        limit as u64
+    }));
+    all_albums_pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    all_albums_pb.set_message(message);
+
+    pin_mut!(albums);
+    while let Some(album_or) = albums.next().await {
+        let album = album_or?;
+        if !album_filter.matches(&album) {
+            debug!("skipping album {:?}", album.title);
+            continue;
+        }
+        let gphoto_album_id = GPhotoAlbumId(album.id.clone().unwrap());
+        let new_items =
+            scan_one_album(pool, args, gphoto_client, gphoto_album_id, album, result).await?;
+
+        if limit == usize::MAX {
+            all_albums_pb.set_length(all_albums_pb.length().unwrap() + 1);
+        }
+
+        all_albums_pb.inc(1);
+        limit -= 1;
+        if limit == 0 || (args.early_exit && !new_items) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn scan_gphoto(
+    pool: &Pool<Sqlite>,
+    args: &Args,
+    multi: &MultiProgress,
+    gphoto_client: &GPClient,
+    result: &mut ScanResult,
+) -> Result<()> {
     // Go through gphoto API and pick what we're looking for.
-    let num_shared = match args.shared_albums.as_ref() {
-        Some(Some(value)) => value.parse::<usize>().ok(),
-        Some(None) => Some(usize::MAX),
-        None => None,
+    let album_limit = |arg: &Option<Option<String>>| -> Result<Option<usize>> {
+        match arg.as_ref() {
+            Some(Some(value)) => match value.parse::<usize>() {
+                Ok(0) | Err(_) => bail!("album limit must be a positive number, got {value:?}"),
+                Ok(limit) => Ok(Some(limit)),
+            },
+            Some(None) => Ok(Some(usize::MAX)),
+            None => Ok(None),
+        }
     };
+    let album_filter = AlbumFilter::new(
+        args.album_title.as_deref(),
+        args.exclude_album_title.as_deref(),
+        &args.album_id,
+        &args.exclude_album_id,
+    )?;
 
     if let Some(gphoto_album_id) = args.gphoto_album_id.as_ref() {
         let gphoto_album_id = GPhotoAlbumId(gphoto_album_id.clone());
@@ -209,46 +654,41 @@ async fn scan(
             .with_context(|| format!("failed to get gphoto album with id {gphoto_album_id}"))?;
         scan_one_album(
             pool,
+            args,
             gphoto_client,
             gphoto_album_id,
             album_metadata,
-            &mut result,
+            result,
         )
         .await?;
     }
-    if let Some(mut num_shared) = num_shared {
-        let all_albums_pb = multi.add(ProgressBar::new(if num_shared == usize::MAX {
-            0
-        } else {
-            num_shared as u64
-        }));
-        all_albums_pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-        );
-        all_albums_pb.set_message("Scanning gphoto albums");
-
-        let shared_albums_stream = gphoto_client.shared_albums_stream();
-        pin_mut!(shared_albums_stream);
-        while let Some(album_or) = shared_albums_stream.next().await {
-            let album = album_or?;
-            let gphoto_album_id = GPhotoAlbumId(album.id.clone().unwrap());
-            let new_items =
-                scan_one_album(pool, gphoto_client, gphoto_album_id, album, &mut result).await?;
-
-            if num_shared == usize::MAX {
-                all_albums_pb.set_length(all_albums_pb.length().unwrap() + 1);
-            }
-
-            all_albums_pb.inc(1);
-            num_shared -= 1;
-            if num_shared == 0 || (args.early_exit && !new_items) {
-                break;
-            }
-        }
+    if let Some(num_shared) = album_limit(&args.shared_albums)? {
+        scan_albums(
+            pool,
+            args,
+            multi,
+            gphoto_client,
+            gphoto_client.shared_albums_stream(),
+            num_shared,
+            &album_filter,
+            "Scanning gphoto shared albums",
+            result,
+        )
+        .await?;
+    }
+    if let Some(num_own) = album_limit(&args.own_albums)? {
+        scan_albums(
+            pool,
+            args,
+            multi,
+            gphoto_client,
+            gphoto_client.albums_stream(),
+            num_own,
+            &album_filter,
+            "Scanning gphoto albums",
+            result,
+        )
+        .await?;
     }
     if let Some(mut n) = args.items {
         let items_pb = multi.add(ProgressBar::new(0));
@@ -274,14 +714,50 @@ async fn scan(
             }
         }
     }
+    if args.library {
+        let filters = LibraryFilter {
+            dates: args.library_date.clone(),
+            from: args.library_from.clone(),
+            to: args.library_to.clone(),
+            media_type: args.library_media_type.into(),
+            include_categories: args.library_category.clone(),
+            exclude_categories: args.library_exclude_category.clone(),
+            favorites: args.library_favorites,
+            include_archived: args.library_include_archived,
+        }
+        .to_filters()?;
+        debug!("library filters: {filters:?}");
 
-    Ok(result)
+        let library_pb = multi.add(ProgressBar::new(0));
+        library_pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+            )
+            .unwrap()
+            .progress_chars("##-"),
+        );
+        library_pb.set_message("Listing library items");
+
+        let s = gphoto_client.filtered_items_stream(filters);
+        pin_mut!(s);
+        while let Some(media_item) = s.next().await {
+            let media_item = media_item?;
+            result
+                .media_items
+                .insert(GPhotoItemId(media_item.id.clone().unwrap()), media_item);
+            library_pb.set_length(library_pb.length().unwrap() + 1);
+            library_pb.inc(1);
+        }
+    }
+    Ok(())
 }
 async fn search(
+    args: &Args,
     multi: &MultiProgress,
     scan_result: &ScanResult,
     pool: &Pool<Sqlite>,
     immich_client: &ImmichClient,
+    gphoto_client: &GPClient,
 ) -> Result<SearchResult> {
     let media_items_pb = multi.add(ProgressBar::new(scan_result.media_items.len() as u64));
     media_items_pb.set_style(
@@ -294,19 +770,38 @@ async fn search(
     media_items_pb.set_message("Linking media items");
 
     let mut result = SearchResult::default();
+    let reviewed = load_decisions(pool).await?;
+    let thresholds = match_thresholds(args);
+    let phash = args.phash_fallback.then_some(PhashOptions {
+        window_secs: args.phash_window_secs,
+        max_distance: args.phash_max_distance,
+    });
+    let phash = &phash;
     // Find what we can in immich/local db and establish links. What can't be found will be either
     // skipped or created (in the stage that follows)
     result.media_items = stream::iter(scan_result.media_items.iter().map(
         |(gphoto_id, media_item)| {
             let pb = media_items_pb.clone();
+            let thresholds = &thresholds;
             async move {
-                let r = (gphoto_id, link_item(pool, immich_client, media_item).await);
+                let r = (
+                    gphoto_id,
+                    link_item(
+                        pool,
+                        immich_client,
+                        gphoto_client,
+                        media_item,
+                        thresholds,
+                        phash.as_ref(),
+                    )
+                    .await,
+                );
                 pb.inc(1);
                 r
             }
         },
     ))
-    .buffer_unordered(10)
+    .buffer_unordered(args.search_concurrency.max(1))
     .collect::<Vec<_>>()
     .await
     .into_iter()
@@ -317,17 +812,33 @@ async fn search(
             None
         }
     })
-    .map(|(gphoto_id, (link_res, message))| {
-        let x = match link_res {
-            LookupResult::MatchedUniqueDB(immich_id) => ElementLinkResult::ExistsInDB(immich_id),
-            LookupResult::MatchedUnique(immich_id) => ElementLinkResult::Found(immich_id),
-            // the following two should be CreateNew, but I'm worried about matching not
-            // working and getting a bunch of dupes.
-            // LookupResult::FoundMultiple => ElementLinkResult::CreateNew(message),
-            // LookupResult::FoundUnique(_) => ElementLinkResult::CreateNew(message),
-            LookupResult::NotFound => ElementLinkResult::CreateNew(message),
-            _ => ElementLinkResult::Unknown(message),
+    .map(|(gphoto_id, (link_res, message, candidates))| {
+        let lookup_name = link_res.name();
+        let (x, policy_link_type) = match (reviewed.get(gphoto_id), link_res) {
+            (_, LookupResult::MatchedUniqueDB(immich_id)) => {
+                (ElementLinkResult::ExistsInDB(immich_id), None)
+            }
+            (Some(ReviewDecision::Create), _) => (
+                ElementLinkResult::CreateNew("create decided in review".to_string()),
+                Some("Reviewed".to_string()),
+            ),
+            // Skipped, links decided in review are in the db already.
+            (Some(_), _) => (
+                ElementLinkResult::Unknown("skip decided in review".to_string()),
+                None,
+            ),
+            (None, link_res) => apply_match_policy(args.match_policy, link_res, message),
         };
+        if matches!(x, ElementLinkResult::Unknown(_)) && !reviewed.contains_key(gphoto_id) {
+            result
+                .review_candidates
+                .insert(gphoto_id.clone(), (lookup_name, candidates));
+        }
+        if let Some(link_type) = policy_link_type {
+            result
+                .policy_link_types
+                .insert(gphoto_id.clone(), link_type);
+        }
         (gphoto_id.clone(), x)
     })
     .collect();
@@ -342,7 +853,7 @@ async fn search(
     );
     albums_pb.set_message("Linking albums");
 
-    let immich_albums = get_immich_albums(&immich_client).await?;
+    let immich_albums = get_immich_albums(immich_client).await?;
     for (gphoto_album_id, gphoto_album) in &scan_result.albums {
         let x = link_album(pool, gphoto_album, &immich_albums).await?;
         albums_pb.inc(1);
@@ -351,14 +862,64 @@ async fn search(
     Ok(result)
 }
 
+fn match_thresholds(args: &Args) -> MatchThresholds {
+    MatchThresholds {
+        time_tolerance_secs: args.match_time_tolerance_secs,
+        time_window_secs: args.match_time_window_secs,
+        min_confidence: args.match_min_confidence,
+        margin: args.match_margin,
+    }
+}
+
+// Decides what to do with an item based on the lookup result. Strict matching only links items
+// whose metadata matched a single candidate. The lax policies trade the risk of bad links or dupes
+// for fewer skipped items, what they decide comes with a link type (lookup result and policy) to
+// record in the db, so that it can be found and undone later.
+fn apply_match_policy(
+    policy: MatchPolicy,
+    lookup: LookupResult,
+    message: String,
+) -> (ElementLinkResult<ImmichItemId>, Option<String>) {
+    let lax = Some(format!("{}:{policy}", lookup.name()));
+    match (lookup, policy) {
+        (LookupResult::MatchedUniqueDB(immich_id), _) => {
+            (ElementLinkResult::ExistsInDB(immich_id), None)
+        }
+        (LookupResult::MatchedUnique(immich_id), _) => (ElementLinkResult::Found(immich_id), None),
+        (LookupResult::MatchedPerceptual(immich_id), _) => (
+            ElementLinkResult::Found(immich_id),
+            Some("MatchedPerceptual".to_string()),
+        ),
+        (LookupResult::NotFound, _) => (ElementLinkResult::CreateNew(message), None),
+        (LookupResult::FoundUnique(_), MatchPolicy::FilenameUnique)
+        | (LookupResult::FoundUnique(_), MatchPolicy::CreateOnAmbiguous) => {
+            (ElementLinkResult::CreateNew(message), lax)
+        }
+        (LookupResult::FoundMultiple, MatchPolicy::CreateOnAmbiguous) => {
+            (ElementLinkResult::CreateNew(message), lax)
+        }
+        (LookupResult::FoundUnique(immich_id), MatchPolicy::LinkOnFilenameUnique) => {
+            (ElementLinkResult::Found(immich_id), lax)
+        }
+        _ => (ElementLinkResult::Unknown(message), None),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn write(
+    args: &Args,
     multi: &MultiProgress,
     search_result: &SearchResult,
     scan_result: &ScanResult,
     pool: &Pool<Sqlite>,
     immich_client: &ImmichClient,
     gphoto_client: &GPClient, // needed for downloading photos
+    buffers: &MediaBuffers,
 ) -> Result<()> {
+    if !immich_client.read_only {
+        queue_uploads(pool, search_result, scan_result).await?;
+    }
+
     let mut linked_albums = HashMap::new();
     for (gphoto_id, link) in &search_result.albums {
         match link {
@@ -405,10 +966,7 @@ async fn write(
         search_result
             .media_items
             .iter()
-            .filter(|(_, x)| match x {
-                ElementLinkResult::CreateNew(_) => true,
-                _ => false,
-            })
+            .filter(|(_, x)| matches!(x, ElementLinkResult::CreateNew(_)))
             .count() as u64,
     ));
     items_copy_pb.set_style(
@@ -420,15 +978,74 @@ async fn write(
     );
     items_copy_pb.set_message("Copying media items");
 
+    // Live photos whose still is about to be copied, by still. The video is copied together with
+    // it, or just paired with it if it is in immich already (e.g. the still failed last time).
+    let pairs = live_photos::find_pairs(&scan_result.media_items);
+    let live_photos: HashMap<&GPhotoItemId, (&GPhotoItemId, Option<&ImmichItemId>)> = pairs
+        .iter()
+        .filter_map(|(still, video)| {
+            if !matches!(
+                search_result.media_items.get(still),
+                Some(ElementLinkResult::CreateNew(_))
+            ) {
+                return None;
+            }
+            match search_result.media_items.get(video)? {
+                ElementLinkResult::CreateNew(_) => Some((still, (video, None))),
+                ElementLinkResult::ExistsInDB(immich_id) => Some((still, (video, Some(immich_id)))),
+                _ => None,
+            }
+        })
+        .collect();
+    let live_videos: HashSet<&GPhotoItemId> = live_photos.values().map(|(v, _)| *v).collect();
+    let (live_photos, live_videos) = (&live_photos, &live_videos);
+
+    // Takeout files are copied in the order they are stored in their archives, which is what
+    // makes reading them from tgz archives cheap. Items without a Takeout file go first.
+    let takeout_order = {
+        let locations: Vec<_> = scan_result
+            .takeout_files
+            .iter()
+            .filter(|(id, _)| search_result.media_items.contains_key(*id))
+            .map(|(id, location)| (id.clone(), location.clone()))
+            .collect();
+        tokio::task::spawn_blocking(move || takeout::read_order(&locations)).await??
+    };
+    let mut media_items: Vec<_> = search_result.media_items.iter().collect();
+    media_items.sort_by_key(|(gphoto_id, _)| takeout_order.get(*gphoto_id));
+
     // Goes through media_items and performs all the actions to sync them to immich. As a result
     // builds a map from GPhotoItemId to ImmichItemId (either new or existing).
     let linked_items: HashMap<GPhotoItemId, ImmichItemId> =
-        stream::iter(search_result.media_items.iter().map(|(gphoto_id, link)| {
+        stream::iter(media_items.into_iter().map(|(gphoto_id, link)| {
             let pb = items_copy_pb.clone();
             let metadata = scan_result.media_items.get(gphoto_id).unwrap();
             let product_url = metadata.product_url.clone().unwrap_or_default();
 
             async move {
+                if live_videos.contains(gphoto_id) {
+                    // Taken care of together with its still.
+                    if matches!(link, ElementLinkResult::CreateNew(_)) {
+                        pb.inc(1);
+                    }
+                    return vec![];
+                }
+                if let Some((video_id, video_immich_id)) = live_photos.get(gphoto_id) {
+                    let r = copy_live_photo(
+                        pool,
+                        immich_client,
+                        gphoto_client,
+                        buffers,
+                        scan_result,
+                        search_result,
+                        gphoto_id,
+                        video_id,
+                        *video_immich_id,
+                    )
+                    .await;
+                    pb.inc(1);
+                    return r;
+                }
                 match link {
                     ElementLinkResult::ExistsInDB(immich_id) => Some(immich_id.clone()),
                     ElementLinkResult::Found(immich_id) => {
@@ -446,7 +1063,11 @@ VALUES ($1, $2, $3, $4)"#,
                             )
                             .bind(&gphoto_id.0)
                             .bind(&immich_id.0)
-                            .bind("MatchedUnique")
+                            .bind(
+                                search_result
+                                    .policy_link_type(gphoto_id)
+                                    .unwrap_or("MatchedUnique"),
+                            )
                             .bind(
                                 SystemTime::now()
                                     .duration_since(UNIX_EPOCH)
@@ -462,7 +1083,7 @@ VALUES ($1, $2, $3, $4)"#,
                                     "failed to add the link {} <-> {} to db",
                                     gphoto_id, immich_id
                                 );
-                                return None;
+                                return vec![];
                             }
                         }
                         Some(immich_id.clone())
@@ -476,9 +1097,19 @@ VALUES ($1, $2, $3, $4)"#,
                             );
                             Some(ImmichItemId("NEW_ITEM".to_string()))
                         } else {
-                            download_and_upload(pool, immich_client, gphoto_client, metadata)
-                                .await
-                                .ok()
+                            download_and_upload(
+                                pool,
+                                immich_client,
+                                gphoto_client,
+                                buffers,
+                                metadata,
+                                scan_result.takeout_files.get(gphoto_id),
+                                search_result.policy_link_type(gphoto_id),
+                                None,
+                            )
+                            .await
+                            .map_err(|e| log_copy_error(&e, &product_url))
+                            .ok()
                         };
                         pb.inc(1);
                         r
@@ -494,17 +1125,34 @@ VALUES ($1, $2, $3, $4)"#,
                                 info!("debug message: {}", message);
                             }
                         }
+                        if let Some((lookup, candidates)) =
+                            search_result.review_candidates.get(gphoto_id)
+                        {
+                            if immich_client.read_only {
+                                info!("will queue {} for review", product_url.red());
+                            } else {
+                                let _ = queue_for_review(pool, metadata, lookup, candidates)
+                                    .await
+                                    .map_err(|e| error!("{e:?}"));
+                                (*STATS.lock().unwrap().entry("items_for_review").or_default()) +=
+                                    1;
+                            }
+                        }
                         None
                     }
                 }
                 .map(|l| (gphoto_id.clone(), l))
+                .into_iter()
+                .collect()
             }
         }))
-        .buffer_unordered(10)
+        // Downloads and uploads have their own limits, this lets one item download while another
+        // one uploads.
+        .buffer_unordered((args.download_concurrency + args.upload_concurrency).max(1))
         .collect::<Vec<_>>()
         .await
         .into_iter()
-        .filter_map(|x| x)
+        .flatten()
         .collect();
 
     let immich_associations: HashMap<_, _> = scan_result
@@ -512,11 +1160,19 @@ VALUES ($1, $2, $3, $4)"#,
         .iter()
         .filter_map(|(gphoto_album_id, gphoto_items)| {
             let immich_album_id = linked_albums.get(gphoto_album_id)?;
-            let immich_items: HashSet<_> = gphoto_items
+            let links: Vec<_> = gphoto_items
                 .iter()
-                .filter_map(|gphoto_item_id| linked_items.get(gphoto_item_id))
+                .filter_map(|gphoto_item_id| {
+                    Some((gphoto_item_id, linked_items.get(gphoto_item_id)?))
+                })
                 .collect();
-            Some((immich_album_id, immich_items))
+            let immich_items: HashSet<_> = links.iter().map(|(_, immich_id)| *immich_id).collect();
+            // Albums with items that were not linked or copied are listed again next time.
+            let complete = links.len() == gphoto_items.len();
+            Some((
+                gphoto_album_id,
+                (immich_album_id, immich_items, links, complete),
+            ))
         })
         .collect();
 
@@ -531,81 +1187,850 @@ VALUES ($1, $2, $3, $4)"#,
     albums_add_pb.set_message("Adding media items to albums");
 
     // Associate all the items with corresponding immich albums.
-    stream::iter(
-        immich_associations
-            .into_iter()
-            .map(|(immich_album_id, immich_items)| {
-                let pb = albums_add_pb.clone();
-                async move {
-                    if immich_client.read_only {
-                        info!(
-                            "will add {} items to immich album {}",
-                            immich_items.len(),
-                            immich_album_id,
-                        );
-                    } else {
-                        let immich_ids: Vec<_> = immich_items
-                            .iter()
-                            .map(|id| {
-                                uuid::Uuid::parse_str(&id.0)
-                                    .with_context(|| format!("while parsing {}", id.0))
-                                    .unwrap()
-                            })
-                            .collect();
-
-                        let _ = albums_api::add_assets_to_album(
-                            &(immich_client.get_config_for_writing().unwrap()
-                                as lib::immich_client::ApiConfigWrapper),
-                            &immich_album_id.0,
-                            models::BulkIdsDto { ids: immich_ids },
-                            None,
-                        )
+    stream::iter(immich_associations.into_iter().map(
+        |(gphoto_album_id, (immich_album_id, immich_items, links, complete))| {
+            let pb = albums_add_pb.clone();
+            async move {
+                if immich_client.read_only {
+                    info!(
+                        "will add {} items to immich album {}",
+                        immich_items.len(),
+                        immich_album_id,
+                    );
+                } else {
+                    let immich_ids: Vec<_> = immich_items
+                        .iter()
+                        .map(|id| {
+                            uuid::Uuid::parse_str(&id.0)
+                                .with_context(|| format!("while parsing {}", id.0))
+                                .unwrap()
+                        })
+                        .collect();
+
+                    let response = immich_client
+                        .retry_writing("add assets to album", |config| {
+                            let ids = immich_ids.clone();
+                            async move {
+                                albums_api::add_assets_to_album(
+                                    &config,
+                                    &immich_album_id.0,
+                                    models::BulkIdsDto { ids },
+                                    None,
+                                )
+                                .await
+                            }
+                        })
                         .await
                         .map_err(|e| {
                             error!(
                                 "failed to add items to immich album {immich_album_id}: {:?}",
                                 e
                             )
-                        });
+                        })
+                        .ok();
+                    let Some(response) = response else {
                         pb.inc(1);
+                        return;
+                    };
+                    // Only items this tool added are recorded. Ones that were in the album
+                    // already were either recorded when they were added, or put there by hand
+                    // and must not be removed by --mirror-removals.
+                    let added: HashSet<_> = response
+                        .iter()
+                        .filter(|r| r.success)
+                        .map(|r| r.id.as_str())
+                        .collect();
+                    let links: Vec<_> = links
+                        .into_iter()
+                        .filter(|(_, immich_id)| added.contains(immich_id.0.as_str()))
+                        .collect();
+                    let _ = save_album_item_links(pool, immich_album_id, gphoto_album_id, &links)
+                        .await
+                        .map_err(|e| error!("failed to save album item links: {e:?}"));
+                    if let (true, Some(state)) =
+                        (complete, scan_result.album_states.get(gphoto_album_id))
+                    {
+                        let _ = save_album_state(pool, gphoto_album_id, state)
+                            .await
+                            .map_err(|e| error!("failed to save album state: {e:?}"));
                     }
+                    pb.inc(1);
                 }
-            }),
-    )
-    .buffer_unordered(1)
+            }
+        },
+    ))
+    .buffer_unordered(args.album_concurrency.max(1))
     .collect::<Vec<_>>()
     .await;
+
+    if args.mirror_removals {
+        // Only albums that were listed in full, anything else would look like a removal.
+        stream::iter(
+            scan_result
+                .album_states
+                .keys()
+                .filter_map(|gphoto_album_id| {
+                    let immich_album_id = linked_albums.get(gphoto_album_id)?;
+                    Some((immich_album_id, &scan_result.associations[gphoto_album_id]))
+                })
+                .map(|(immich_album_id, gphoto_items)| async move {
+                    let _ = mirror_removals(pool, immich_client, immich_album_id, gphoto_items)
+                        .await
+                        .map_err(|e| {
+                            error!("failed to mirror removals to immich album {immich_album_id}: {e:?}")
+                        });
+                }),
+        )
+        .buffer_unordered(args.album_concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    }
     Ok(())
 }
 
-// Links a media item from google photos to a immich item. Linking is done by:
-// 1. local DB mapping (for items that we have created),
-// 2. filename and metadata.
-async fn link_item(
+// Removes the assets from an immich album whose gphoto items are no longer in the gphoto album.
+// Only assets this tool put into the album (recorded in album_item_links) are considered,
+// anything else in the immich album was put there by hand and is left alone.
+async fn mirror_removals(
     pool: &Pool<Sqlite>,
     immich_client: &ImmichClient,
-    gphoto_item: &MediaItem,
-) -> Result<(LookupResult, String)> {
-    let gphoto_id = GPhotoItemId(gphoto_item.id.as_ref().unwrap().clone());
-    let filename = gphoto_item.filename.as_ref().unwrap();
-    let mut message = "".to_string();
-
-    let local_match = sqlx::query(r#"SELECT immich_id FROM item_item_links WHERE gphoto_id = $1"#)
-        .bind(&gphoto_id.0)
-        .fetch_optional(pool)
+    immich_album_id: &ImmichAlbumId,
+    gphoto_items: &HashSet<GPhotoItemId>,
+) -> Result<()> {
+    let album = immich_client
+        .retry("get album info", || async {
+            albums_api::get_album_info(
+                &*immich_client.get_config().await,
+                &immich_album_id.0,
+                None,
+                Some(false),
+            )
+            .await
+        })
         .await?;
-    if let Some(immich_id) = local_match {
-        return Ok((
-            LookupResult::MatchedUniqueDB(ImmichItemId(immich_id.get("immich_id"))),
-            message,
-        ));
+
+    let recorded = load_album_item_links(pool, immich_album_id).await?;
+    let mut removed = vec![];
+    for asset in &album.assets {
+        let immich_id = ImmichItemId(asset.id.clone());
+        match recorded.get(&immich_id) {
+            Some(gphoto_ids) if gphoto_ids.is_disjoint(gphoto_items) => {
+                debug!("{immich_id} is no longer in the gphoto album of {immich_album_id}");
+                removed.push(immich_id);
+            }
+            _ => {}
+        }
+    }
+    if removed.is_empty() {
+        return Ok(());
     }
 
-    let gphoto_metadata: ImageData = gphoto_item
-        .media_metadata
-        .as_ref()
-        .ok_or(anyhow!("missing metadata"))?
-        .as_ref()
+    if immich_client.read_only {
+        info!(
+            "will remove {} items from immich album {}",
+            removed.len(),
+            immich_album_id
+        );
+        return Ok(());
+    }
+    let ids: Vec<_> = removed
+        .iter()
+        .map(|id| uuid::Uuid::parse_str(&id.0).with_context(|| format!("while parsing {id}")))
+        .collect::<Result<_>>()?;
+    immich_client
+        .retry_writing("remove assets from album", |config| {
+            let ids = ids.clone();
+            async move {
+                albums_api::remove_asset_from_album(
+                    &config,
+                    &immich_album_id.0,
+                    models::BulkIdsDto { ids },
+                )
+                .await
+            }
+        })
+        .await?;
+    (*STATS
+        .lock()
+        .unwrap()
+        .entry("items_removed_from_albums")
+        .or_default()) += removed.len();
+    for immich_id in &removed {
+        delete_album_item_links(pool, immich_album_id, immich_id).await?;
+    }
+    Ok(())
+}
+
+// Compares the immich albums with the memberships recorded in album_item_links and reports the
+// items that were added or removed by hand in immich.
+async fn check_albums(pool: &Pool<Sqlite>, immich_client: &ImmichClient) -> Result<()> {
+    let album_ids: Vec<String> =
+        sqlx::query_scalar(r#"SELECT DISTINCT immich_album_id FROM album_item_links"#)
+            .fetch_all(pool)
+            .await?;
+    for immich_album_id in album_ids.into_iter().map(ImmichAlbumId) {
+        let album = match immich_client
+            .retry("get album info", || async {
+                albums_api::get_album_info(
+                    &*immich_client.get_config().await,
+                    &immich_album_id.0,
+                    None,
+                    Some(false),
+                )
+                .await
+            })
+            .await
+        {
+            Ok(album) => album,
+            Err(e) => {
+                warn!("failed to get immich album {immich_album_id}, deleted? {e:?}");
+                (*STATS.lock().unwrap().entry("albums_missing").or_default()) += 1;
+                continue;
+            }
+        };
+        let recorded = load_album_item_links(pool, &immich_album_id).await?;
+        let album_assets: HashSet<_> = album
+            .assets
+            .iter()
+            .map(|a| ImmichItemId(a.id.clone()))
+            .collect();
+        let drift = find_drift(&recorded, &album_assets);
+        if drift.is_empty() {
+            continue;
+        }
+        warn!(
+            "immich album {:?} ({}) was changed by hand: {} items removed, {} added",
+            album.album_name,
+            immich_client.album_url(&immich_album_id),
+            drift.removed_by_hand.len(),
+            drift.added_by_hand.len()
+        );
+        for immich_id in &drift.removed_by_hand {
+            info!("removed by hand: {}", immich_client.item_url(immich_id));
+        }
+        for immich_id in &drift.added_by_hand {
+            info!("added by hand: {}", immich_client.item_url(immich_id));
+        }
+        (*STATS.lock().unwrap().entry("albums_drifted").or_default()) += 1;
+        (*STATS
+            .lock()
+            .unwrap()
+            .entry("items_removed_by_hand")
+            .or_default()) += drift.removed_by_hand.len();
+        (*STATS
+            .lock()
+            .unwrap()
+            .entry("items_added_by_hand")
+            .or_default()) += drift.added_by_hand.len();
+    }
+    Ok(())
+}
+
+fn log_copy_error(e: &anyhow::Error, product_url: &str) {
+    if e.is::<QuotaExhausted>() {
+        (*STATS.lock().unwrap().entry("items_over_quota").or_default()) += 1;
+    } else {
+        error!("failed to copy {}: {e:?}", product_url)
+    }
+}
+
+// Copies a live photo: the video (unless it is in immich already) as a hidden asset, then the
+// still with the video as its livePhotoVideoId. Both gphoto items map to the still, which is what
+// goes into albums. If the video can't be copied, the still is copied on its own.
+#[allow(clippy::too_many_arguments)]
+async fn copy_live_photo(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    gphoto_client: &GPClient,
+    buffers: &MediaBuffers,
+    scan_result: &ScanResult,
+    search_result: &SearchResult,
+    still_id: &GPhotoItemId,
+    video_id: &GPhotoItemId,
+    video_immich_id: Option<&ImmichItemId>,
+) -> Vec<(GPhotoItemId, ImmichItemId)> {
+    let still = &scan_result.media_items[still_id];
+    let video = &scan_result.media_items[video_id];
+    let still_url = still.product_url.clone().unwrap_or_default();
+    if immich_client.read_only {
+        info!(
+            "will copy {} and {} to immich as a live photo",
+            still_url.red(),
+            video.product_url.clone().unwrap_or_default().red()
+        );
+        let new_item = ImmichItemId("NEW_ITEM".to_string());
+        return vec![
+            (still_id.clone(), new_item.clone()),
+            (video_id.clone(), new_item),
+        ];
+    }
+
+    let copy_still = |live_photo| {
+        download_and_upload(
+            pool,
+            immich_client,
+            gphoto_client,
+            buffers,
+            still,
+            scan_result.takeout_files.get(still_id),
+            search_result.policy_link_type(still_id),
+            live_photo,
+        )
+    };
+    let video_immich_id = match video_immich_id {
+        Some(immich_id) => immich_id.clone(),
+        None => match download_and_upload(
+            pool,
+            immich_client,
+            gphoto_client,
+            buffers,
+            video,
+            scan_result.takeout_files.get(video_id),
+            None,
+            Some(LivePhotoPart::Video),
+        )
+        .await
+        {
+            Ok(immich_id) => immich_id,
+            Err(e) => {
+                log_copy_error(&e, video.product_url.as_deref().unwrap_or_default());
+                return copy_still(None)
+                    .await
+                    .map_err(|e| log_copy_error(&e, &still_url))
+                    .map(|immich_id| (still_id.clone(), immich_id))
+                    .into_iter()
+                    .collect();
+            }
+        },
+    };
+    let still_immich_id = match copy_still(Some(LivePhotoPart::Still {
+        video_id: &video_immich_id,
+    }))
+    .await
+    {
+        Ok(immich_id) => immich_id,
+        Err(e) => {
+            log_copy_error(&e, &still_url);
+            return vec![];
+        }
+    };
+    let _ = save_live_photo_link(pool, still_id, video_id, &still_immich_id)
+        .await
+        .map_err(|e| error!("{e:?}"));
+    (*STATS
+        .lock()
+        .unwrap()
+        .entry("live_photos_copied")
+        .or_default()) += 1;
+    vec![
+        (still_id.clone(), still_immich_id.clone()),
+        (video_id.clone(), still_immich_id),
+    ]
+}
+
+// Records that the still and the video of a live photo are one immich asset (the still). The
+// video keeps its own row in item_item_links, pointing at the hidden video asset.
+async fn save_live_photo_link(
+    pool: &Pool<Sqlite>,
+    still_id: &GPhotoItemId,
+    video_id: &GPhotoItemId,
+    immich_id: &ImmichItemId,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+INSERT OR REPLACE INTO live_photo_links (gphoto_video_id, gphoto_photo_id, immich_id, insert_time)
+VALUES ($1, $2, $3, $4)"#,
+    )
+    .bind(&video_id.0)
+    .bind(&still_id.0)
+    .bind(&immich_id.0)
+    .bind(now())
+    .execute(pool)
+    .await
+    .with_context(|| format!("failed to save live photo link {still_id} + {video_id}"))?;
+    Ok(())
+}
+
+// Goes through the linked items in the local db and patches location, description and original
+// time from the matching takeout sidecar onto the immich asset. Only fields that immich does not
+// have are set. What was patched is recorded in the db so that reruns skip those assets.
+async fn backfill(
+    args: &Args,
+    multi: &MultiProgress,
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    takeout: &TakeoutScan,
+) -> Result<()> {
+    let links = sqlx::query(
+        r#"
+SELECT l.gphoto_id, l.immich_id FROM item_item_links l
+LEFT JOIN takeout_backfills b ON l.immich_id = b.immich_id
+WHERE b.immich_id IS NULL"#,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            GPhotoItemId(row.get("gphoto_id")),
+            ImmichItemId(row.get("immich_id")),
+        )
+    })
+    .collect::<Vec<_>>();
+
+    // Items linked through the gphoto API are looked up in takeout by filename.
+    let by_filename = takeout_by_filename(takeout);
+
+    let pb = multi.add(ProgressBar::new(links.len() as u64));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    pb.set_message("Backfilling from takeout");
+
+    stream::iter(links.iter().map(|(gphoto_id, immich_id)| {
+        let pb = pb.clone();
+        let by_filename = &by_filename;
+        async move {
+            let _ = backfill_item(
+                pool,
+                immich_client,
+                takeout,
+                by_filename,
+                gphoto_id,
+                immich_id,
+            )
+            .await
+            .map_err(|e| error!("backfill of {immich_id} failed: {e:?}"));
+            pb.inc(1);
+        }
+    }))
+    .buffer_unordered(args.search_concurrency.max(1))
+    .collect::<Vec<_>>()
+    .await;
+    Ok(())
+}
+
+// The takeout item for a linked immich asset: the same item if it was imported from takeout,
+// otherwise the single takeout item with the same filename and matching metadata.
+fn find_takeout_item<'a>(
+    immich_client: &ImmichClient,
+    takeout: &'a TakeoutScan,
+    by_filename: &HashMap<&str, Vec<&'a GPhotoItemId>>,
+    gphoto_id: &'a GPhotoItemId,
+    asset: &models::AssetResponseDto,
+) -> Option<&'a GPhotoItemId> {
+    if takeout.sidecars.contains_key(gphoto_id) {
+        return Some(gphoto_id);
+    }
+    let immich_metadata = ImageData::from(asset.clone());
+    let candidates = by_filename
+        .get(asset.original_file_name.as_str())
+        .map(|ids| {
+            ids.iter()
+                .filter(|id| {
+                    takeout.media_items[**id]
+                        .media_metadata
+                        .as_ref()
+                        .and_then(|m| ImageData::try_from(m.as_ref()).ok())
+                        .is_some_and(|m| compare_metadata(&m, &immich_metadata))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if candidates.len() != 1 {
+        debug!(
+            "{} takeout matches for {} ({})",
+            candidates.len(),
+            asset.original_file_name,
+            immich_client.item_url(&ImmichItemId(asset.id.clone()))
+        );
+        return None;
+    }
+    Some(candidates[0])
+}
+
+// Takeout items by filename, for find_takeout_item.
+fn takeout_by_filename(takeout: &TakeoutScan) -> HashMap<&str, Vec<&GPhotoItemId>> {
+    let mut by_filename: HashMap<&str, Vec<&GPhotoItemId>> = HashMap::new();
+    for (takeout_id, media_item) in &takeout.media_items {
+        if let Some(filename) = media_item.filename.as_ref() {
+            by_filename.entry(filename).or_default().push(takeout_id);
+        }
+    }
+    by_filename
+}
+
+async fn backfill_item(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    takeout: &TakeoutScan,
+    by_filename: &HashMap<&str, Vec<&GPhotoItemId>>,
+    gphoto_id: &GPhotoItemId,
+    immich_id: &ImmichItemId,
+) -> Result<()> {
+    let asset = immich_client
+        .retry("get asset info", || async {
+            assets_api::get_asset_info(&*immich_client.get_config().await, &immich_id.0, None).await
+        })
+        .await
+        .with_context(|| format!("failed to get immich asset {immich_id}"))?;
+
+    let Some(takeout_id) =
+        find_takeout_item(immich_client, takeout, by_filename, gphoto_id, &asset)
+    else {
+        (*STATS
+            .lock()
+            .unwrap()
+            .entry("backfill_no_match")
+            .or_default()) += 1;
+        return Ok(());
+    };
+    let sidecar = &takeout.sidecars[takeout_id];
+
+    let exif = asset.exif_info.as_deref();
+    let mut update = models::UpdateAssetDto::default();
+    let mut patched = vec![];
+    if let Some(geo) = sidecar.location() {
+        if exif.and_then(|e| e.latitude.flatten()).is_none() {
+            update.latitude = Some(geo.latitude);
+            update.longitude = Some(geo.longitude);
+            patched.push("location");
+        }
+    }
+    if let Some(description) = sidecar.description.as_ref().filter(|d| !d.is_empty()) {
+        if exif
+            .and_then(|e| e.description.clone().flatten())
+            .unwrap_or_default()
+            .is_empty()
+        {
+            update.description = Some(description.clone());
+            patched.push("description");
+        }
+    }
+    if let Some(taken_time) = sidecar.taken_time_rfc3339() {
+        if exif
+            .and_then(|e| e.date_time_original.clone().flatten())
+            .is_none()
+        {
+            update.date_time_original = Some(taken_time);
+            patched.push("dateTimeOriginal");
+        }
+    }
+
+    if immich_client.read_only {
+        if !patched.is_empty() {
+            info!(
+                "will set {} on {}",
+                patched.join(", "),
+                immich_client.item_url(immich_id).green()
+            );
+        }
+        return Ok(());
+    }
+    if !patched.is_empty() {
+        immich_client
+            .retry_writing("update asset", |config| {
+                let update = update.clone();
+                async move { assets_api::update_asset(&config, &immich_id.0, update).await }
+            })
+            .await
+            .with_context(|| format!("failed to update immich asset {immich_id}"))?;
+        (*STATS.lock().unwrap().entry("items_backfilled").or_default()) += 1;
+    }
+    sqlx::query(
+        r#"
+INSERT INTO takeout_backfills (immich_id, gphoto_id, takeout_id, patched_fields, insert_time)
+VALUES ($1, $2, $3, $4, $5)"#,
+    )
+    .bind(&immich_id.0)
+    .bind(&gphoto_id.0)
+    .bind(&takeout_id.0)
+    .bind(patched.join(","))
+    .bind(now())
+    .execute(pool)
+    .await
+    .with_context(|| "failed to save the backfill to the db".to_string())?;
+    Ok(())
+}
+
+// Replaces immich copies that were flagged as lower quality (see flag_lower_quality), and other
+// items this tool copied from gphoto whose original in the --takeout archives is larger, with the
+// original. The asset is
+// replaced in place, so its id, albums and links stay the same.
+async fn upgrade(
+    args: &Args,
+    multi: &MultiProgress,
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    buffers: &MediaBuffers,
+    takeout: &TakeoutScan,
+) -> Result<()> {
+    // Items this tool copied from a gphoto download that were not flagged have no reason yet,
+    // they may still be worse copies (e.g. photos edited in gphoto). Copies are linked as
+    // MatchedUniqueDB or LivePhotoVideo, or with the link type of a policy that creates items.
+    // Anything else was in immich already (phone backups, matched items) and is left alone, as
+    // are items copied from takeout.
+    let mut copies = sqlx::query(
+        r#"
+SELECT l.gphoto_id, l.immich_id, q.reason FROM item_item_links l
+LEFT JOIN lower_quality_copies q ON q.gphoto_id = l.gphoto_id AND q.immich_id = l.immich_id
+LEFT JOIN pending_uploads p ON p.gphoto_id = l.gphoto_id
+WHERE q.replaced_time IS NULL AND (q.immich_id IS NOT NULL OR (
+    p.takeout_archive IS NULL AND (
+        l.link_type IN ('MatchedUniqueDB', 'LivePhotoVideo')
+        OR l.link_type LIKE $1 OR l.link_type LIKE $2)))"#,
+    )
+    .bind(format!("%:{}", MatchPolicy::FilenameUnique))
+    .bind(format!("%:{}", MatchPolicy::CreateOnAmbiguous))
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            GPhotoItemId(row.get("gphoto_id")),
+            ImmichItemId(row.get("immich_id")),
+            row.get::<Option<String>, _>("reason"),
+        )
+    })
+    .collect::<Vec<_>>();
+    let by_filename = takeout_by_filename(takeout);
+    // Same order as in the archives, like in write().
+    let takeout_order = {
+        let locations: Vec<_> = copies
+            .iter()
+            .filter_map(|(gphoto_id, _, _)| {
+                Some((gphoto_id.clone(), takeout.locations.get(gphoto_id)?.clone()))
+            })
+            .collect();
+        tokio::task::spawn_blocking(move || takeout::read_order(&locations)).await??
+    };
+    copies.sort_by_key(|(gphoto_id, _, _)| takeout_order.get(gphoto_id));
+
+    let pb = multi.add(ProgressBar::new(copies.len() as u64));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
+        )
+        .unwrap()
+        .progress_chars("##-"),
+    );
+    pb.set_message("Replacing lower quality copies");
+
+    stream::iter(copies.iter().map(|(gphoto_id, immich_id, reason)| {
+        let reason = reason.as_deref();
+        let pb = pb.clone();
+        let by_filename = &by_filename;
+        async move {
+            let _ = upgrade_item(
+                pool,
+                immich_client,
+                buffers,
+                takeout,
+                by_filename,
+                gphoto_id,
+                immich_id,
+                reason,
+            )
+            .await
+            .map_err(|e| error!("replacing {immich_id} failed: {e:?}"));
+            pb.inc(1);
+        }
+    }))
+    .buffer_unordered(args.upload_concurrency.max(1))
+    .collect::<Vec<_>>()
+    .await;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn upgrade_item(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    buffers: &MediaBuffers,
+    takeout: &TakeoutScan,
+    by_filename: &HashMap<&str, Vec<&GPhotoItemId>>,
+    gphoto_id: &GPhotoItemId,
+    immich_id: &ImmichItemId,
+    // Why the copy was flagged, None for copies that were not.
+    reason: Option<&str>,
+) -> Result<()> {
+    let asset = immich_client
+        .retry("get asset info", || async {
+            assets_api::get_asset_info(&*immich_client.get_config().await, &immich_id.0, None).await
+        })
+        .await
+        .with_context(|| format!("failed to get immich asset {immich_id}"))?;
+
+    let Some(location) = find_takeout_item(immich_client, takeout, by_filename, gphoto_id, &asset)
+        .and_then(|takeout_id| takeout.locations.get(takeout_id))
+    else {
+        (*STATS
+            .lock()
+            .unwrap()
+            .entry("upgrade_no_original")
+            .or_default()) += 1;
+        return Ok(());
+    };
+    let reason = match reason {
+        Some(reason) => reason.to_string(),
+        None => {
+            let location = location.clone();
+            let original_size = tokio::task::spawn_blocking(move || location.size()).await??;
+            let immich_size = asset
+                .exif_info
+                .as_deref()
+                .and_then(|exif| exif.file_size_in_byte.flatten());
+            // Only ever replaced with something bigger: a smaller takeout file is more likely a
+            // compressed copy (storage saver) than the immich one is.
+            match immich_size {
+                Some(immich_size) if (immich_size as u64) < original_size => {
+                    format!("takeout original is {original_size} bytes, immich has {immich_size}")
+                }
+                _ => {
+                    (*STATS
+                        .lock()
+                        .unwrap()
+                        .entry("upgrade_not_needed")
+                        .or_default()) += 1;
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    if immich_client.read_only {
+        info!(
+            "will replace {} ({reason}) with {} from {:?}",
+            immich_client.item_url(immich_id).green(),
+            location.entry,
+            location.archive
+        );
+        return Ok(());
+    }
+
+    let staged = stage_takeout_file(buffers, location)
+        .await
+        .with_context(|| format!("failed to read {} from takeout", location.entry))?;
+    let _permit = immich_client.uploads().permit().await;
+    let file_name = &asset.original_file_name;
+    let checksum = &staged.checksum;
+    let res = immich_client
+        .retry_writing("replace asset", |config| {
+            let asset_data = staged
+                .part(file_name, immich_client.uploads())
+                .map_err(immich_api::apis::Error::Io);
+            let (created, modified) = (
+                asset.file_created_at.clone(),
+                asset.file_modified_at.clone(),
+            );
+            async move {
+                let asset_data = asset_data?;
+                assets_api::replace_asset(
+                    &config,
+                    &immich_id.0,
+                    asset_data,
+                    checksum,
+                    "immich-sync",
+                    created,
+                    modified,
+                    None,
+                    None,
+                )
+                .await
+            }
+        })
+        .await
+        .with_context(|| format!("replace_asset of {immich_id} failed"))?;
+    debug!("replace result: {:?}", res);
+    if res.status == models::AssetMediaStatus::Duplicate {
+        // E.g. the original came in from a phone backup. Both stay, the flag too.
+        info!(
+            "original of {} is already in immich as {}",
+            immich_client.item_url(immich_id),
+            immich_client.item_url(&ImmichItemId(res.id))
+        );
+        (*STATS
+            .lock()
+            .unwrap()
+            .entry("upgrade_duplicate")
+            .or_default()) += 1;
+        return Ok(());
+    }
+
+    let now = now();
+    sqlx::query(
+        r#"
+INSERT INTO lower_quality_copies (immich_id, gphoto_id, reason, insert_time, replaced_time)
+VALUES ($1, $2, $3, $4, $4)
+ON CONFLICT (immich_id) DO UPDATE SET replaced_time = excluded.replaced_time"#,
+    )
+    .bind(&immich_id.0)
+    .bind(&gphoto_id.0)
+    .bind(&reason)
+    .bind(now)
+    .execute(pool)
+    .await
+    .with_context(|| format!("failed to mark {immich_id} as replaced"))?;
+    (*STATS.lock().unwrap().entry("items_replaced").or_default()) += 1;
+    Ok(())
+}
+
+// Links a media item from google photos to a immich item. Linking is done by:
+// 1. local DB mapping (for items that we have created),
+// 2. filename and metadata.
+async fn link_item(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    gphoto_client: &GPClient,
+    gphoto_item: &MediaItem,
+    thresholds: &MatchThresholds,
+    // Set when items without a filename match should be looked up by thumbnail.
+    phash: Option<&PhashOptions>,
+) -> Result<(LookupResult, String, Vec<models::AssetResponseDto>)> {
+    let gphoto_id = GPhotoItemId(gphoto_item.id.as_ref().unwrap().clone());
+    let filename = gphoto_item.filename.as_ref().unwrap();
+    let mut message = "".to_string();
+
+    // Live photo videos map to their still.
+    let local_match = sqlx::query(
+        r#"
+SELECT COALESCE(p.immich_id, l.immich_id) AS immich_id FROM item_item_links l
+LEFT JOIN live_photo_links p ON p.gphoto_video_id = l.gphoto_id
+WHERE l.gphoto_id = $1"#,
+    )
+    .bind(&gphoto_id.0)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(immich_id) = local_match {
+        return Ok((
+            LookupResult::MatchedUniqueDB(ImmichItemId(immich_id.get("immich_id"))),
+            message,
+            vec![],
+        ));
+    }
+    // Same bytes as an item linked to the asset, see copy_to_immich.
+    let duplicate_of: Option<String> =
+        sqlx::query_scalar(r#"SELECT immich_id FROM checksum_duplicates WHERE gphoto_id = $1"#)
+            .bind(&gphoto_id.0)
+            .fetch_optional(pool)
+            .await?;
+    if let Some(immich_id) = duplicate_of {
+        return Ok((
+            LookupResult::MatchedUniqueDB(ImmichItemId(immich_id)),
+            message,
+            vec![],
+        ));
+    }
+
+    let gphoto_metadata: ImageData = gphoto_item
+        .media_metadata
+        .as_ref()
+        .ok_or(anyhow!("missing metadata"))?
+        .as_ref()
         .try_into()
         .with_context(|| {
             format!(
@@ -614,38 +2039,60 @@ async fn link_item(
             )
         })?;
 
-    let search_req = models::MetadataSearchDto {
-        original_file_name: Some(filename.to_string()),
-        with_exif: Some(true),
-        ..Default::default()
-    };
     let mut rv = LookupResult::NotFound;
-    let res = search_api::search_metadata(&immich_client.get_config(), search_req).await?;
+    let candidates = match immich_client.index() {
+        Some(index) => index.by_filename(filename).await?,
+        None => {
+            let search_req = models::MetadataSearchDto {
+                original_file_name: Some(filename.to_string()),
+                with_exif: Some(true),
+                ..Default::default()
+            };
+            immich_client
+                .retry("search metadata", || {
+                    let search_req = search_req.clone();
+                    async move {
+                        search_api::search_metadata(&*immich_client.get_config().await, search_req)
+                            .await
+                    }
+                })
+                .await?
+                .assets
+                .items
+        }
+    };
     (*STATS.lock().unwrap().entry("item_searched").or_default()) += 1;
-    message.push_str(&format!(
-        "found {} filename matches; ",
-        res.assets.items.len()
-    ));
-    if res.assets.items.len() == 1 {
-        rv = LookupResult::FoundUnique(ImmichItemId(res.assets.items[0].id.clone()));
-    } else if res.assets.items.len() > 1 {
+    message.push_str(&format!("found {} filename matches; ", candidates.len()));
+    if candidates.len() == 1 {
+        rv = LookupResult::FoundUnique(ImmichItemId(candidates[0].id.clone()));
+    } else if candidates.len() > 1 {
         rv = LookupResult::FoundMultiple;
     }
-    for immich_item in &res.assets.items {
+    let scores: Vec<_> = candidates
+        .iter()
+        .map(|immich_item| {
+            score_metadata(
+                &gphoto_metadata,
+                &ImageData::from(immich_item.clone()),
+                thresholds,
+            )
+        })
+        .collect();
+    for (immich_item, score) in candidates.iter().zip(&scores) {
         let immich_metadata = ImageData::from(immich_item.clone());
 
-        if compare_metadata(&gphoto_metadata, &immich_metadata) {
+        if score.is_match(thresholds) {
             rv = match rv {
-                LookupResult::MatchedUnique(_) => {
+                LookupResult::MatchedUnique(_) | LookupResult::MatchedMultiple => {
                     message.push_str(&format!(
-                        " and {}",
+                        " and {} (score {score})",
                         immich_client.item_url(&ImmichItemId(immich_item.id.clone()))
                     ));
                     LookupResult::MatchedMultiple
                 }
                 _ => {
                     message.push_str(&format!(
-                        "matched {}",
+                        "matched {} (score {score})",
                         immich_client.item_url(&ImmichItemId(immich_item.id.clone()))
                     ));
                     LookupResult::MatchedUnique(ImmichItemId(immich_item.id.clone()))
@@ -653,7 +2100,7 @@ async fn link_item(
             };
         } else {
             message.push_str(&format!(
-                "{}: No metadata match! gphoto_id: {}\n",
+                "{}: No metadata match (score {score})! gphoto_id: {}\n",
                 filename.yellow(),
                 gphoto_id
             ));
@@ -671,7 +2118,49 @@ async fn link_item(
             ));
         }
     }
-    Ok((rv, message))
+    if let LookupResult::MatchedMultiple = rv {
+        if let Some(best) = pick_best(&scores, thresholds) {
+            let immich_id = ImmichItemId(candidates[best].id.clone());
+            message.push_str(&format!(
+                "; picked {} by score",
+                immich_client.item_url(&immich_id)
+            ));
+            (*STATS
+                .lock()
+                .unwrap()
+                .entry("item_picked_by_score")
+                .or_default()) += 1;
+            rv = LookupResult::MatchedUnique(immich_id);
+        }
+    }
+    if let (LookupResult::NotFound, Some(phash)) = (&rv, phash) {
+        match find_similar(pool, immich_client, gphoto_client, gphoto_item, phash).await {
+            Ok(similar) => match similar.as_slice() {
+                [] => {}
+                [(immich_item, d)] => {
+                    let immich_id = ImmichItemId(immich_item.id.clone());
+                    message.push_str(&format!(
+                        "thumbnail looks like {} (distance {d})",
+                        immich_client.item_url(&immich_id)
+                    ));
+                    (*STATS
+                        .lock()
+                        .unwrap()
+                        .entry("item_matched_phash")
+                        .or_default()) += 1;
+                    rv = LookupResult::MatchedPerceptual(immich_id);
+                }
+                // Most likely a burst, copying is safer than picking one.
+                _ => message.push_str(&format!(
+                    "{} items with similar thumbnails, not linking",
+                    similar.len()
+                )),
+            },
+            // E.g. a takeout item without a baseUrl.
+            Err(e) => warn!("thumbnail lookup for {gphoto_id} failed: {e:#}"),
+        }
+    }
+    Ok((rv, message, candidates))
 }
 
 // Goes through all of the albums in gphotos that pass the filter f and are not linked with
@@ -712,7 +2201,7 @@ async fn link_album(
             .or_else(|| immich_albums.get(&name_nfc))
         {
             Some(immich_ids) => {
-                if immich_ids.len() == 0 {
+                if immich_ids.is_empty() {
                     panic!("");
                 } else if immich_ids.len() == 1 {
                     Some(immich_ids[0].clone())
@@ -726,13 +2215,11 @@ async fn link_album(
         debug!(
             "album {album_title:?} ({gphoto_album_id}) matched with immich album {immich_album_id}"
         );
-        if let Some(_) = sqlx::query(
-            r#"SELECT gphoto_album_id FROM album_album_links WHERE immich_album_id = $1"#,
-        )
-        .bind(&immich_album_id.0)
-        .fetch_optional(pool)
-        .await?
-        .map(|row| ImmichAlbumId(row.get("immich_id")))
+        if sqlx::query(r#"SELECT gphoto_id FROM album_album_links WHERE immich_id = $1"#)
+            .bind(&immich_album_id.0)
+            .fetch_optional(pool)
+            .await?
+            .is_some()
         {
             debug!("album titled {album_title:?} already exists in immich but is mapped to another album, creating a new one");
             Ok(ElementLinkResult::CreateNew("".to_string()))
@@ -768,25 +2255,530 @@ async fn save_album_link(
     Ok(r.rows_affected() > 0)
 }
 
-// Downloads a media_item identified by `gphoto_id` from google photos and uploads it
-// to immich. The newly created mapping (gphoto_id <=> immich_id) is stored in the local
-// database.
+// Records the items that are about to be copied to immich in the pending_uploads table, so that
+// an interrupted run can be picked up with --resume. Items already in the queue keep their state.
+async fn queue_uploads(
+    pool: &Pool<Sqlite>,
+    search_result: &SearchResult,
+    scan_result: &ScanResult,
+) -> Result<()> {
+    let mut item_albums: HashMap<&GPhotoItemId, Vec<&str>> = HashMap::new();
+    for (gphoto_album_id, gphoto_items) in &scan_result.associations {
+        for gphoto_item_id in gphoto_items {
+            item_albums
+                .entry(gphoto_item_id)
+                .or_default()
+                .push(&gphoto_album_id.0);
+        }
+    }
+    let now = now();
+
+    let mut tx = pool.begin().await?;
+    for (gphoto_id, link) in &search_result.media_items {
+        if !matches!(link, ElementLinkResult::CreateNew(_)) {
+            continue;
+        }
+        let takeout_file = scan_result.takeout_files.get(gphoto_id);
+        sqlx::query(
+            r#"
+INSERT INTO pending_uploads (gphoto_id, media_item, gphoto_album_ids, takeout_archive,
+    takeout_entry, state, link_type, attempts, insert_time, update_time)
+VALUES ($1, $2, $3, $4, $5, $6, $9, 0, $7, $7)
+ON CONFLICT (gphoto_id) DO UPDATE SET
+    media_item = excluded.media_item,
+    gphoto_album_ids = excluded.gphoto_album_ids,
+    takeout_archive = excluded.takeout_archive,
+    takeout_entry = excluded.takeout_entry,
+    link_type = CASE WHEN state = $10 THEN link_type ELSE excluded.link_type END,
+    state = CASE WHEN state = $8 THEN excluded.state ELSE state END,
+    update_time = excluded.update_time"#,
+        )
+        .bind(&gphoto_id.0)
+        .bind(serde_json::to_string(&scan_result.media_items[gphoto_id])?)
+        .bind(serde_json::to_string(
+            &item_albums.get(gphoto_id).cloned().unwrap_or_default(),
+        )?)
+        .bind(takeout_file.map(|f| f.archive.to_string_lossy().to_string()))
+        .bind(takeout_file.map(|f| f.entry.clone()))
+        .bind(UploadState::Planned.to_string())
+        .bind(now)
+        .bind(UploadState::Linked.to_string())
+        // Uploaded items have the link type that goes with their immich id.
+        .bind(search_result.policy_link_type(gphoto_id))
+        .bind(UploadState::Uploaded.to_string())
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit()
+        .await
+        .with_context(|| "failed to save the upload queue".to_string())?;
+    Ok(())
+}
+
+async fn set_upload_state(
+    pool: &Pool<Sqlite>,
+    gphoto_id: &GPhotoItemId,
+    state: UploadState,
+    immich_id: Option<&ImmichItemId>,
+    error: Option<&str>,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+UPDATE pending_uploads SET
+    state = $2,
+    immich_id = COALESCE($3, immich_id),
+    last_error = COALESCE($4, last_error),
+    attempts = attempts + $5,
+    update_time = $6
+WHERE gphoto_id = $1"#,
+    )
+    .bind(&gphoto_id.0)
+    .bind(state.to_string())
+    .bind(immich_id.map(|id| id.0.clone()))
+    .bind(error)
+    .bind(if state == UploadState::Downloading {
+        1
+    } else {
+        0
+    })
+    .bind(now())
+    .execute(pool)
+    .await
+    .with_context(|| format!("failed to set upload state of {gphoto_id} to {state}"))?;
+    Ok(())
+}
+
+// Rebuilds the scan and search results for the items left in the upload queue so that they can
+// be passed to write(). Nothing is listed in gphoto, but download urls expire so items that still
+// need downloading are fetched again by id.
+async fn load_pending_uploads(
+    args: &Args,
+    pool: &Pool<Sqlite>,
+    gphoto_client: &GPClient,
+) -> Result<(ScanResult, SearchResult)> {
+    // Items that got linked by a normal run in the meantime.
+    sqlx::query(
+        r#"
+UPDATE pending_uploads SET state = $1
+WHERE state != $1 AND (gphoto_id IN (SELECT gphoto_id FROM item_item_links)
+    OR gphoto_id IN (SELECT gphoto_id FROM checksum_duplicates))"#,
+    )
+    .bind(UploadState::Linked.to_string())
+    .execute(pool)
+    .await?;
+
+    let rows = sqlx::query(
+        r#"
+SELECT gphoto_id, media_item, gphoto_album_ids, takeout_archive, takeout_entry, state, link_type,
+    attempts
+FROM pending_uploads WHERE state != $1"#,
+    )
+    .bind(UploadState::Linked.to_string())
+    .fetch_all(pool)
+    .await?;
+
+    let mut scan_result = ScanResult::default();
+    let mut search_result = SearchResult::default();
+    let mut to_refresh = vec![];
+    for row in rows {
+        let gphoto_id = GPhotoItemId(row.get("gphoto_id"));
+        let media_item: MediaItem = serde_json::from_str(row.get("media_item"))
+            .with_context(|| format!("bad media item in the upload queue for {gphoto_id}"))?;
+        let state: String = row.get("state");
+        let attempts: i64 = row.get("attempts");
+
+        match (
+            row.get::<Option<String>, _>("takeout_archive"),
+            row.get::<Option<String>, _>("takeout_entry"),
+        ) {
+            (Some(archive), Some(entry)) => {
+                scan_result.takeout_files.insert(
+                    gphoto_id.clone(),
+                    MediaLocation {
+                        archive: archive.into(),
+                        entry,
+                    },
+                );
+            }
+            _ if state != UploadState::Uploaded.to_string() => {
+                to_refresh.push(gphoto_id.clone());
+            }
+            _ => {}
+        }
+
+        let gphoto_album_ids: Vec<String> = serde_json::from_str(row.get("gphoto_album_ids"))?;
+        for gphoto_album_id in gphoto_album_ids {
+            let gphoto_album_id = GPhotoAlbumId(gphoto_album_id);
+            let Some(immich_album_id) =
+                sqlx::query(r#"SELECT immich_id FROM album_album_links WHERE gphoto_id = $1"#)
+                    .bind(&gphoto_album_id.0)
+                    .fetch_optional(pool)
+                    .await?
+                    .map(|row| ImmichAlbumId(row.get("immich_id")))
+            else {
+                warn!("album {gphoto_album_id} is not linked, not adding {gphoto_id} to it");
+                continue;
+            };
+            scan_result
+                .albums
+                .entry(gphoto_album_id.clone())
+                .or_insert_with(|| Album {
+                    id: Some(gphoto_album_id.0.clone()),
+                    ..Default::default()
+                });
+            search_result.albums.insert(
+                gphoto_album_id.clone(),
+                ElementLinkResult::ExistsInDB(immich_album_id),
+            );
+            scan_result
+                .associations
+                .entry(gphoto_album_id)
+                .or_default()
+                .insert(gphoto_id.clone());
+        }
+
+        if let Some(link_type) = row.get::<Option<String>, _>("link_type") {
+            search_result
+                .policy_link_types
+                .insert(gphoto_id.clone(), link_type);
+        }
+        search_result.media_items.insert(
+            gphoto_id.clone(),
+            ElementLinkResult::CreateNew(format!(
+                "resuming {state} item, {attempts} attempts so far"
+            )),
+        );
+        scan_result.media_items.insert(gphoto_id, media_item);
+    }
+    info!(
+        "{} items in the upload queue, refreshing {} from gphoto",
+        scan_result.media_items.len(),
+        to_refresh.len()
+    );
+
+    let refreshed = stream::iter(to_refresh.into_iter().map(|gphoto_id| async move {
+        let r = gphoto_client.get_media_item(&gphoto_id).await;
+        (gphoto_id, r)
+    }))
+    .buffer_unordered(args.list_concurrency.max(1))
+    .collect::<Vec<_>>()
+    .await;
+    for (gphoto_id, r) in refreshed {
+        match r {
+            Ok(media_item) => {
+                scan_result.media_items.insert(gphoto_id, media_item);
+            }
+            Err(e) => {
+                error!("failed to refresh {gphoto_id}, skipping it: {e:?}");
+                scan_result.media_items.remove(&gphoto_id);
+                search_result.media_items.remove(&gphoto_id);
+            }
+        }
+    }
+    Ok((scan_result, search_result))
+}
+
+// Downloads a media_item identified by `gphoto_id` from google photos (or reads it from the
+// takeout archive if `takeout_file` is given) and uploads it to immich. The newly created mapping
+// (gphoto_id <=> immich_id) is stored in the local database, with `policy_link_type` as the link
+// type if the item is copied because of a lax --match-policy. Progress is tracked in the
+// pending_uploads table.
+#[allow(clippy::too_many_arguments)]
 async fn download_and_upload(
     pool: &Pool<Sqlite>,
     immich_client: &ImmichClient,
     gphoto_client: &GPClient,
+    buffers: &MediaBuffers,
     gphoto_item: &MediaItem,
+    takeout_file: Option<&MediaLocation>,
+    policy_link_type: Option<&str>,
+    live_photo: Option<LivePhotoPart<'_>>,
 ) -> Result<ImmichItemId> {
-    // Download gphoto id
-    let bytes = gphoto_client
-        .fetch_media_item(gphoto_item)
-        .await
-        .with_context(|| {
-            format!(
-                "failed to fetch gphoto item id {}",
-                gphoto_item.id.as_ref().unwrap()
+    let gphoto_id = GPhotoItemId(gphoto_item.id.clone().unwrap());
+    let r = copy_to_immich(
+        pool,
+        immich_client,
+        gphoto_client,
+        buffers,
+        gphoto_item,
+        takeout_file,
+        policy_link_type,
+        live_photo,
+    )
+    .await;
+    if let Err(e) = &r {
+        // Items over quota were not tried, they go back to the queue.
+        let state = if e.is::<QuotaExhausted>() {
+            UploadState::Planned
+        } else {
+            UploadState::Failed
+        };
+        set_upload_state(pool, &gphoto_id, state, None, Some(&format!("{e:#}"))).await?;
+    }
+    r
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn copy_to_immich(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    gphoto_client: &GPClient,
+    buffers: &MediaBuffers,
+    gphoto_item: &MediaItem,
+    takeout_file: Option<&MediaLocation>,
+    policy_link_type: Option<&str>,
+    live_photo: Option<LivePhotoPart<'_>>,
+) -> Result<ImmichItemId> {
+    let gphoto_id = &GPhotoItemId(gphoto_item.id.clone().unwrap());
+    let copied_link_type = match live_photo {
+        Some(LivePhotoPart::Video) => "LivePhotoVideo",
+        _ => policy_link_type.unwrap_or("MatchedUniqueDB"),
+    };
+    // An earlier run may have stopped between the upload and saving the link.
+    let uploaded = sqlx::query(
+        r#"SELECT immich_id, link_type FROM pending_uploads WHERE gphoto_id = $1 AND state = $2"#,
+    )
+    .bind(&gphoto_id.0)
+    .bind(UploadState::Uploaded.to_string())
+    .fetch_optional(pool)
+    .await?
+    .map(|row| {
+        (
+            ImmichItemId(row.get("immich_id")),
+            row.get::<Option<String>, _>("link_type"),
+        )
+    });
+    let (immich_id, link_type) = match uploaded {
+        Some((immich_id, link_type)) => (
+            immich_id,
+            link_type.unwrap_or_else(|| copied_link_type.to_string()),
+        ),
+        None => {
+            set_upload_state(pool, gphoto_id, UploadState::Downloading, None, None).await?;
+            let (outcome, transcoded) = upload(
+                immich_client,
+                gphoto_client,
+                buffers,
+                gphoto_item,
+                takeout_file,
+                live_photo,
             )
-        })?;
+            .await?;
+            let (immich_id, link_type) = match outcome {
+                UploadOutcome::Uploaded(immich_id) => (immich_id, copied_link_type),
+                UploadOutcome::Duplicate(immich_id) => (immich_id, "MatchedChecksum"),
+            };
+            if let Some(reason) = transcoded {
+                flag_lower_quality(pool, &immich_id, gphoto_id, &reason).await?;
+            }
+            // The link type goes with the immich id, a resumed run records the same link.
+            sqlx::query(r#"UPDATE pending_uploads SET link_type = $2 WHERE gphoto_id = $1"#)
+                .bind(&gphoto_id.0)
+                .bind(link_type)
+                .execute(pool)
+                .await?;
+            set_upload_state(
+                pool,
+                gphoto_id,
+                UploadState::Uploaded,
+                Some(&immich_id),
+                None,
+            )
+            .await?;
+            (immich_id, link_type.to_string())
+        }
+    };
+
+    let now = now();
+    // The same bytes can be in gphoto more than once. Only one of the items can be linked to the
+    // asset, the others are recorded as duplicates of it.
+    let linked_to: Option<String> =
+        sqlx::query_scalar(r#"SELECT gphoto_id FROM item_item_links WHERE immich_id = $1"#)
+            .bind(&immich_id.0)
+            .fetch_optional(pool)
+            .await?;
+    match linked_to {
+        Some(other) if other != gphoto_id.0 => {
+            info!("{gphoto_id} has the same checksum as {other}, which is linked to {immich_id}");
+            (*STATS
+                .lock()
+                .unwrap()
+                .entry("items_duplicate_of_linked")
+                .or_default()) += 1;
+            sqlx::query(
+                r#"
+INSERT OR REPLACE INTO checksum_duplicates (gphoto_id, immich_id, linked_gphoto_id, insert_time)
+VALUES ($1, $2, $3, $4)"#,
+            )
+            .bind(&gphoto_id.0)
+            .bind(&immich_id.0)
+            .bind(&other)
+            .bind(now)
+            .execute(pool)
+            .await
+            .with_context(|| "failed to save the checksum duplicate to the db".to_string())?;
+        }
+        _ => {
+            sqlx::query(r#"INSERT OR IGNORE INTO item_item_links (gphoto_id, immich_id, link_type, insert_time) VALUES ($1, $2, $3, $4)"#)
+                .bind(&gphoto_id.0)
+                .bind(&immich_id.0)
+                .bind(&link_type)
+                .bind(now)
+                .execute(pool)
+                .await
+                .with_context(|| "failed to save item_item link to the db".to_string())?;
+        }
+    }
+    set_upload_state(pool, gphoto_id, UploadState::Linked, None, None).await?;
+
+    Ok(immich_id)
+}
+
+// Records that the immich copy of a gphoto item is worse than the original, so it can be
+// replaced once the original is available (e.g. from Takeout).
+async fn flag_lower_quality(
+    pool: &Pool<Sqlite>,
+    immich_id: &ImmichItemId,
+    gphoto_id: &GPhotoItemId,
+    reason: &str,
+) -> Result<()> {
+    info!("{gphoto_id} was copied as a lower quality transcode ({reason})");
+    (*STATS.lock().unwrap().entry("items_transcoded").or_default()) += 1;
+    sqlx::query(
+        r#"
+INSERT OR IGNORE INTO lower_quality_copies (immich_id, gphoto_id, reason, insert_time)
+VALUES ($1, $2, $3, $4)"#,
+    )
+    .bind(&immich_id.0)
+    .bind(&gphoto_id.0)
+    .bind(reason)
+    .bind(now())
+    .execute(pool)
+    .await
+    .with_context(|| format!("failed to flag {immich_id} as lower quality"))?;
+    Ok(())
+}
+
+// Google serves a transcode rather than the original when a video is downloaded (`=dv`). Returns
+// why the staged download is worse than the original, if it is.
+fn transcode_reason(staged: &StagedMedia, gphoto_item: &MediaItem) -> Option<String> {
+    let metadata = gphoto_item.media_metadata.as_deref()?;
+    metadata.video.as_ref()?;
+    let info = match staged
+        .reader()
+        .map_err(anyhow::Error::from)
+        .and_then(|mut r| video::read_video_info(&mut r))
+    {
+        Ok(info) => info,
+        Err(e) => {
+            warn!(
+                "failed to read video {}: {e:#}",
+                gphoto_item.id.as_ref().unwrap()
+            );
+            return None;
+        }
+    };
+    video::transcode_reason(
+        &info,
+        gphoto_item.filename.as_deref().unwrap_or_default(),
+        metadata,
+    )
+}
+
+// The part an item plays in a live photo, see live_photos.rs. The video is uploaded first and
+// hidden, then the still pointing at it.
+#[derive(Debug, Clone, Copy)]
+enum LivePhotoPart<'a> {
+    Video,
+    Still { video_id: &'a ImmichItemId },
+}
+
+// What upload() did with an item.
+enum UploadOutcome {
+    Uploaded(ImmichItemId),
+    // Immich already had the same bytes (by SHA1), possibly under a different filename.
+    Duplicate(ImmichItemId),
+}
+
+// Asks immich whether an asset with this checksum already exists.
+async fn find_by_checksum(
+    immich_client: &ImmichClient,
+    checksum: &str,
+) -> Result<Option<ImmichItemId>> {
+    if let Some(index) = immich_client.index() {
+        if let Some(immich_id) = index.by_checksum(checksum).await? {
+            return Ok(Some(immich_id));
+        }
+        // Could have been uploaded since the index was refreshed.
+    }
+    let req = models::AssetBulkUploadCheckDto {
+        assets: vec![models::AssetBulkUploadCheckItem {
+            checksum: checksum.to_string(),
+            id: checksum.to_string(),
+        }],
+    };
+    let res =
+        immich_client
+            .retry("check bulk upload", || {
+                let req = req.clone();
+                async move {
+                    assets_api::check_bulk_upload(&*immich_client.get_config().await, req).await
+                }
+            })
+            .await
+            .with_context(|| format!("failed to check immich for checksum {checksum}"))?;
+    Ok(res
+        .results
+        .into_iter()
+        .find(|r| {
+            r.action == models::asset_bulk_upload_check_result::Action::Reject
+                && r.reason == Some(models::asset_bulk_upload_check_result::Reason::Duplicate)
+        })
+        .and_then(|r| r.asset_id)
+        .map(ImmichItemId))
+}
+
+async fn stage_takeout_file(
+    buffers: &MediaBuffers,
+    location: &MediaLocation,
+) -> Result<StagedMedia> {
+    let location = location.clone();
+    let mut buffer = buffers.buffer();
+    tokio::task::spawn_blocking(move || -> Result<_> {
+        location.copy_to(&mut buffer)?;
+        Ok(buffer.finish()?)
+    })
+    .await?
+}
+
+async fn upload(
+    immich_client: &ImmichClient,
+    gphoto_client: &GPClient,
+    buffers: &MediaBuffers,
+    gphoto_item: &MediaItem,
+    takeout_file: Option<&MediaLocation>,
+    live_photo: Option<LivePhotoPart<'_>>,
+) -> Result<(UploadOutcome, Option<String>)> {
+    let staged = match takeout_file {
+        Some(location) => stage_takeout_file(buffers, location)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to read takeout item id {}",
+                    gphoto_item.id.as_ref().unwrap()
+                )
+            })?,
+        // Download gphoto id
+        None => gphoto_client
+            .fetch_media_item(gphoto_item, buffers)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to fetch gphoto item id {}",
+                    gphoto_item.id.as_ref().unwrap()
+                )
+            })?,
+    };
 
     let creation_time = gphoto_item
         .media_metadata
@@ -796,53 +2788,91 @@ async fn download_and_upload(
         .as_ref()
         .unwrap();
 
-    let asset_data = reqwest::multipart::Part::bytes(bytes.to_vec()).file_name(
-        gphoto_item
-            .filename
-            .clone()
-            .unwrap_or(format!("no name on gphoto.name")),
-    );
+    let file_name = gphoto_item
+        .filename
+        .clone()
+        .unwrap_or("no name on gphoto.name".to_string());
+
+    if staged.is_spilled() {
+        (*STATS.lock().unwrap().entry("items_spilled").or_default()) += 1;
+    }
+    // Takeout has the originals. Looking inside a spilled file is blocking I/O.
+    let (staged, transcoded) = match takeout_file {
+        Some(_) => (staged, None),
+        None => {
+            let gphoto_item = gphoto_item.clone();
+            tokio::task::spawn_blocking(move || {
+                let reason = transcode_reason(&staged, &gphoto_item);
+                (staged, reason)
+            })
+            .await?
+        }
+    };
+    let checksum = &staged.checksum;
+
+    if let Some(immich_id) = find_by_checksum(immich_client, checksum).await? {
+        debug!(
+            "{} is already in immich as {}",
+            file_name,
+            immich_client.item_url(&immich_id)
+        );
+        (*STATS
+            .lock()
+            .unwrap()
+            .entry("items_duplicate_checksum")
+            .or_default()) += 1;
+        return Ok((UploadOutcome::Duplicate(immich_id), transcoded));
+    }
 
-    let mut hasher = Sha1::new();
-    hasher.input(&bytes.to_vec());
+    let (is_visible, live_photo_video_id) = match live_photo {
+        Some(LivePhotoPart::Video) => (Some(false), None),
+        Some(LivePhotoPart::Still { video_id }) => (None, Some(video_id.0.as_str())),
+        None => (None, None),
+    };
 
     // Upload to immich
-    let res = assets_api::upload_asset(
-        &(immich_client.get_config_for_writing()? as lib::immich_client::ApiConfigWrapper),
-        asset_data,
-        &hasher.result_str(),
-        "immich-sync",
-        creation_time.clone(),
-        creation_time.clone(),
-        None,
-        Some(&hasher.result_str()),
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-        None,
-    )
-    .await
-    .with_context(|| format!("upload_asset to immich failed"))?;
-    (*STATS.lock().unwrap().entry("items_uploaded").or_default()) += 1;
-    debug!("upload result: {:?}", res);
-    sqlx::query(r#"INSERT INTO item_item_links (gphoto_id, immich_id, link_type, insert_time) VALUES ($1, $2, $3, $4)"#)
-        .bind(&gphoto_item.id.as_ref().unwrap())
-        .bind(&res.id)
-        .bind("MatchedUniqueDB")
-        .bind(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-        )
-        .execute(pool)
+    let _permit = immich_client.uploads().permit().await;
+    let res = immich_client
+        .retry_writing("upload asset", |config| {
+            let asset_data = staged
+                .part(&file_name, immich_client.uploads())
+                .map_err(immich_api::apis::Error::Io);
+            async move {
+                let asset_data = asset_data?;
+                assets_api::upload_asset(
+                    &config,
+                    asset_data,
+                    checksum,
+                    "immich-sync",
+                    creation_time.clone(),
+                    creation_time.clone(),
+                    None,
+                    Some(checksum),
+                    None,
+                    None,
+                    None,
+                    None,
+                    is_visible,
+                    live_photo_video_id,
+                    None,
+                )
+                .await
+            }
+        })
         .await
-        .with_context(|| format!("failed to save item_item link to the db"))?;
-
-    Ok(ImmichItemId(res.id))
+        .with_context(|| "upload_asset to immich failed".to_string())?;
+    debug!("upload result: {:?}", res);
+    // Someone else may have uploaded the same bytes since the check.
+    if res.status == models::AssetMediaStatus::Duplicate {
+        (*STATS
+            .lock()
+            .unwrap()
+            .entry("items_duplicate_checksum")
+            .or_default()) += 1;
+        return Ok((UploadOutcome::Duplicate(ImmichItemId(res.id)), transcoded));
+    }
+    (*STATS.lock().unwrap().entry("items_uploaded").or_default()) += 1;
+    Ok((UploadOutcome::Uploaded(ImmichItemId(res.id)), transcoded))
 }
 
 // Creates an immich album named `title` that is then linked (in the local database) to
@@ -863,12 +2893,13 @@ async fn create_linked_album(
         description: None,
         album_users: None, // When I passed in the current user, album page had 2 users registered
     };
-    let res = albums_api::create_album(
-        &(immich_client.get_config_for_writing()? as lib::immich_client::ApiConfigWrapper),
-        req,
-    )
-    .await
-    .with_context(|| format!("failed to create an immich album with title {title:?}"))?;
+    let res = immich_client
+        .retry_writing("create album", |config| {
+            let req = req.clone();
+            async move { albums_api::create_album(&config, req).await }
+        })
+        .await
+        .with_context(|| format!("failed to create an immich album with title {title:?}"))?;
     (*STATS.lock().unwrap().entry("albums_created").or_default()) += 1;
     let immich_album_id = ImmichAlbumId(res.id);
 
@@ -930,7 +2961,28 @@ UPDATE "item_item_links" SET insert_time = unixepoch(CURRENT_TIMESTAMP);
         sqlx::raw_sql(update_schema)
             .execute(pool)
             .await
-            .with_context(|| format!("failed to update the new db schema. oops"))?;
+            .with_context(|| "failed to update the new db schema. oops".to_string())?;
+    }
+    // Tables added later are created here, the schema file only has CREATE ... IF NOT EXISTS.
+    sqlx::raw_sql(include_str!("db_schema.sql"))
+        .execute(pool)
+        .await
+        .with_context(|| "failed to create new db tables".to_string())?;
+    // Columns added to tables that may exist already.
+    for (table, column, definition) in [("pending_uploads", "link_type", "TEXT DEFAULT NULL")] {
+        if sqlx::query(&format!("SELECT {column} FROM {table} LIMIT 1"))
+            .fetch_optional(pool)
+            .await
+            .is_err()
+        {
+            warn!("adding column {column} to {table}");
+            sqlx::raw_sql(&format!(
+                r#"ALTER TABLE "{table}" ADD COLUMN {column} {definition};"#
+            ))
+            .execute(pool)
+            .await
+            .with_context(|| format!("failed to add {column} to {table}"))?;
+        }
     }
     Ok(())
 }
@@ -938,9 +2990,12 @@ UPDATE "item_item_links" SET insert_time = unixepoch(CURRENT_TIMESTAMP);
 async fn get_immich_albums(
     immich_client: &ImmichClient,
 ) -> Result<HashMap<String, Vec<ImmichAlbumId>>> {
-    let res = albums_api::get_all_albums(&immich_client.get_config(), None, None)
+    let res = immich_client
+        .retry("get all albums", || async {
+            albums_api::get_all_albums(&*immich_client.get_config().await, None, None).await
+        })
         .await
-        .with_context(|| format!("failed to get list of immich albums"))?;
+        .with_context(|| "failed to get list of immich albums".to_string())?;
 
     let immich_albums = res
         .into_iter()
@@ -973,6 +3028,26 @@ async fn get_immich_albums(
     Ok(m)
 }
 
+async fn log_quota(gphoto_client: &GPClient) {
+    for quota in gphoto_client.quotas() {
+        let remaining = quota.remaining().await;
+        info!(
+            "gphoto quota left today for client {}: {} api requests, {} downloads",
+            quota.client_id(),
+            remaining.api_requests,
+            remaining.downloads
+        );
+    }
+}
+
+fn log_immich_pool(immich_client: &ImmichClient) {
+    let stats = immich_client.stats();
+    info!(
+        "immich requests: {}, waited for a free slot {:?} in total ({:?} max), max in flight: {}",
+        stats.requests, stats.total_wait, stats.max_wait, stats.max_in_flight
+    );
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let logger =
@@ -991,7 +3066,6 @@ async fn main() -> Result<()> {
         warn!("DB not found, creating a new one in {}", args.db);
         let _ = std::fs::OpenOptions::new()
             .create_new(true)
-            .write(true)
             .append(true)
             .open(std::path::Path::new(&args.db))
             .with_context(|| format!("failed to create db file {}", args.db))?;
@@ -1008,7 +3082,7 @@ async fn main() -> Result<()> {
         sqlx::raw_sql(db_schema)
             .execute(&pool)
             .await
-            .with_context(|| format!("failed to create new db schema"))?;
+            .with_context(|| "failed to create new db schema".to_string())?;
     }
     check_and_update_schema(&pool).await?;
 
@@ -1018,26 +3092,161 @@ async fn main() -> Result<()> {
             prefix: None,
             key: v,
         });
-    let immich_client = ImmichClient::new(10, &args.immich_url, api_key, args.read_only);
+    let retry = RetryPolicy {
+        max_attempts: args.max_attempts.max(1),
+        initial_backoff: Duration::from_millis(args.retry_backoff_ms),
+        ..Default::default()
+    };
+    // Enough api clients for everything that can talk to immich at the same time.
+    let immich_client = ImmichClient::new(
+        (args.search_concurrency + args.upload_concurrency + args.album_concurrency).max(1),
+        &args.immich_url,
+        api_key,
+        args.read_only,
+        Duration::from_secs(args.immich_timeout_secs),
+    )?
+    .with_retry(retry.clone())
+    .with_upload_throttle(Throttle::new(
+        args.upload_concurrency,
+        args.upload_bytes_per_sec,
+    ));
 
-    if !std::path::Path::new(&args.auth_token).exists() {
-        warn!(
-            "auth file {:?} does not exist, will request new auth",
-            args.auth_token
+    match &args.command {
+        Some(Command::Review) => {
+            review(&pool, &immich_client, args.read_only).await?;
+            return Ok(());
+        }
+        Some(Command::Report { output }) => {
+            let n = write_report(&pool, &immich_client, &match_thresholds(&args), output).await?;
+            info!("wrote {n} items to {output}");
+            return Ok(());
+        }
+        Some(Command::CheckAlbums) => {
+            check_albums(&pool, &immich_client).await?;
+            println!("stats: {:?}", STATS.lock().unwrap());
+            return Ok(());
+        }
+        Some(Command::Upgrade) => {
+            if args.takeout.is_empty() {
+                return Err(anyhow!("upgrade needs at least one --takeout archive"));
+            }
+            let takeout = read_takeout(&args).await?;
+            let buffers =
+                MediaBuffers::new(args.memory_budget_mb << 20, args.spill_threshold_mb << 20);
+            upgrade(&args, &multi, &pool, &immich_client, &buffers, &takeout).await?;
+            log_immich_pool(&immich_client);
+            println!("stats: {:?}", STATS.lock().unwrap());
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if args.backfill {
+        if args.takeout.is_empty() {
+            return Err(anyhow!("--backfill needs at least one --takeout archive"));
+        }
+        let takeout = read_takeout(&args).await?;
+        backfill(&args, &multi, &pool, &immich_client, &takeout).await?;
+        log_immich_pool(&immich_client);
+        println!("stats: {:?}", STATS.lock().unwrap());
+        return Ok(());
+    }
+
+    if args.client_secret.len() != args.auth_token.len() {
+        return Err(anyhow!(
+            "got {} --client-secret but {} --auth-token, they must come in pairs",
+            args.client_secret.len(),
+            args.auth_token.len()
+        ));
+    }
+    let gphoto_files: Vec<(String, String)> = args
+        .client_secret
+        .iter()
+        .cloned()
+        .zip(args.auth_token.iter().cloned())
+        .collect();
+    for (client_secret, auth_token) in &gphoto_files {
+        if !std::path::Path::new(auth_token).exists() {
+            warn!(
+                "auth file {:?} does not exist, will request new auth",
+                auth_token
+            );
+            get_auth(client_secret, auth_token).await?;
+        }
+    }
+    let gphoto_client = GPClient::new_from_files(&gphoto_files)
+        .await?
+        .with_quota(
+            &pool,
+            args.max_api_requests,
+            args.max_downloads,
+            args.read_only,
+        )
+        .await?
+        .with_retry(retry)
+        .with_throttles(
+            Throttle::new(args.list_concurrency, None),
+            Throttle::new(args.download_concurrency, args.download_bytes_per_sec),
         );
-        get_auth(&args.client_secret, &args.auth_token).await?;
+    let buffers = MediaBuffers::new(args.memory_budget_mb << 20, args.spill_threshold_mb << 20);
+
+    let immich_client = if args.immich_index {
+        let index = ImmichIndex::new(pool.clone());
+        if args.read_only {
+            info!("read-only, using the immich index without refreshing it");
+        } else {
+            index.refresh(&immich_client).await?;
+        }
+        if index.len().await? == 0 {
+            warn!("immich index is empty, searching immich instead");
+            immich_client
+        } else {
+            immich_client.with_index(index)
+        }
+    } else {
+        immich_client
+    };
+
+    if args.resume {
+        let (scan_result, search_result) =
+            load_pending_uploads(&args, &pool, &gphoto_client).await?;
+        write(
+            &args,
+            &multi,
+            &search_result,
+            &scan_result,
+            &pool,
+            &immich_client,
+            &gphoto_client,
+            &buffers,
+        )
+        .await?;
+        search_result.log_summary();
+        log_quota(&gphoto_client).await;
+        log_immich_pool(&immich_client);
+        println!("stats: {:?}", STATS.lock().unwrap());
+        return Ok(());
     }
-    let gphoto_client = GPClient::new_from_file(&args.client_secret, &args.auth_token).await?;
 
     let scan_result = scan(&pool, &args, &multi, &gphoto_client).await?;
-    let search_result = search(&multi, &scan_result, &pool, &immich_client).await?;
+    let search_result = search(
+        &args,
+        &multi,
+        &scan_result,
+        &pool,
+        &immich_client,
+        &gphoto_client,
+    )
+    .await?;
     write(
+        &args,
         &multi,
         &search_result,
         &scan_result,
         &pool,
         &immich_client,
         &gphoto_client,
+        &buffers,
     )
     .await?;
 
@@ -1047,7 +3256,77 @@ async fn main() -> Result<()> {
         scan_result.albums.len()
     );
     search_result.log_summary();
+    log_quota(&gphoto_client).await;
+    log_immich_pool(&immich_client);
 
     println!("stats: {:?}", STATS.lock().unwrap());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_match_policy() {
+        let id = || ImmichItemId("immich".to_string());
+        let lookups = [
+            LookupResult::NotFound,
+            LookupResult::FoundMultiple,
+            LookupResult::FoundUnique(id()),
+            LookupResult::MatchedMultiple,
+            LookupResult::MatchedUnique(id()),
+            LookupResult::MatchedUniqueDB(id()),
+            LookupResult::MatchedPerceptual(id()),
+        ];
+        let policies = [
+            MatchPolicy::Strict,
+            MatchPolicy::FilenameUnique,
+            MatchPolicy::CreateOnAmbiguous,
+            MatchPolicy::LinkOnFilenameUnique,
+        ];
+        // Outcome for each lookup result (rows) and policy (columns), with the link type recorded.
+        let expected: [[(&str, Option<&str>); 4]; 7] = [
+            [("create", None); 4],
+            [
+                ("unknown", None),
+                ("unknown", None),
+                ("create", Some("FoundMultiple:create-on-ambiguous")),
+                ("unknown", None),
+            ],
+            [
+                ("unknown", None),
+                ("create", Some("FoundUnique:filename-unique")),
+                ("create", Some("FoundUnique:create-on-ambiguous")),
+                ("found", Some("FoundUnique:link-on-filename-unique")),
+            ],
+            [("unknown", None); 4],
+            [("found", None); 4],
+            [("db", None); 4],
+            [("found", Some("MatchedPerceptual")); 4],
+        ];
+        for (lookup, row) in lookups.iter().zip(expected) {
+            for (policy, (outcome, link_type)) in policies.iter().zip(row) {
+                let (x, policy_link_type) =
+                    apply_match_policy(*policy, lookup.clone(), String::new());
+                let x = match x {
+                    ElementLinkResult::ExistsInDB(i) => {
+                        assert_eq!(i, id());
+                        "db"
+                    }
+                    ElementLinkResult::Found(i) => {
+                        assert_eq!(i, id());
+                        "found"
+                    }
+                    ElementLinkResult::CreateNew(_) => "create",
+                    ElementLinkResult::Unknown(_) => "unknown",
+                };
+                assert_eq!(
+                    (x, policy_link_type.as_deref()),
+                    (outcome, link_type),
+                    "{lookup:?} with {policy}"
+                );
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Context;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Offset, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
 use immich_api::models;
 use itertools::Itertools;
 use std::mem;
@@ -35,9 +36,9 @@ impl TryFrom<&gphotos_api::models::MediaItemMediaMetadataPhoto> for PhotoMetadat
         Ok(PhotoMetadata {
             camera_make: value.camera_make.clone(),
             camera_model: value.camera_model.clone(),
-            focal_length: value.focal_length.clone(),
-            aperture_f_number: value.aperture_f_number.clone(),
-            iso_equivalent: value.iso_equivalent.clone(),
+            focal_length: value.focal_length,
+            aperture_f_number: value.aperture_f_number,
+            iso_equivalent: value.iso_equivalent,
             exposure_time: value
                 .exposure_time
                 .clone()
@@ -53,7 +54,7 @@ impl TryFrom<&gphotos_api::models::MediaItemMediaMetadataPhoto> for PhotoMetadat
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+#[derive(Debug, PartialEq, PartialOrd, Default, Clone)]
 pub struct ImageData {
     // Immich has several times in the metadata, gphoto only one. We try to see if any match.
     all_times: Vec<DateTime<Utc>>,
@@ -61,6 +62,8 @@ pub struct ImageData {
     height: Option<f64>,
     photo: Option<PhotoMetadata>,
     video: Option<VideoMetadata>,
+    // Google Photos doesn't tell, so this is only known for immich items.
+    file_size: Option<i64>,
 }
 impl TryFrom<&gphotos_api::models::MediaItemMediaMetadata> for ImageData {
     type Error = anyhow::Error;
@@ -81,6 +84,7 @@ impl TryFrom<&gphotos_api::models::MediaItemMediaMetadata> for ImageData {
                 .map(|x| x.as_ref().try_into())
                 .transpose()?,
             video: value.video.clone().map(|x| x.as_ref().into()),
+            file_size: None,
         })
     }
 }
@@ -100,27 +104,106 @@ fn cmp_hf(a: Option<f64>, b: Option<f64>) -> bool {
 // Compares metadata. Returns false if we have good confidence that metadata differs.
 // OTOH true could just mean that there was no metadata present, or it could be indeed the same.
 pub fn compare_metadata(a: &ImageData, b: &ImageData) -> bool {
-    let mut a = a.clone();
-    let mut b = b.clone();
+    mismatched_fields(a, b, &MatchThresholds::default()).is_empty()
+}
 
-    let mut has_match = false;
-    for t_a in &a.all_times {
-        for t_b in &b.all_times {
-            if t_a.timestamp() == t_b.timestamp() {
-                has_match = true;
-                break;
-            }
-        }
+// How the closest times of two items relate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimeMatch {
+    // At most the skew apart.
+    Same,
+    // A whole or half number of hours apart (give or take the skew), as happens when a local time
+    // was stored as UTC somewhere. `distance` is in seconds.
+    Offset { offset: i64, distance: i64 },
+    Different { distance: i64 },
+    // One of the items has no time at all.
+    Unknown,
+}
+
+// Local times go up to UTC+14.
+const MAX_OFFSET_SECS: i64 = 14 * 3600;
+const HALF_HOUR_SECS: i64 = 1800;
+
+// Videos: rounding when downsizing changes the aspect ratio a little. Duration and frame rate
+// are not compared, gphoto only knows the frame rate and immich only the duration.
+const ASPECT_RATIO_TOLERANCE: f64 = 0.02;
+
+fn match_times(a: &ImageData, b: &ImageData, skew_secs: i64) -> TimeMatch {
+    let distances: Vec<_> = a
+        .all_times
+        .iter()
+        .cartesian_product(&b.all_times)
+        .map(|(x, y)| (x.timestamp() - y.timestamp()).abs())
+        .collect();
+    let Some(&distance) = distances.iter().min() else {
+        return TimeMatch::Unknown;
+    };
+    if distance <= skew_secs {
+        return TimeMatch::Same;
     }
-    if !has_match {
-        return false;
+    distances
+        .iter()
+        .filter_map(|&d| {
+            let offset = (d + HALF_HOUR_SECS / 2) / HALF_HOUR_SECS * HALF_HOUR_SECS;
+            (offset > 0 && offset <= MAX_OFFSET_SECS && (d - offset).abs() <= skew_secs)
+                .then_some(offset)
+        })
+        .min()
+        .map(|offset| TimeMatch::Offset { offset, distance })
+        .unwrap_or(TimeMatch::Different { distance })
+}
+
+// Whether some field other than the time positively agrees, which is needed before a time offset
+// is put down to time zones.
+fn corroborated(a: &ImageData, b: &ImageData) -> bool {
+    let camera = |x: &ImageData| match (&x.photo, &x.video) {
+        (Some(p), _) => (p.camera_make.clone(), p.camera_model.clone()),
+        (_, Some(v)) => (v.camera_make.clone(), v.camera_model.clone()),
+        _ => (None, None),
+    };
+    let (make_a, model_a) = camera(a);
+    let (make_b, model_b) = camera(b);
+    let dimensions = |x: &ImageData| match (x.width, x.height) {
+        (Some(w), Some(h)) => Some((w.max(h), w.min(h))),
+        _ => None,
+    };
+    (make_a.is_some() && make_a == make_b)
+        || (model_a.is_some() && model_a == model_b)
+        || (a.photo.is_some() && dimensions(a).is_some() && dimensions(a) == dimensions(b))
+}
+
+// Names of the fields on which the metadata differs, see compare_metadata. Names are the ones
+// used by ImageData::fields.
+pub fn mismatched_fields(
+    a: &ImageData,
+    b: &ImageData,
+    thresholds: &MatchThresholds,
+) -> Vec<&'static str> {
+    let time_match = match_times(a, b, thresholds.time_tolerance_secs);
+    let mut rv = other_mismatched_fields(a, b);
+    let time_ok = match time_match {
+        TimeMatch::Same => true,
+        TimeMatch::Offset { .. } => rv.is_empty() && corroborated(a, b),
+        TimeMatch::Different { .. } | TimeMatch::Unknown => false,
+    };
+    if !time_ok {
+        rv.insert(0, "time");
     }
+    rv
+}
+
+fn other_mismatched_fields(a: &ImageData, b: &ImageData) -> Vec<&'static str> {
+    let mut a = a.clone();
+    let mut b = b.clone();
+    let mut rv = vec![];
 
-    if (a.photo.is_some() && b.photo.is_none()) || (b.photo.is_some() && a.photo.is_none()) {
-        return false;
+    if a.photo.is_some() != b.photo.is_some() {
+        rv.push("type");
+        return rv;
     }
-    if a.photo.is_some() {
+    if let (Some(pa), Some(pb)) = (&a.photo, &b.photo) {
         // gphoto downsizes videos to 1080p, so only look at height and width on photos
+        let (pa, pb) = (pa.clone(), pb.clone());
 
         // allow for flips, for some reason immich and gphoto like to flip
         for x in [&mut a, &mut b] {
@@ -129,49 +212,377 @@ pub fn compare_metadata(a: &ImageData, b: &ImageData) -> bool {
             }
         }
         if cmp_h(a.width, b.width) {
-            // println!("width");
-            return false;
+            rv.push("width");
         }
         if cmp_h(a.height, b.height) {
-            // println!("height");
-            return false;
+            rv.push("height");
         }
-        let a = a.photo.unwrap();
-        let b = b.photo.unwrap();
 
-        if cmp_h(a.camera_make, b.camera_make) {
-            return false;
+        if cmp_h(pa.camera_make, pb.camera_make) {
+            rv.push("camera_make");
         }
-        if cmp_h(a.camera_model, b.camera_model) {
-            return false;
+        if cmp_h(pa.camera_model, pb.camera_model) {
+            rv.push("camera_model");
         }
-        if cmp_h(a.iso_equivalent, b.iso_equivalent) {
-            return false;
+        if cmp_h(pa.iso_equivalent, pb.iso_equivalent) {
+            rv.push("iso");
         }
-        if cmp_hf(a.focal_length, b.focal_length) {
-            return false;
+        if cmp_hf(pa.focal_length, pb.focal_length) {
+            rv.push("focal_length");
         }
-        if cmp_hf(a.aperture_f_number, b.aperture_f_number) {
-            return false;
+        if cmp_hf(pa.aperture_f_number, pb.aperture_f_number) {
+            rv.push("aperture");
         }
-        if cmp_hf(a.exposure_time, b.exposure_time) {
-            return false;
+        if cmp_hf(pa.exposure_time, pb.exposure_time) {
+            rv.push("exposure_time");
         }
     }
 
-    if a.video.is_some() {
-        let a = a.video.unwrap();
-        let b = b.video.unwrap();
+    if let (Some(va), Some(vb)) = (&a.video, &b.video) {
+        // Transcodes are smaller than the original but keep its shape.
+        if let (Some(ra), Some(rb)) = (aspect_ratio(&a), aspect_ratio(&b)) {
+            if ratio(ra, rb) < 1.0 - ASPECT_RATIO_TOLERANCE {
+                rv.push("aspect_ratio");
+            }
+        }
+        if cmp_h(va.camera_make.clone(), vb.camera_make.clone()) {
+            rv.push("camera_make");
+        }
+        if cmp_h(va.camera_model.clone(), vb.camera_model.clone()) {
+            rv.push("camera_model");
+        }
+    }
+
+    rv
+}
+
+// Long side over short side, so flips don't matter.
+fn aspect_ratio(x: &ImageData) -> Option<f64> {
+    match (x.width, x.height) {
+        (Some(w), Some(h)) if w > 0.0 && h > 0.0 => Some(w.max(h) / w.min(h)),
+        _ => None,
+    }
+}
+
+fn show<X: std::fmt::Display>(x: &Option<X>) -> String {
+    x.as_ref().map(|x| x.to_string()).unwrap_or_default()
+}
 
-        if cmp_h(a.camera_make, b.camera_make) {
-            return false;
+impl ImageData {
+    // Fields as (name, value) pairs, for showing to humans.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let photo = self.photo.clone().unwrap_or_default();
+        let video = self.video.clone().unwrap_or_default();
+        let (camera_make, camera_model) = if self.photo.is_some() {
+            (photo.camera_make.clone(), photo.camera_model.clone())
+        } else {
+            (video.camera_make.clone(), video.camera_model.clone())
+        };
+        vec![
+            (
+                "time",
+                self.all_times.iter().map(|t| t.to_rfc3339()).join(", "),
+            ),
+            (
+                "type",
+                match (&self.photo, &self.video) {
+                    (Some(_), _) => "photo",
+                    (_, Some(_)) => "video",
+                    _ => "",
+                }
+                .to_string(),
+            ),
+            ("width", show(&self.width)),
+            ("height", show(&self.height)),
+            ("camera_make", show(&camera_make)),
+            ("camera_model", show(&camera_model)),
+            ("iso", show(&photo.iso_equivalent)),
+            ("focal_length", show(&photo.focal_length)),
+            ("aperture", show(&photo.aperture_f_number)),
+            ("exposure_time", show(&photo.exposure_time)),
+            ("file_size", show(&self.file_size)),
+            (
+                "aspect_ratio",
+                show(
+                    &self
+                        .video
+                        .as_ref()
+                        .and_then(|_| aspect_ratio(self))
+                        .map(|r| format!("{r:.3}")),
+                ),
+            ),
+        ]
+    }
+}
+
+// Knobs for score_metadata and for picking between candidates.
+#[derive(Debug, Clone)]
+pub struct MatchThresholds {
+    // Times at most this far apart count as the same (also on top of time zone offsets).
+    pub time_tolerance_secs: i64,
+    // Past the tolerance, the time score drops to 0 over this many seconds.
+    pub time_window_secs: i64,
+    // Candidates with a lower confidence don't match.
+    pub min_confidence: f64,
+    // When several candidates match, the best one is only taken if it is this far ahead.
+    pub margin: f64,
+}
+
+impl Default for MatchThresholds {
+    fn default() -> Self {
+        MatchThresholds {
+            time_tolerance_secs: 0,
+            time_window_secs: 600,
+            min_confidence: 0.9,
+            margin: 0.05,
         }
-        if cmp_h(a.camera_model, b.camera_model) {
-            return false;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldScore {
+    pub field: &'static str,
+    // 1 is a perfect match, 0 is a clear mismatch.
+    pub score: f64,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchScore {
+    pub fields: Vec<FieldScore>,
+    // Weighted average of the field scores, 0 if the items are of different types.
+    pub confidence: f64,
+}
+
+impl MatchScore {
+    pub fn is_match(&self, thresholds: &MatchThresholds) -> bool {
+        self.confidence >= thresholds.min_confidence
+    }
+}
+
+impl std::fmt::Display for MatchScore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2} ({})",
+            self.confidence,
+            self.fields
+                .iter()
+                .map(|x| format!("{} {:.2}", x.field, x.score))
+                .join(", ")
+        )
+    }
+}
+
+fn ratio(a: f64, b: f64) -> f64 {
+    if a == b {
+        1.0
+    } else if a <= 0.0 || b <= 0.0 {
+        0.0
+    } else {
+        a.min(b) / a.max(b)
+    }
+}
+
+// Same tolerance as cmp_hf, past it the score is the ratio of the two.
+fn close(a: f64, b: f64) -> f64 {
+    if (a - b).abs() <= 1e-1 {
+        1.0
+    } else {
+        ratio(a, b)
+    }
+}
+
+fn mean(xs: &[f64]) -> Option<f64> {
+    if xs.is_empty() {
+        None
+    } else {
+        Some(xs.iter().sum::<f64>() / xs.len() as f64)
+    }
+}
+
+fn same<X: PartialEq>(a: &Option<X>, b: &Option<X>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a == b { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
+
+// Scores how likely it is that `a` and `b` are the same item. Unlike compare_metadata, fields
+// only count when both sides know them, and near misses get partial scores.
+pub fn score_metadata(a: &ImageData, b: &ImageData, thresholds: &MatchThresholds) -> MatchScore {
+    let mut fields: Vec<FieldScore> = vec![];
+    let mut add = |field, score: Option<f64>, weight| {
+        if let Some(score) = score {
+            fields.push(FieldScore {
+                field,
+                score,
+                weight,
+            });
         }
+    };
+
+    let time_match = match_times(a, b, thresholds.time_tolerance_secs);
+    let by_distance = |d: i64| {
+        let over = (d - thresholds.time_tolerance_secs).max(0) as f64;
+        (1.0 - over / thresholds.time_window_secs.max(1) as f64).max(0.0)
+    };
+    add(
+        "time",
+        match time_match {
+            TimeMatch::Same => Some(1.0),
+            TimeMatch::Offset { distance, .. } | TimeMatch::Different { distance } => {
+                Some(by_distance(distance))
+            }
+            TimeMatch::Unknown => None,
+        },
+        3.0,
+    );
+
+    if a.photo.is_some() != b.photo.is_some() {
+        add("type", Some(0.0), 1.0);
+        return MatchScore {
+            fields,
+            confidence: 0.0,
+        };
     }
 
-    true
+    let (make_a, model_a, make_b, model_b) = match (&a.photo, &b.photo, &a.video, &b.video) {
+        (Some(pa), Some(pb), _, _) => (
+            &pa.camera_make,
+            &pa.camera_model,
+            &pb.camera_make,
+            &pb.camera_model,
+        ),
+        (_, _, Some(va), Some(vb)) => (
+            &va.camera_make,
+            &va.camera_model,
+            &vb.camera_make,
+            &vb.camera_model,
+        ),
+        _ => (&None, &None, &None, &None),
+    };
+    let camera: Vec<_> = [same(make_a, make_b), same(model_a, model_b)]
+        .into_iter()
+        .flatten()
+        .collect();
+    add("camera", mean(&camera), 1.0);
+
+    if let (Some(pa), Some(pb)) = (&a.photo, &b.photo) {
+        // gphoto downsizes videos, so only photos are compared. Flips are fine.
+        let dimensions = |x: &ImageData| match (x.width, x.height) {
+            (Some(w), Some(h)) => Some((w.max(h), w.min(h))),
+            _ => None,
+        };
+        add(
+            "dimensions",
+            match (dimensions(a), dimensions(b)) {
+                (Some((wa, ha)), Some((wb, hb))) => Some(ratio(wa, wb).min(ratio(ha, hb))),
+                _ => None,
+            },
+            1.0,
+        );
+
+        let exposure: Vec<_> = [
+            (pa.focal_length, pb.focal_length),
+            (pa.aperture_f_number, pb.aperture_f_number),
+            (pa.exposure_time, pb.exposure_time),
+        ]
+        .into_iter()
+        .filter_map(|x| match x {
+            (Some(a), Some(b)) => Some(close(a, b)),
+            _ => None,
+        })
+        .collect();
+        add("exposure", mean(&exposure), 1.0);
+
+        add(
+            "iso",
+            match (pa.iso_equivalent, pb.iso_equivalent) {
+                (Some(a), Some(b)) => Some(ratio(a as f64, b as f64)),
+                _ => None,
+            },
+            1.0,
+        );
+    }
+
+    if a.video.is_some() && b.video.is_some() {
+        // Sizes of transcodes are off, their shape is not.
+        let shape = match (aspect_ratio(a), aspect_ratio(b)) {
+            (Some(ra), Some(rb)) if ratio(ra, rb) >= 1.0 - ASPECT_RATIO_TOLERANCE => Some(1.0),
+            (Some(ra), Some(rb)) => Some(ratio(ra, rb)),
+            _ => None,
+        };
+        add("video", shape, 1.0);
+    }
+
+    add(
+        "file_size",
+        match (a.file_size, b.file_size) {
+            (Some(a), Some(b)) => Some(ratio(a as f64, b as f64)),
+            _ => None,
+        },
+        1.0,
+    );
+
+    let confidence = |fields: &[FieldScore]| {
+        let total_weight: f64 = fields.iter().map(|x| x.weight).sum();
+        if total_weight > 0.0 {
+            fields.iter().map(|x| x.score * x.weight).sum::<f64>() / total_weight
+        } else {
+            // Nothing to go by, same as compare_metadata.
+            1.0
+        }
+    };
+    // An offset of whole or half hours is put down to time zones if everything else agrees.
+    if let TimeMatch::Offset { .. } = time_match {
+        if confidence(&fields[1..]) >= thresholds.min_confidence && corroborated(a, b) {
+            fields[0].score = 1.0;
+        }
+    }
+    let confidence = confidence(&fields);
+    MatchScore { fields, confidence }
+}
+
+// Picks the best of the candidates that match: the only one, or the one that is ahead of the
+// rest by a clear margin. Returns None if nothing matches or it is too close to call.
+pub fn pick_best(scores: &[MatchScore], thresholds: &MatchThresholds) -> Option<usize> {
+    let mut matching: Vec<_> = scores
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.is_match(thresholds))
+        .collect();
+    matching.sort_by(|(_, x), (_, y)| y.confidence.total_cmp(&x.confidence));
+    match matching.as_slice() {
+        [(i, _)] => Some(*i),
+        [(i, best), (_, second), ..]
+            if best.confidence - second.confidence >= thresholds.margin =>
+        {
+            Some(*i)
+        }
+        _ => None,
+    }
+}
+
+// Offset from UTC at the given local time. Immich time zones are either "UTC", fixed offsets like
+// "UTC+2" or "UTC-03:30", or IANA names like "Europe/Zurich" (where the offset depends on DST).
+fn utc_offset(time_zone: &str, local: &NaiveDateTime) -> Option<TimeDelta> {
+    if let Some(offset) = time_zone.strip_prefix("UTC") {
+        if offset.is_empty() {
+            return Some(TimeDelta::zero());
+        }
+        let (sign, offset) = match offset.split_at(1) {
+            ("+", rest) => (1, rest),
+            ("-", rest) => (-1, rest),
+            _ => return None,
+        };
+        let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+        let minutes = hours.parse::<i64>().ok()? * 60 + minutes.parse::<i64>().ok()?;
+        return Some(TimeDelta::minutes(sign * minutes));
+    }
+    let tz: Tz = time_zone.parse().ok()?;
+    let offset = tz.offset_from_local_datetime(local).earliest()?.fix();
+    Some(TimeDelta::seconds(offset.local_minus_utc() as i64))
 }
 
 impl From<models::AssetResponseDto> for ImageData {
@@ -222,6 +633,19 @@ impl From<models::AssetResponseDto> for ImageData {
                 );
             }
         }
+        // localDateTime is the local time written down as if it was UTC. With the time zone known
+        // it can be turned back into the actual time.
+        if let Some(time_zone) = exif
+            .as_ref()
+            .and_then(|exif| exif.time_zone.clone().flatten())
+        {
+            let local = DateTime::parse_from_rfc3339(&value.local_date_time)
+                .unwrap()
+                .naive_utc();
+            if let Some(offset) = utc_offset(&time_zone, &local) {
+                all_times.push((local - offset).and_utc());
+            }
+        }
         let all_times = all_times.into_iter().unique().collect::<Vec<_>>();
 
         ImageData {
@@ -237,12 +661,15 @@ impl From<models::AssetResponseDto> for ImageData {
                     camera_make: exif
                         .as_ref()
                         .and_then(|exif| exif.make.clone().flatten())
-                        .and_then(|x| if x == "" { None } else { Some(x) }),
+                        .and_then(|x| if x.is_empty() { None } else { Some(x) }),
                     camera_model: exif.as_ref().and_then(|exif| {
-                        exif.model
-                            .clone()
-                            .flatten()
-                            .and_then(|x| if x == "" { None } else { Some(x) })
+                        exif.model.clone().flatten().and_then(|x| {
+                            if x.is_empty() {
+                                None
+                            } else {
+                                Some(x)
+                            }
+                        })
                     }),
                     aperture_f_number: exif.as_ref().and_then(|exif| exif.f_number.flatten()),
                     focal_length: exif.as_ref().and_then(|exif| exif.focal_length.flatten()),
@@ -250,7 +677,6 @@ impl From<models::AssetResponseDto> for ImageData {
                         .as_ref()
                         .and_then(|exif| exif.iso.flatten().map(|x| x as i32)),
                     exposure_time,
-                    ..Default::default()
                 })
             } else {
                 None
@@ -263,6 +689,9 @@ impl From<models::AssetResponseDto> for ImageData {
             } else {
                 None
             },
+            file_size: exif
+                .as_ref()
+                .and_then(|exif| exif.file_size_in_byte.flatten()),
         }
     }
 }
@@ -338,6 +767,10 @@ mod tests {
             .into();
 
         assert!(!compare_metadata(&g, &i));
+        assert_eq!(
+            mismatched_fields(&g, &i, &MatchThresholds::default()),
+            vec!["time"]
+        );
     }
     #[test]
     fn test_video_ignores_height_width() {
@@ -354,6 +787,37 @@ mod tests {
 
         assert!(compare_metadata(&g, &i));
     }
+
+    fn video(width: f64, height: f64) -> ImageData {
+        ImageData {
+            all_times: vec![DateTime::parse_from_rfc3339("2024-07-14T14:44:38Z")
+                .unwrap()
+                .into()],
+            width: Some(width),
+            height: Some(height),
+            video: Some(VideoMetadata::default()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_video_fields() {
+        let t = MatchThresholds::default();
+
+        // A 720p transcode of a 4k original.
+        let original = video(2160.0, 3840.0);
+        let transcode = video(1280.0, 720.0);
+        assert!(mismatched_fields(&original, &transcode, &t).is_empty());
+        assert_eq!(score_metadata(&original, &transcode, &t).confidence, 1.0);
+
+        // Another shape.
+        let other = video(1080.0, 1080.0);
+        assert_eq!(
+            mismatched_fields(&original, &other, &t),
+            vec!["aspect_ratio"]
+        );
+        assert!(!score_metadata(&original, &other, &t).is_match(&t));
+    }
     #[test]
     fn test_should_match() {
         let gphoto_metadata = r#"{"creationTime":"2024-06-27T19:35:56.496Z","width":"3072","height":"4080","photo":{"cameraMake":"Google","cameraModel":"Pixel 6","focalLength":6.81,"apertureFNumber":1.85,"isoEquivalent":104,"exposureTime":"0.041997s"}}"#;
@@ -384,4 +848,207 @@ mod tests {
 
         assert!(compare_metadata(&g, &i));
     }
+
+    fn photo(time: &str, width: f64, height: f64, model: &str, iso: i32) -> ImageData {
+        ImageData {
+            all_times: vec![DateTime::parse_from_rfc3339(time).unwrap().into()],
+            width: Some(width),
+            height: Some(height),
+            photo: Some(PhotoMetadata {
+                camera_make: Some("Google".to_string()),
+                camera_model: Some(model.to_string()),
+                iso_equivalent: Some(iso),
+                aperture_f_number: Some(1.85),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_score() {
+        let t = MatchThresholds::default();
+        let g = photo("2024-06-27T19:35:56Z", 3072.0, 4080.0, "Pixel 6", 104);
+
+        let same = score_metadata(
+            &g,
+            &photo("2024-06-27T19:35:56Z", 4080.0, 3072.0, "Pixel 6", 104),
+            &t,
+        );
+        assert_eq!(same.confidence, 1.0);
+        assert_eq!(
+            same.fields.iter().map(|x| x.field).collect::<Vec<_>>(),
+            vec!["time", "camera", "dimensions", "exposure", "iso"]
+        );
+
+        // Two minutes off and a bit smaller (e.g. an edited copy) is a near miss that still matches.
+        let near = score_metadata(
+            &g,
+            &photo("2024-06-27T19:37:56Z", 3000.0, 4000.0, "Pixel 6", 104),
+            &t,
+        );
+        assert!(near.confidence < 1.0);
+        assert!(near.is_match(&t));
+
+        // Another phone, an hour later.
+        let far = score_metadata(
+            &g,
+            &photo("2024-06-27T20:35:56Z", 3072.0, 4080.0, "Pixel 8", 400),
+            &t,
+        );
+        assert!(far.confidence < 0.5);
+        assert!(!far.is_match(&t));
+
+        // A video is never the same item as a photo.
+        let video = ImageData {
+            all_times: g.all_times.clone(),
+            video: Some(VideoMetadata::default()),
+            ..Default::default()
+        };
+        assert_eq!(score_metadata(&g, &video, &t).confidence, 0.0);
+
+        assert_eq!(pick_best(&[far.clone(), same.clone()], &t), Some(1));
+        assert_eq!(
+            pick_best(&[near.clone(), far.clone(), same.clone()], &t),
+            Some(2)
+        );
+        // Too close to call.
+        assert_eq!(pick_best(&[same.clone(), same.clone()], &t), None);
+        assert_eq!(pick_best(&[far], &t), None);
+    }
+
+    // An immich item that only has the right time in localDateTime.
+    fn immich_local(local_date_time: &str, time_zone: Option<&str>) -> ImageData {
+        AssetResponseDto {
+            file_created_at: "2020-01-01T00:00:00Z".to_string(),
+            file_modified_at: "2020-01-01T00:00:00Z".to_string(),
+            local_date_time: local_date_time.to_string(),
+            exif_info: Some(Box::new(models::ExifResponseDto {
+                time_zone: Some(time_zone.map(|x| x.to_string())),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn at(time: &str) -> ImageData {
+        ImageData {
+            all_times: vec![DateTime::parse_from_rfc3339(time).unwrap().into()],
+            photo: Some(PhotoMetadata::default()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_time_zone_fixed_offset() {
+        let g = at("2024-06-27T19:35:56Z");
+        assert!(compare_metadata(
+            &g,
+            &immich_local("2024-06-27T21:35:56.496Z", Some("UTC+2"))
+        ));
+        assert!(compare_metadata(
+            &g,
+            &immich_local("2024-06-27T19:35:56Z", Some("UTC"))
+        ));
+        assert!(compare_metadata(
+            &g,
+            &immich_local("2024-06-27T14:35:56Z", Some("UTC-5"))
+        ));
+        assert!(compare_metadata(
+            &g,
+            &immich_local("2024-06-28T01:05:56Z", Some("UTC+05:30"))
+        ));
+        // Wrong zone.
+        assert!(!compare_metadata(
+            &g,
+            &immich_local("2024-06-27T21:35:56Z", Some("UTC+3"))
+        ));
+    }
+
+    #[test]
+    fn test_time_zone_dst() {
+        // Zurich is UTC+2 in summer and UTC+1 in winter.
+        let summer = at("2024-06-30T17:52:38Z");
+        let winter = at("2024-01-15T11:00:00Z");
+        assert!(compare_metadata(
+            &summer,
+            &immich_local("2024-06-30T19:52:38Z", Some("Europe/Zurich"))
+        ));
+        assert!(compare_metadata(
+            &winter,
+            &immich_local("2024-01-15T12:00:00Z", Some("Europe/Zurich"))
+        ));
+        assert!(!compare_metadata(
+            &winter,
+            &immich_local("2024-01-15T13:00:00Z", Some("Europe/Zurich"))
+        ));
+        // Right after the switch to summer time (02:00 local becomes 03:00).
+        let after_switch = at("2024-03-31T01:30:00Z");
+        assert!(compare_metadata(
+            &after_switch,
+            &immich_local("2024-03-31T03:30:00Z", Some("Europe/Zurich"))
+        ));
+        // Unknown zones are ignored.
+        assert!(!compare_metadata(
+            &summer,
+            &immich_local("2024-06-30T19:52:38Z", Some("Mars/Olympus"))
+        ));
+    }
+
+    #[test]
+    fn test_time_offset_and_skew() {
+        let camera = |mut x: ImageData, model: &str| {
+            x.photo = Some(PhotoMetadata {
+                camera_model: Some(model.to_string()),
+                ..Default::default()
+            });
+            x
+        };
+        let g = camera(at("2024-06-27T19:35:56Z"), "Pixel 6");
+        // No time zone in immich, but a whole or half hour apart and the camera agrees.
+        assert!(compare_metadata(
+            &g,
+            &camera(at("2024-06-27T20:35:56Z"), "Pixel 6")
+        ));
+        assert!(compare_metadata(
+            &g,
+            &camera(at("2024-06-27T15:05:56Z"), "Pixel 6")
+        ));
+        // Not if nothing else agrees, or something disagrees.
+        assert!(!compare_metadata(
+            &at("2024-06-27T19:35:56Z"),
+            &at("2024-06-27T20:35:56Z")
+        ));
+        assert!(!compare_metadata(
+            &g,
+            &camera(at("2024-06-27T20:35:56Z"), "Pixel 8")
+        ));
+        // Not a whole or half hour.
+        assert!(!compare_metadata(
+            &g,
+            &camera(at("2024-06-27T20:15:56Z"), "Pixel 6")
+        ));
+
+        // A few seconds off is only fine with a skew.
+        let t = MatchThresholds {
+            time_tolerance_secs: 5,
+            ..Default::default()
+        };
+        let off = camera(at("2024-06-27T19:35:59Z"), "Pixel 6");
+        assert!(!compare_metadata(&g, &off));
+        assert!(mismatched_fields(&g, &off, &t).is_empty());
+        assert!(
+            mismatched_fields(&g, &camera(at("2024-06-27T20:36:00Z"), "Pixel 6"), &t).is_empty()
+        );
+
+        // Scoring agrees.
+        let d = MatchThresholds::default();
+        assert!(
+            score_metadata(&g, &camera(at("2024-06-27T20:35:56Z"), "Pixel 6"), &d).is_match(&d)
+        );
+        assert!(
+            !score_metadata(&g, &camera(at("2024-06-27T20:35:56Z"), "Pixel 8"), &d).is_match(&d)
+        );
+    }
 }
@@ -0,0 +1,297 @@
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use futures::stream::{self, Stream};
+use log::debug;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
+
+use crate::throttle::{chunked, Throttle};
+
+const CHUNK_SIZE: usize = 64 << 10;
+
+// Media files are staged between the download (or Takeout archive) and the Immich upload, which
+// needs the checksum up front. Small files are kept in memory, files above the spill threshold,
+// or ones that don't fit in what is left of the shared memory budget, go to a temporary file.
+// The SHA1 is computed while the data comes in.
+
+#[derive(Clone, Debug)]
+pub struct MediaBuffers {
+    // One permit per byte held in memory across all buffers.
+    budget: Arc<Semaphore>,
+    spill_threshold: usize,
+}
+
+impl MediaBuffers {
+    pub fn new(memory_budget: usize, spill_threshold: usize) -> Self {
+        MediaBuffers {
+            budget: Arc::new(Semaphore::new(memory_budget.min(Semaphore::MAX_PERMITS))),
+            spill_threshold,
+        }
+    }
+    pub fn buffer(&self) -> MediaBuffer {
+        MediaBuffer {
+            budget: self.budget.clone(),
+            spill_threshold: self.spill_threshold,
+            hasher: Sha1::new(),
+            len: 0,
+            data: Data::Memory(Memory {
+                buf: vec![],
+                reserved: 0,
+                budget: self.budget.clone(),
+            }),
+        }
+    }
+}
+
+// Bytes held in memory, returned to the budget when dropped.
+struct Memory {
+    buf: Vec<u8>,
+    reserved: usize,
+    budget: Arc<Semaphore>,
+}
+impl Drop for Memory {
+    fn drop(&mut self) {
+        self.budget.add_permits(self.reserved);
+    }
+}
+
+enum Data {
+    Memory(Memory),
+    File(File),
+}
+
+// A file being written. Implements Write so it can be filled from blocking readers too.
+pub struct MediaBuffer {
+    budget: Arc<Semaphore>,
+    spill_threshold: usize,
+    hasher: Sha1,
+    len: u64,
+    data: Data,
+}
+
+impl MediaBuffer {
+    fn spill(&mut self) -> std::io::Result<()> {
+        let mut file = tempfile::tempfile()?;
+        if let Data::Memory(mem) = &self.data {
+            file.write_all(&mem.buf)?;
+        }
+        debug!("spilling media buffer of {} bytes to disk", self.len);
+        // Drops the in-memory data and returns its budget.
+        self.data = Data::File(file);
+        Ok(())
+    }
+
+    // Appends to the data in memory if it stays under the threshold and fits in the budget.
+    fn try_write_memory(&mut self, buf: &[u8]) -> bool {
+        let Data::Memory(mem) = &mut self.data else {
+            return false;
+        };
+        let fits = mem.buf.len() + buf.len() <= self.spill_threshold
+            && u32::try_from(buf.len())
+                .ok()
+                .and_then(|n| self.budget.try_acquire_many(n).ok())
+                .map(|p| p.forget())
+                .is_some();
+        if !fits {
+            return false;
+        }
+        mem.reserved += buf.len();
+        mem.buf.extend_from_slice(buf);
+        self.hasher.input(buf);
+        self.len += buf.len() as u64;
+        true
+    }
+
+    // Writes from async code. Data that goes to the temporary file (including spilling) is
+    // written on the blocking pool.
+    pub async fn write_chunk(mut self, chunk: bytes::Bytes) -> std::io::Result<Self> {
+        if self.try_write_memory(&chunk) {
+            return Ok(self);
+        }
+        tokio::task::spawn_blocking(move || {
+            self.write_all(&chunk)?;
+            Ok(self)
+        })
+        .await?
+    }
+
+    pub fn finish(self) -> std::io::Result<StagedMedia> {
+        let MediaBuffer {
+            mut hasher,
+            len,
+            data,
+            ..
+        } = self;
+        let data = match data {
+            Data::Memory(mut mem) => {
+                let bytes = bytes::Bytes::from(std::mem::take(&mut mem.buf));
+                StagedData::Memory {
+                    bytes,
+                    _reservation: mem,
+                }
+            }
+            Data::File(mut file) => {
+                file.flush()?;
+                StagedData::File(file)
+            }
+        };
+        Ok(StagedMedia {
+            checksum: hasher.result_str(),
+            len,
+            data,
+        })
+    }
+}
+
+impl Write for MediaBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.try_write_memory(buf) {
+            return Ok(buf.len());
+        }
+        if let Data::Memory(_) = &self.data {
+            self.spill()?;
+        }
+        if let Data::File(file) = &mut self.data {
+            file.write_all(buf)?;
+        }
+        self.hasher.input(buf);
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.data {
+            Data::Memory(_) => Ok(()),
+            Data::File(file) => file.flush(),
+        }
+    }
+}
+
+enum StagedData {
+    Memory {
+        bytes: bytes::Bytes,
+        // Keeps the budget reservation until the upload is done.
+        _reservation: Memory,
+    },
+    File(File),
+}
+
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+// A complete file, ready to be uploaded (possibly more than once, when retrying).
+pub struct StagedMedia {
+    pub checksum: String,
+    pub len: u64,
+    data: StagedData,
+}
+
+impl StagedMedia {
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.data, StagedData::File(_))
+    }
+    // Reads the staged bytes from the start, e.g. to look inside the file before uploading it.
+    pub fn reader(&self) -> std::io::Result<Box<dyn ReadSeek + '_>> {
+        match &self.data {
+            StagedData::Memory { bytes, .. } => Ok(Box::new(std::io::Cursor::new(&bytes[..]))),
+            StagedData::File(file) => {
+                let mut file = file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+    // Body for an upload, streamed from memory or the temporary file at the pace `throttle`
+    // allows.
+    pub fn part(
+        &self,
+        file_name: &str,
+        throttle: &Throttle,
+    ) -> std::io::Result<reqwest::multipart::Part> {
+        let body = match &self.data {
+            StagedData::Memory { bytes, .. } => {
+                reqwest::Body::wrap_stream(throttle.limit(chunked(bytes.clone(), CHUNK_SIZE)))
+            }
+            StagedData::File(file) => {
+                let mut file = file.try_clone()?;
+                file.seek(SeekFrom::Start(0))?;
+                reqwest::Body::wrap_stream(
+                    throttle.limit(file_stream(tokio::fs::File::from_std(file))),
+                )
+            }
+        };
+        Ok(reqwest::multipart::Part::stream_with_length(body, self.len)
+            .file_name(file_name.to_string()))
+    }
+}
+
+fn file_stream(file: tokio::fs::File) -> impl Stream<Item = std::io::Result<bytes::Bytes>> {
+    stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(bytes::Bytes::from(buf)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill() {
+        let buffers = MediaBuffers::new(10, 8);
+
+        // Over the threshold.
+        let mut big = buffers.buffer();
+        big.write_all(b"abcd").unwrap();
+        big.write_all(b"efghi").unwrap();
+        let big = big.finish().unwrap();
+        assert!(big.is_spilled());
+        assert_eq!(big.len, 9);
+
+        // Fits.
+        let mut small = buffers.buffer();
+        small.write_all(b"abcd").unwrap();
+        small.write_all(b"efgh").unwrap();
+        let small = small.finish().unwrap();
+        assert!(!small.is_spilled());
+        assert_eq!(small.len, 8);
+        assert_eq!(small.checksum, "425af12a0743502b322e93a015bcf868e324d56a");
+
+        // Under the threshold, but the budget is held by `small`.
+        let mut other = buffers.buffer();
+        other.write_all(b"abcd").unwrap();
+        assert!(other.finish().unwrap().is_spilled());
+
+        drop(small);
+        let mut other = buffers.buffer();
+        other.write_all(b"abcd").unwrap();
+        assert!(!other.finish().unwrap().is_spilled());
+    }
+
+    #[tokio::test]
+    async fn test_write_chunk() {
+        let buffers = MediaBuffers::new(10, 8);
+        let mut buffer = buffers.buffer();
+        for chunk in ["abcd", "efgh", "i"] {
+            buffer = buffer
+                .write_chunk(bytes::Bytes::from_static(chunk.as_bytes()))
+                .await
+                .unwrap();
+        }
+        let staged = buffer.finish().unwrap();
+        assert!(staged.is_spilled());
+        let mut data = vec![];
+        staged.reader().unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abcdefghi");
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use gphotos_api::models::MediaItem;
+use image::imageops::FilterType;
+use immich_api::apis::{assets_api, search_api};
+use immich_api::models::{self, AssetMediaSize, AssetResponseDto};
+use log::{debug, warn};
+use sqlx::{Pool, Row, Sqlite};
+
+use crate::gpclient::GPClient;
+use crate::immich_client::ImmichClient;
+use crate::now;
+
+// Perceptual hashes for finding items that were renamed, edited or recompressed, so neither the
+// filename nor the checksum matches. Thumbnails are reduced to a difference hash (dHash): 64 bits,
+// each telling whether a pixel of a 9x8 grayscale version is brighter than its right neighbour.
+// Similar looking images have hashes that differ in few bits. Hashes of immich items are kept in
+// the immich_phashes table so each is only fetched and hashed once.
+
+// Size of the box the gphoto thumbnail is fetched in, anything this small hashes the same.
+const THUMBNAIL_SIZE: u32 = 256;
+
+#[derive(Debug, Clone)]
+pub struct PhashOptions {
+    // Immich items taken this long before or after the gphoto item are compared.
+    pub window_secs: i64,
+    // Hashes that differ in at most this many bits look the same.
+    pub max_distance: u32,
+}
+
+pub fn dhash(image_bytes: &[u8]) -> Result<u64> {
+    let image = image::load_from_memory(image_bytes)
+        .with_context(|| "failed to decode thumbnail".to_string())?
+        .to_luma8();
+    let small = image::imageops::resize(&image, 9, 8, FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Ok(hash)
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Hash of the immich thumbnail, from the cache if it was seen before.
+pub async fn immich_phash(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    immich_id: &str,
+) -> Result<u64> {
+    if let Some(row) = sqlx::query(r#"SELECT phash FROM immich_phashes WHERE immich_id = $1"#)
+        .bind(immich_id)
+        .fetch_optional(pool)
+        .await?
+    {
+        return Ok(row.get::<i64, _>("phash") as u64);
+    }
+    let thumbnail = immich_client
+        .retry("view asset", || async {
+            assets_api::view_asset(
+                &*immich_client.get_config().await,
+                immich_id,
+                None,
+                Some(AssetMediaSize::Thumbnail),
+            )
+            .await
+        })
+        .await
+        .with_context(|| format!("failed to get thumbnail of {immich_id}"))?;
+    let hash = dhash(&thumbnail)?;
+    // Only a cache, but read-only means no db writes at all.
+    if !immich_client.read_only {
+        sqlx::query(
+            r#"INSERT OR REPLACE INTO immich_phashes (immich_id, phash, insert_time) VALUES ($1, $2, $3)"#,
+        )
+        .bind(immich_id)
+        .bind(hash as i64)
+        .bind(now())
+        .execute(pool)
+        .await?;
+    }
+    Ok(hash)
+}
+
+async fn search_taken_between(
+    immich_client: &ImmichClient,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    asset_type: models::AssetTypeEnum,
+) -> Result<Vec<AssetResponseDto>> {
+    let mut candidates = vec![];
+    let mut page = Some("1".to_string());
+    while let Some(p) = page {
+        let search_req = models::MetadataSearchDto {
+            taken_after: Some(from.to_rfc3339()),
+            taken_before: Some(to.to_rfc3339()),
+            r#type: Some(asset_type),
+            page: Some(p.parse()?),
+            size: Some(1000.0),
+            ..Default::default()
+        };
+        let res = immich_client
+            .retry("search metadata", || {
+                let search_req = search_req.clone();
+                async move {
+                    search_api::search_metadata(&*immich_client.get_config().await, search_req)
+                        .await
+                }
+            })
+            .await?;
+        candidates.extend(res.assets.items);
+        page = res.assets.next_page;
+    }
+    Ok(candidates)
+}
+
+// Immich items of the same type taken around the time of the gphoto item whose thumbnails look
+// like the gphoto one, closest first, with their distance.
+pub async fn find_similar(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    gphoto_client: &GPClient,
+    gphoto_item: &MediaItem,
+    options: &PhashOptions,
+) -> Result<Vec<(AssetResponseDto, u32)>> {
+    let metadata = gphoto_item
+        .media_metadata
+        .as_ref()
+        .ok_or(anyhow!("missing metadata"))?;
+    let time: DateTime<Utc> = DateTime::parse_from_rfc3339(
+        metadata
+            .creation_time
+            .as_ref()
+            .ok_or(anyhow!("missing creation time"))?,
+    )?
+    .into();
+    let window = TimeDelta::seconds(options.window_secs);
+    let (from, to) = (time - window, time + window);
+    let asset_type = if metadata.video.is_some() {
+        models::AssetTypeEnum::Video
+    } else {
+        models::AssetTypeEnum::Image
+    };
+
+    let candidates = match immich_client.index() {
+        Some(index) => index.taken_between(from, to, asset_type).await?,
+        None => search_taken_between(immich_client, from, to, asset_type).await?,
+    };
+    if candidates.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let gphoto_hash = dhash(
+        &gphoto_client
+            .fetch_thumbnail(gphoto_item, THUMBNAIL_SIZE)
+            .await?,
+    )?;
+    let mut similar = vec![];
+    for candidate in candidates {
+        match immich_phash(pool, immich_client, &candidate.id).await {
+            Ok(hash) => {
+                let d = distance(gphoto_hash, hash);
+                debug!("phash distance to {} is {d}", candidate.id);
+                if d <= options.max_distance {
+                    similar.push((candidate, d));
+                }
+            }
+            // E.g. no thumbnail generated yet.
+            Err(e) => warn!("skipping {} for phash matching: {e:#}", candidate.id),
+        }
+    }
+    similar.sort_by_key(|(_, d)| *d);
+    Ok(similar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    fn encode(image: &RgbImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(vec![]);
+        image.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn test_dhash() {
+        let gradient = RgbImage::from_fn(256, 192, |x, y| {
+            let v = ((x + y) % 256) as u8;
+            Rgb([v, v / 2, 255 - v])
+        });
+        let hash = dhash(&encode(&gradient, ImageFormat::Png)).unwrap();
+
+        // Scaled down and recompressed, looks the same.
+        let small = image::imageops::resize(&gradient, 128, 96, FilterType::Triangle);
+        let recompressed = dhash(&encode(&small, ImageFormat::Jpeg)).unwrap();
+        assert!(distance(hash, recompressed) <= 4);
+
+        // Mirrored, looks different.
+        let mirrored = image::imageops::flip_horizontal(&gradient);
+        let other = dhash(&encode(&mirrored, ImageFormat::Png)).unwrap();
+        assert!(distance(hash, other) > 20);
+
+        assert!(dhash(b"not an image").is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use derive_more::Display;
+use log::debug;
+use sqlx::{Pool, Row, Sqlite};
+use tokio::sync::Mutex;
+
+// Google Photo Library API quota is per client ID and per day. Usage is kept in the local db per
+// UTC day so that budgets hold across runs.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+pub enum QuotaKind {
+    #[display(fmt = "api request")]
+    ApiRequest,
+    #[display(fmt = "download")]
+    Download,
+}
+
+// Returned (wrapped in anyhow) when a call would go over the budget, or google says that there's
+// no quota left.
+#[derive(Debug, Display)]
+#[display(fmt = "daily {} quota for client {} is used up", kind, client_id)]
+pub struct QuotaExhausted {
+    pub kind: QuotaKind,
+    pub client_id: String,
+}
+impl std::error::Error for QuotaExhausted {}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub api_requests: u64,
+    pub downloads: u64,
+}
+impl QuotaUsage {
+    fn get(&self, kind: QuotaKind) -> u64 {
+        match kind {
+            QuotaKind::ApiRequest => self.api_requests,
+            QuotaKind::Download => self.downloads,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QuotaLedger {
+    pool: Pool<Sqlite>,
+    client_id: String,
+    limits: QuotaUsage,
+    // Read-only runs count in memory only.
+    read_only: bool,
+    // UTC day and what was used on it.
+    used: Mutex<(String, QuotaUsage)>,
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+impl QuotaLedger {
+    pub async fn new(
+        pool: Pool<Sqlite>,
+        client_id: &str,
+        max_api_requests: u64,
+        max_downloads: u64,
+        read_only: bool,
+    ) -> Result<Self> {
+        let day = today();
+        let used = load_usage(&pool, client_id, &day).await?;
+        Ok(QuotaLedger {
+            pool,
+            client_id: client_id.to_string(),
+            limits: QuotaUsage {
+                api_requests: max_api_requests,
+                downloads: max_downloads,
+            },
+            read_only,
+            used: Mutex::new((day, used)),
+        })
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    // Records one call of the given kind. Fails with QuotaExhausted without recording anything if
+    // it would go over the budget.
+    pub async fn acquire(&self, kind: QuotaKind) -> Result<()> {
+        let mut used = self.used.lock().await;
+        let day = today();
+        if used.0 != day {
+            debug!("new quota day {day} for client {}", self.client_id);
+            *used = (
+                day.clone(),
+                load_usage(&self.pool, &self.client_id, &day).await?,
+            );
+        }
+        let limit = self.limits.get(kind);
+        if used.1.get(kind) >= limit {
+            return Err(QuotaExhausted {
+                kind,
+                client_id: self.client_id.clone(),
+            }
+            .into());
+        }
+        match kind {
+            QuotaKind::ApiRequest => used.1.api_requests += 1,
+            QuotaKind::Download => used.1.downloads += 1,
+        }
+        if !self.read_only {
+            sqlx::query(
+                r#"
+INSERT INTO gphoto_quota (client_id, day, api_requests, downloads) VALUES ($1, $2, $3, $4)
+ON CONFLICT (client_id, day) DO UPDATE SET
+    api_requests = api_requests + excluded.api_requests,
+    downloads = downloads + excluded.downloads"#,
+            )
+            .bind(&self.client_id)
+            .bind(&day)
+            .bind((kind == QuotaKind::ApiRequest) as i64)
+            .bind((kind == QuotaKind::Download) as i64)
+            .execute(&self.pool)
+            .await
+            .with_context(|| "failed to record quota usage".to_string())?;
+        }
+        Ok(())
+    }
+
+    // Google knows better: no more calls of this kind today.
+    pub async fn set_exhausted(&self, kind: QuotaKind) {
+        let mut used = self.used.lock().await;
+        match kind {
+            QuotaKind::ApiRequest => used.1.api_requests = self.limits.api_requests,
+            QuotaKind::Download => used.1.downloads = self.limits.downloads,
+        }
+    }
+
+    // What is left of the budget today.
+    pub async fn remaining(&self) -> QuotaUsage {
+        let used = self.used.lock().await;
+        QuotaUsage {
+            api_requests: self.limits.api_requests.saturating_sub(used.1.api_requests),
+            downloads: self.limits.downloads.saturating_sub(used.1.downloads),
+        }
+    }
+}
+
+async fn load_usage(pool: &Pool<Sqlite>, client_id: &str, day: &str) -> Result<QuotaUsage> {
+    Ok(sqlx::query(
+        r#"SELECT api_requests, downloads FROM gphoto_quota WHERE client_id = $1 AND day = $2"#,
+    )
+    .bind(client_id)
+    .bind(day)
+    .fetch_optional(pool)
+    .await
+    .with_context(|| "failed to read quota usage".to_string())?
+    .map(|row| QuotaUsage {
+        api_requests: row.get::<i64, _>("api_requests") as u64,
+        downloads: row.get::<i64, _>("downloads") as u64,
+    })
+    .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_pool;
+
+    #[tokio::test]
+    async fn test_budget_persists() {
+        let pool = test_pool().await;
+
+        let ledger = QuotaLedger::new(pool.clone(), "client", 2, 1, false)
+            .await
+            .unwrap();
+        ledger.acquire(QuotaKind::ApiRequest).await.unwrap();
+        ledger.acquire(QuotaKind::Download).await.unwrap();
+        let e = ledger.acquire(QuotaKind::Download).await.unwrap_err();
+        assert!(e.is::<QuotaExhausted>());
+        assert_eq!(
+            ledger.remaining().await,
+            QuotaUsage {
+                api_requests: 1,
+                downloads: 0
+            }
+        );
+
+        // A later run on the same day sees what was used.
+        let ledger = QuotaLedger::new(pool.clone(), "client", 2, 1, false)
+            .await
+            .unwrap();
+        ledger.acquire(QuotaKind::ApiRequest).await.unwrap();
+        assert!(ledger.acquire(QuotaKind::ApiRequest).await.is_err());
+        // Other clients have their own budget.
+        let ledger = QuotaLedger::new(pool, "other", 2, 1, false).await.unwrap();
+        ledger.acquire(QuotaKind::ApiRequest).await.unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use gphotos_api::models::MediaItem;
+use immich_api::models::AssetResponseDto;
+use sqlx::{Pool, Row, Sqlite};
+use std::fmt::Write;
+
+use crate::immich_client::ImmichClient;
+use crate::match_metadata::{score_metadata, ImageData, MatchScore, MatchThresholds};
+use crate::types::ImmichItemId;
+
+// The report is one HTML file (no scripts, styles inline) showing the items waiting in the
+// review queue: gphoto metadata next to the metadata of each immich candidate, with the scores
+// from score_metadata and the fields that scored below the match threshold highlighted.
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }
+th { background: #f4f4f4; }
+td.mismatch { background: #fdd; }
+.note { color: #666; }
+"#;
+
+// One queued item as it was seen by the run that queued it.
+pub struct ReportItem {
+    pub lookup_result: String,
+    pub gphoto_item: MediaItem,
+    pub candidates: Vec<AssetResponseDto>,
+}
+
+fn escape(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => rv.push_str("&amp;"),
+            '<' => rv.push_str("&lt;"),
+            '>' => rv.push_str("&gt;"),
+            '"' => rv.push_str("&quot;"),
+            '\'' => rv.push_str("&#39;"),
+            c => rv.push(c),
+        }
+    }
+    rv
+}
+
+// Fields scored by score_metadata, in the order they are shown.
+const SCORED_FIELDS: [&str; 8] = [
+    "time",
+    "type",
+    "camera",
+    "dimensions",
+    "exposure",
+    "iso",
+    "video",
+    "file_size",
+];
+
+// The score a metadata field (see ImageData::fields) goes into.
+fn scored_field(field: &str) -> &str {
+    match field {
+        "width" | "height" => "dimensions",
+        "camera_make" | "camera_model" => "camera",
+        "focal_length" | "aperture" | "exposure_time" => "exposure",
+        "aspect_ratio" => "video",
+        field => field,
+    }
+}
+
+fn field_score(score: &MatchScore, field: &str) -> Option<f64> {
+    score
+        .fields
+        .iter()
+        .find(|x| x.field == field)
+        .map(|x| x.score)
+}
+
+fn link(url: &str, text: &str) -> String {
+    format!(r#"<a href="{}">{}</a>"#, escape(url), escape(text))
+}
+
+// Items waiting for review, oldest first.
+pub async fn load_report_items(pool: &Pool<Sqlite>) -> Result<Vec<ReportItem>> {
+    let rows = sqlx::query(
+        r#"
+SELECT lookup_result, gphoto_metadata, immich_metadata FROM review_queue
+WHERE decision IS NULL ORDER BY insert_time"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            Ok(ReportItem {
+                lookup_result: row.get("lookup_result"),
+                gphoto_item: serde_json::from_str(row.get("gphoto_metadata"))?,
+                candidates: serde_json::from_str(row.get("immich_metadata"))?,
+            })
+        })
+        .collect()
+}
+
+fn render_item(
+    out: &mut String,
+    item: &ReportItem,
+    immich_client: &ImmichClient,
+    thresholds: &MatchThresholds,
+) -> Result<()> {
+    let gphoto = &item.gphoto_item;
+    let gphoto_data = gphoto
+        .media_metadata
+        .as_deref()
+        .and_then(|m| ImageData::try_from(m).ok());
+    let candidates: Vec<_> = item
+        .candidates
+        .iter()
+        .map(|c| ImageData::from(c.clone()))
+        .collect();
+    let scores: Vec<_> = candidates
+        .iter()
+        .map(|c| {
+            gphoto_data
+                .as_ref()
+                .map(|g| score_metadata(g, c, thresholds))
+        })
+        .collect();
+    let below = |score: f64| score < thresholds.min_confidence;
+    let class = |mismatch: bool| if mismatch { " class=\"mismatch\"" } else { "" };
+
+    writeln!(
+        out,
+        "<h2>{} <span class=\"note\">{}</span></h2>",
+        escape(gphoto.filename.as_deref().unwrap_or_default()),
+        escape(&item.lookup_result)
+    )?;
+    if gphoto_data.is_none() {
+        writeln!(out, "<p class=\"note\">no usable gphoto metadata</p>")?;
+    }
+    if candidates.is_empty() {
+        writeln!(out, "<p class=\"note\">no immich candidates</p>")?;
+    }
+
+    writeln!(out, "<table>\n<tr><th></th><th>gphoto</th>")?;
+    for (n, candidate) in item.candidates.iter().enumerate() {
+        let url = immich_client.item_url(&ImmichItemId(candidate.id.clone()));
+        writeln!(out, "<th>immich {}</th>", link(&url, &(n + 1).to_string()))?;
+    }
+    writeln!(out, "</tr>")?;
+
+    writeln!(out, "<tr><th>link</th><td>")?;
+    if let Some(url) = &gphoto.product_url {
+        write!(out, "{}", link(url, "Google Photos"))?;
+    }
+    write!(out, "</td>")?;
+    for candidate in &item.candidates {
+        let url = immich_client.item_url(&ImmichItemId(candidate.id.clone()));
+        write!(
+            out,
+            "<td>{}</td>",
+            link(&url, &candidate.original_file_name)
+        )?;
+    }
+    writeln!(out, "</tr>")?;
+
+    let gphoto_fields = gphoto_data.as_ref().map(|g| g.fields()).unwrap_or_default();
+    let candidate_fields: Vec<_> = candidates.iter().map(|c| c.fields()).collect();
+    let names = ImageData::default().fields();
+    for (i, (name, _)) in names.iter().enumerate() {
+        write!(out, "<tr><th>{name}</th>")?;
+        let value = gphoto_fields.get(i).map(|f| f.1.as_str()).unwrap_or("");
+        write!(out, "<td>{}</td>", escape(value))?;
+        for (fields, score) in candidate_fields.iter().zip(&scores) {
+            let mismatch = fields[i].1 != value
+                && score
+                    .as_ref()
+                    .and_then(|s| field_score(s, scored_field(name)))
+                    .is_some_and(below);
+            write!(out, "<td{}>{}</td>", class(mismatch), escape(&fields[i].1))?;
+        }
+        writeln!(out, "</tr>")?;
+    }
+
+    if gphoto_data.is_some() {
+        for field in SCORED_FIELDS {
+            let field_scores: Vec<_> = scores
+                .iter()
+                .map(|s| s.as_ref().and_then(|s| field_score(s, field)))
+                .collect();
+            // Fields neither side of any candidate knows.
+            if field_scores.iter().all(Option::is_none) {
+                continue;
+            }
+            write!(out, "<tr><th>score: {field}</th><td></td>")?;
+            for score in field_scores {
+                match score {
+                    Some(score) => write!(out, "<td{}>{score:.2}</td>", class(below(score)))?,
+                    None => write!(out, "<td></td>")?,
+                }
+            }
+            writeln!(out, "</tr>")?;
+        }
+        write!(out, "<tr><th>confidence</th><td></td>")?;
+        for score in scores.iter().flatten() {
+            write!(
+                out,
+                "<td{}>{:.2}</td>",
+                class(!score.is_match(thresholds)),
+                score.confidence
+            )?;
+        }
+        writeln!(out, "</tr>")?;
+    }
+    writeln!(out, "</table>")?;
+    Ok(())
+}
+
+// Scores are computed and highlighted with the same thresholds as used for matching.
+pub fn render(
+    items: &[ReportItem],
+    immich_client: &ImmichClient,
+    thresholds: &MatchThresholds,
+) -> Result<String> {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>immich-sync report</title>\n<style>{STYLE}</style>\n</head>\n<body>"
+    )?;
+    writeln!(out, "<h1>{} items to review</h1>", items.len())?;
+    for item in items {
+        render_item(&mut out, item, immich_client, thresholds)?;
+    }
+    writeln!(out, "</body>\n</html>")?;
+    Ok(out)
+}
+
+// Writes the report for the items waiting in the review queue to `path`.
+pub async fn write_report(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    thresholds: &MatchThresholds,
+    path: &str,
+) -> Result<usize> {
+    let items = load_report_items(pool).await?;
+    let html = render(&items, immich_client, thresholds)?;
+    tokio::fs::write(path, html)
+        .await
+        .with_context(|| format!("failed to write report to {path}"))?;
+    Ok(items.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::review::queue_for_review;
+    use crate::test_util::test_pool;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_report() {
+        let pool = test_pool().await;
+        let immich_client =
+            ImmichClient::new(1, "http://immich/api", None, true, Duration::from_secs(1)).unwrap();
+
+        let gphoto_item: MediaItem = serde_json::from_str(
+            r#"{"id":"gphoto-1","productUrl":"https://photos.google.com/lr/photo/gphoto-1","filename":"<PXL>.jpg","mediaMetadata":{"creationTime":"2024-06-27T19:35:56.496Z","width":"3072","height":"4080","photo":{"cameraMake":"Google","cameraModel":"Pixel 6","focalLength":6.81,"apertureFNumber":1.85,"isoEquivalent":104,"exposureTime":"0.041997s"}}}"#,
+        )
+        .unwrap();
+        // Same as gphoto-1 except for the camera make.
+        let candidate: AssetResponseDto = serde_json::from_str(r#"{"checksum":"lw5y6IsCo11RT8+dYgQmWrE+5QU=","deviceAssetId":"970e72e88b02a35d514fcf9d6204265ab13ee505","deviceId":"immich-sync","duplicateId":null,"duration":"0:00:00.00000","exifInfo":{"city":null,"country":null,"dateTimeOriginal":"2024-06-27T19:35:56.496Z","description":"","exifImageHeight":4080.0,"exifImageWidth":3072.0,"exposureTime":"1/24","fNumber":1.9,"fileSizeInByte":792315,"focalLength":6.81,"iso":104.0,"latitude":null,"lensModel":"Pixel 6 back camera 6.81mm f/1.85","longitude":null,"make":"Apple","model":"Pixel 6","modifyDate":"2024-06-27T19:35:56.496Z","orientation":"1","projectionType":null,"state":null,"timeZone":"UTC+2"},"fileCreatedAt":"2024-06-27T19:35:56.496Z","fileModifiedAt":"2024-06-27T19:35:56.496Z","hasMetadata":true,"id":"immich-1","isArchived":false,"isFavorite":false,"isOffline":false,"isTrashed":false,"libraryId":null,"livePhotoVideoId":null,"localDateTime":"2024-06-27T21:35:56.496Z","originalFileName":"PXL_20240627_193556496.jpg","originalMimeType":"image/jpeg","originalPath":"upload/x.jpg","ownerId":"fe5315c8-d1cd-4822-a3c9-1e00d244e71a","people":[],"resized":true,"stackCount":null,"thumbhash":"1icKDQZxiE+ImHbYdnmHeC+5zPas","type":"IMAGE","updatedAt":"2024-07-02T10:18:00.641Z"}"#).unwrap();
+        queue_for_review(
+            &pool,
+            &gphoto_item,
+            "FoundUnique",
+            std::slice::from_ref(&candidate),
+        )
+        .await
+        .unwrap();
+
+        let items = load_report_items(&pool).await.unwrap();
+        assert_eq!(items.len(), 1);
+        let html = render(&items, &immich_client, &MatchThresholds::default()).unwrap();
+        assert!(html.contains("&lt;PXL&gt;.jpg"));
+        assert!(html.contains(r#"href="https://photos.google.com/lr/photo/gphoto-1""#));
+        assert!(html.contains(r#"href="http://immich/photos/immich-1""#));
+        assert!(html.contains(
+            r#"<tr><th>camera_make</th><td>Google</td><td class="mismatch">Apple</td></tr>"#
+        ));
+        assert!(html
+            .contains(r#"<tr><th>score: camera</th><td></td><td class="mismatch">0.50</td></tr>"#));
+        assert!(html.contains(r#"<tr><th>score: time</th><td></td><td>1.00</td></tr>"#));
+        // The camera alone doesn't make it a mismatch.
+        assert!(html.contains(r#"<tr><th>confidence</th><td></td><td>0.93</td></tr>"#));
+        assert_eq!(html.matches("class=\"mismatch\"").count(), 2);
+    }
+}
@@ -0,0 +1,193 @@
+use log::warn;
+use rand::Rng;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+
+// Retry policy shared by the Google Photos and Immich clients. Failed calls that look transient
+// (timeouts, connection errors, 408, 429 and 5xx) are retried with exponential backoff and full
+// jitter, everything else is returned right away.
+
+// Errors that know whether trying again could help.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+fn retryable_status(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+impl Retryable for reqwest::Error {
+    fn is_retryable(&self) -> bool {
+        if let Some(status) = self.status() {
+            return retryable_status(status.as_u16());
+        }
+        self.is_timeout() || self.is_connect() || self.is_request() || self.is_body()
+    }
+}
+
+impl<T> Retryable for gphotos_api::apis::Error<T> {
+    fn is_retryable(&self) -> bool {
+        match self {
+            gphotos_api::apis::Error::Reqwest(e) => e.is_retryable(),
+            gphotos_api::apis::Error::ResponseError(r) => retryable_status(r.status.as_u16()),
+            _ => false,
+        }
+    }
+}
+
+impl<T> Retryable for immich_api::apis::Error<T> {
+    fn is_retryable(&self) -> bool {
+        match self {
+            immich_api::apis::Error::Reqwest(e) => e.is_retryable(),
+            immich_api::apis::Error::ResponseError(r) => retryable_status(r.status.as_u16()),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // Total number of tries, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // How long to wait after the given (0 based) failed attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let cap = self
+            .initial_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff);
+        cap.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    // Calls `f` until it succeeds, fails with a non-retryable error or runs out of attempts.
+    pub async fn retry<T, E, F, Fut>(&self, what: &str, f: F) -> Result<T, E>
+    where
+        E: Retryable + Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.retry_if(what, f, |e: &E| e.is_retryable()).await
+    }
+
+    // Same as `retry`, but the caller decides what is worth retrying.
+    pub async fn retry_if<T, E, F, Fut, P>(
+        &self,
+        what: &str,
+        mut f: F,
+        is_retryable: P,
+    ) -> Result<T, E>
+    where
+        E: Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        P: Fn(&E) -> bool,
+    {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Err(e) if attempt + 1 < self.max_attempts && is_retryable(&e) => {
+                    let backoff = self.backoff(attempt);
+                    warn!(
+                        "{what} failed (attempt {}/{}), retrying in {:?}: {:?}",
+                        attempt + 1,
+                        self.max_attempts,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Debug)]
+    struct TestError(u16);
+    impl Retryable for TestError {
+        fn is_retryable(&self) -> bool {
+            retryable_status(self.0)
+        }
+    }
+
+    fn no_wait(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        // Transient errors are retried until the call goes through.
+        let calls = Cell::new(0);
+        let r = no_wait(5)
+            .retry("test", || async {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Err(TestError(503))
+                } else {
+                    Ok(calls.get())
+                }
+            })
+            .await;
+        assert_eq!(r.unwrap(), 3);
+
+        // Up to max_attempts.
+        calls.set(0);
+        let r: Result<(), _> = no_wait(4)
+            .retry("test", || async {
+                calls.set(calls.get() + 1);
+                Err(TestError(500))
+            })
+            .await;
+        assert!(r.is_err());
+        assert_eq!(calls.get(), 4);
+
+        // Permanent errors are not retried.
+        calls.set(0);
+        let r: Result<(), _> = no_wait(4)
+            .retry("test", || async {
+                calls.set(calls.get() + 1);
+                Err(TestError(404))
+            })
+            .await;
+        assert!(r.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        for attempt in 0..10 {
+            let cap = Duration::from_millis(100 * (1 << attempt)).min(Duration::from_secs(1));
+            assert!(policy.backoff(attempt) <= cap);
+        }
+    }
+}
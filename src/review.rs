@@ -0,0 +1,276 @@
+use anyhow::{anyhow, Context, Result};
+use colored::Colorize;
+use derive_more::Display;
+use gphotos_api::models::MediaItem;
+use immich_api::models::AssetResponseDto;
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::HashMap;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::immich_client::ImmichClient;
+use crate::match_metadata::ImageData;
+use crate::now;
+use crate::types::{GPhotoItemId, ImmichItemId};
+
+// Items that could not be linked or copied automatically are kept in the review_queue table
+// together with what gphoto and immich know about them. `review` goes through them one by one,
+// and later runs do what was decided.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum ReviewDecision {
+    #[display(fmt = "link")]
+    Link, // Linked to one of the candidates, the link is in item_item_links.
+    #[display(fmt = "create")]
+    Create, // Copy to immich even though it may be a dupe.
+    #[display(fmt = "skip")]
+    Skip, // Leave it alone.
+}
+
+impl FromStr for ReviewDecision {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "link" => Ok(ReviewDecision::Link),
+            "create" => Ok(ReviewDecision::Create),
+            "skip" => Ok(ReviewDecision::Skip),
+            _ => Err(anyhow!("unknown review decision {s:?}")),
+        }
+    }
+}
+
+// Adds an item to the review queue, or refreshes what is known about it if it is still waiting
+// for review. Items that were already reviewed are left alone.
+pub async fn queue_for_review(
+    pool: &Pool<Sqlite>,
+    gphoto_item: &MediaItem,
+    lookup_result: &str,
+    candidates: &[AssetResponseDto],
+) -> Result<()> {
+    let candidate_ids: Vec<_> = candidates.iter().map(|c| c.id.clone()).collect();
+    sqlx::query(
+        r#"
+INSERT INTO review_queue
+    (gphoto_id, lookup_result, gphoto_metadata, immich_metadata, candidate_ids, insert_time)
+VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (gphoto_id) DO UPDATE SET
+    lookup_result = excluded.lookup_result,
+    gphoto_metadata = excluded.gphoto_metadata,
+    immich_metadata = excluded.immich_metadata,
+    candidate_ids = excluded.candidate_ids
+WHERE decision IS NULL"#,
+    )
+    .bind(gphoto_item.id.as_ref().ok_or(anyhow!("missing id"))?)
+    .bind(lookup_result)
+    .bind(serde_json::to_string(gphoto_item)?)
+    .bind(serde_json::to_string(candidates)?)
+    .bind(serde_json::to_string(&candidate_ids)?)
+    .bind(now())
+    .execute(pool)
+    .await
+    .with_context(|| "failed to queue item for review".to_string())?;
+    Ok(())
+}
+
+// Decisions made so far. Links are not included, those are in item_item_links already.
+pub async fn load_decisions(pool: &Pool<Sqlite>) -> Result<HashMap<GPhotoItemId, ReviewDecision>> {
+    let rows = sqlx::query(
+        r#"SELECT gphoto_id, decision FROM review_queue WHERE decision IS NOT NULL AND decision != $1"#,
+    )
+    .bind(ReviewDecision::Link.to_string())
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            Ok((
+                GPhotoItemId(row.get("gphoto_id")),
+                row.get::<String, _>("decision").parse()?,
+            ))
+        })
+        .collect()
+}
+
+// Stores the decision for an item. For links, the item link is saved too.
+pub async fn decide(
+    pool: &Pool<Sqlite>,
+    gphoto_id: &GPhotoItemId,
+    decision: ReviewDecision,
+    immich_id: Option<&ImmichItemId>,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    if decision == ReviewDecision::Link {
+        let immich_id = immich_id.ok_or(anyhow!("link needs an immich id"))?;
+        sqlx::query(
+            r#"
+INSERT INTO item_item_links (gphoto_id, immich_id, link_type, insert_time)
+VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(&gphoto_id.0)
+        .bind(&immich_id.0)
+        .bind("Reviewed")
+        .bind(now())
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("failed to link {gphoto_id} to {immich_id}"))?;
+    }
+    sqlx::query(
+        r#"
+UPDATE review_queue SET decision = $2, decision_immich_id = $3, decision_time = $4
+WHERE gphoto_id = $1"#,
+    )
+    .bind(&gphoto_id.0)
+    .bind(decision.to_string())
+    .bind(immich_id.map(|id| &id.0))
+    .bind(now())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+fn describe_gphoto(item: &MediaItem) -> String {
+    match item.media_metadata.as_deref().map(ImageData::try_from) {
+        Some(Ok(data)) => format!("{data:?}"),
+        Some(Err(e)) => format!("bad metadata: {e}"),
+        None => "no metadata".to_string(),
+    }
+}
+
+// Walks the undecided items in the terminal.
+pub async fn review(
+    pool: &Pool<Sqlite>,
+    immich_client: &ImmichClient,
+    read_only: bool,
+) -> Result<()> {
+    let rows = sqlx::query(
+        r#"
+SELECT gphoto_id, lookup_result, gphoto_metadata, immich_metadata FROM review_queue
+WHERE decision IS NULL ORDER BY insert_time"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    println!("{} items to review", rows.len());
+
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+    for (i, row) in rows.iter().enumerate() {
+        let gphoto_id = GPhotoItemId(row.get("gphoto_id"));
+        let gphoto_item: MediaItem = serde_json::from_str(row.get("gphoto_metadata"))?;
+        let candidates: Vec<AssetResponseDto> = serde_json::from_str(row.get("immich_metadata"))?;
+
+        println!(
+            "\n[{}/{}] {} ({})",
+            i + 1,
+            rows.len(),
+            gphoto_item.filename.clone().unwrap_or_default().yellow(),
+            row.get::<String, _>("lookup_result")
+        );
+        println!(
+            "gphoto: {}\n  {}",
+            gphoto_item.product_url.clone().unwrap_or_default().red(),
+            describe_gphoto(&gphoto_item)
+        );
+        for (n, candidate) in candidates.iter().enumerate() {
+            println!(
+                "{}: {}\n  {:?}",
+                n + 1,
+                immich_client
+                    .item_url(&ImmichItemId(candidate.id.clone()))
+                    .green(),
+                ImageData::from(candidate.clone())
+            );
+        }
+
+        loop {
+            stdout
+                .write_all(b"link to [1-N], (c)reate, (s)kip, (q)uit: ")
+                .await?;
+            stdout.flush().await?;
+            let Some(line) = stdin.next_line().await? else {
+                return Ok(());
+            };
+            let (decision, immich_id) = match line.trim() {
+                "q" => return Ok(()),
+                "c" => (ReviewDecision::Create, None),
+                "s" => (ReviewDecision::Skip, None),
+                n => match n
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| candidates.get(n.checked_sub(1)?))
+                {
+                    Some(c) => (ReviewDecision::Link, Some(ImmichItemId(c.id.clone()))),
+                    None => continue,
+                },
+            };
+            if read_only {
+                println!("will {decision} {gphoto_id}");
+                break;
+            }
+            match decide(pool, &gphoto_id, decision, immich_id.as_ref()).await {
+                Ok(()) => break,
+                // E.g. the candidate is linked to another gphoto item already.
+                Err(e) => println!("{}: {e:#}", "failed".red()),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_pool;
+
+    #[tokio::test]
+    async fn test_decisions_stick() {
+        let pool = test_pool().await;
+
+        let item = |id: &str| MediaItem {
+            id: Some(id.to_string()),
+            ..Default::default()
+        };
+        let candidate = AssetResponseDto {
+            id: "immich-1".to_string(),
+            ..Default::default()
+        };
+        for id in ["a", "b", "c"] {
+            queue_for_review(
+                &pool,
+                &item(id),
+                "FoundMultiple",
+                std::slice::from_ref(&candidate),
+            )
+            .await
+            .unwrap();
+        }
+        let a = GPhotoItemId("a".to_string());
+        let b = GPhotoItemId("b".to_string());
+        let immich_id = ImmichItemId("immich-1".to_string());
+        decide(&pool, &a, ReviewDecision::Skip, None).await.unwrap();
+        decide(&pool, &b, ReviewDecision::Link, Some(&immich_id))
+            .await
+            .unwrap();
+        // The candidate is taken now.
+        let c = GPhotoItemId("c".to_string());
+        assert!(decide(&pool, &c, ReviewDecision::Link, Some(&immich_id))
+            .await
+            .is_err());
+
+        // A later run queues them again, which doesn't undo the decisions.
+        for id in ["a", "b"] {
+            queue_for_review(&pool, &item(id), "FoundUnique", &[])
+                .await
+                .unwrap();
+        }
+        let decisions = load_decisions(&pool).await.unwrap();
+        assert_eq!(decisions, HashMap::from([(a, ReviewDecision::Skip)]));
+        let linked: String =
+            sqlx::query(r#"SELECT immich_id FROM item_item_links WHERE gphoto_id = $1"#)
+                .bind("b")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .get("immich_id");
+        assert_eq!(linked, "immich-1");
+    }
+}
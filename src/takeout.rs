@@ -0,0 +1,745 @@
+use crate::types::*;
+use anyhow::{anyhow, Context, Result};
+use chrono::DateTime;
+use gphotos_api::models::{
+    Album, MediaItem, MediaItemMediaMetadata, MediaItemMediaMetadataPhoto,
+    MediaItemMediaMetadataVideo,
+};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Google Takeout reader. Takeout exports contain the original media files (with the EXIF
+// location that the Library API strips) next to `*.json` sidecars holding what Google knows
+// about the item. Albums are folders with a `metadata.json` file in them.
+
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "heic", "heif", "webp", "tif", "tiff", "bmp", "avif", "dng",
+    "cr2", "nef", "arw", "raw",
+];
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "mov", "m4v", "3gp", "avi", "mkv", "webm", "mts", "m2ts", "wmv", "mpg", "mpeg",
+];
+
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TakeoutTime {
+    // Seconds since epoch, as a string.
+    pub timestamp: String,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GeoData {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default)]
+    pub altitude: f64,
+}
+impl GeoData {
+    // Takeout uses 0.0/0.0 for "no location".
+    pub fn is_set(&self) -> bool {
+        self.latitude != 0.0 || self.longitude != 0.0
+    }
+}
+
+// Sidecar json that Takeout writes next to every media file. Album folders have a
+// `metadata.json` of the same shape but without the photo times.
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Sidecar {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub creation_time: Option<TakeoutTime>,
+    pub photo_taken_time: Option<TakeoutTime>,
+    pub geo_data: Option<GeoData>,
+    pub geo_data_exif: Option<GeoData>,
+    pub url: Option<String>,
+}
+impl Sidecar {
+    // Location, preferring what Google has (may have been set manually) over what it read from
+    // the EXIF.
+    pub fn location(&self) -> Option<&GeoData> {
+        self.geo_data
+            .as_ref()
+            .filter(|g| g.is_set())
+            .or(self.geo_data_exif.as_ref().filter(|g| g.is_set()))
+    }
+    pub fn taken_time_rfc3339(&self) -> Option<String> {
+        let t = self
+            .photo_taken_time
+            .as_ref()
+            .or(self.creation_time.as_ref())?;
+        let secs = t.timestamp.parse::<i64>().ok()?;
+        DateTime::from_timestamp(secs, 0).map(|dt| dt.to_rfc3339())
+    }
+    // Takeout does not expose Library API ids, the closest thing to a stable id is the url. Items
+    // are not matched with the same item seen through the API, see the README.
+    fn item_id(&self) -> Option<GPhotoItemId> {
+        if let Some(url) = self.url.as_ref().filter(|u| !u.is_empty()) {
+            return Some(GPhotoItemId(format!("takeout:{url}")));
+        }
+        let t = self.photo_taken_time.as_ref()?;
+        Some(GPhotoItemId(format!(
+            "takeout:{}/{}",
+            self.title.as_ref()?,
+            t.timestamp
+        )))
+    }
+}
+
+// Where a media file lives: the archive and the entry name in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaLocation {
+    pub archive: PathBuf,
+    pub entry: String,
+}
+
+// Where the data of each media entry of a tgz starts in the decompressed stream, and its size.
+type TarIndex = HashMap<String, (u64, u64)>;
+
+// A decompressed tgz stream and how far into it it has been read.
+struct TarCursor {
+    stream: flate2::read::GzDecoder<BufReader<File>>,
+    pos: u64,
+}
+impl TarCursor {
+    fn open(archive: &Path) -> Result<Self> {
+        let file = File::open(archive).with_context(|| format!("failed to open {archive:?}"))?;
+        Ok(TarCursor {
+            stream: flate2::read::GzDecoder::new(BufReader::new(file)),
+            pos: 0,
+        })
+    }
+}
+
+// Tgz cursors kept per archive. Copies running at the same time each need one.
+const MAX_TAR_CURSORS: usize = 16;
+
+// Archives kept open between reads. Zip archives so their central directory is read once, tgz
+// archives as streams positioned after the last entry read from them: tgz can't be seeked, but
+// reading its entries in archive order (see `read_order`) decompresses it about once.
+#[derive(Default)]
+struct OpenArchives {
+    zips: Mutex<HashMap<PathBuf, Vec<zip::ZipArchive<File>>>>,
+    tar_indexes: Mutex<HashMap<PathBuf, Arc<TarIndex>>>,
+    tars: Mutex<HashMap<PathBuf, Vec<TarCursor>>>,
+}
+
+lazy_static! {
+    static ref OPEN_ARCHIVES: OpenArchives = OpenArchives::default();
+}
+
+// The index of a tgz archive, from the scan or (e.g. when resuming) by going through it once.
+fn tar_index(archive: &Path) -> Result<Arc<TarIndex>> {
+    if let Some(index) = OPEN_ARCHIVES.tar_indexes.lock().unwrap().get(archive) {
+        return Ok(index.clone());
+    }
+    info!("indexing takeout archive {archive:?}");
+    let file = File::open(archive).with_context(|| format!("failed to open {archive:?}"))?;
+    let mut index = TarIndex::new();
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    for entry in tar
+        .entries()
+        .with_context(|| format!("failed to read tar {archive:?}"))?
+    {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if entry.header().entry_type().is_file() && mime_type(&name).is_some() {
+            index.insert(name, (entry.raw_file_position(), entry.size()));
+        }
+    }
+    let index = Arc::new(index);
+    OPEN_ARCHIVES
+        .tar_indexes
+        .lock()
+        .unwrap()
+        .insert(archive.to_path_buf(), index.clone());
+    Ok(index)
+}
+
+impl MediaLocation {
+    // Copies the file out of the archive into `w`, returns the number of bytes copied. Blocking.
+    pub fn copy_to<W: Write>(&self, w: &mut W) -> Result<u64> {
+        if is_zip(&self.archive) {
+            self.copy_from_zip(w)
+        } else {
+            self.copy_from_tar(w)
+        }
+    }
+
+    // Size of the file, without reading it. Blocking.
+    pub fn size(&self) -> Result<u64> {
+        if is_zip(&self.archive) {
+            return self.with_zip(|zip| Ok(zip.by_name(&self.entry)?.size()));
+        }
+        tar_index(&self.archive)?
+            .get(&self.entry)
+            .map(|&(_, size)| size)
+            .ok_or_else(|| anyhow!("{} not found in {:?}", self.entry, self.archive))
+    }
+
+    fn copy_from_zip<W: Write>(&self, w: &mut W) -> Result<u64> {
+        self.with_zip(|zip| Ok(std::io::copy(&mut zip.by_name(&self.entry)?, w)?))
+    }
+
+    // Runs `f` with an open zip of the archive, from the pool if there is one.
+    fn with_zip<T>(&self, f: impl FnOnce(&mut zip::ZipArchive<File>) -> Result<T>) -> Result<T> {
+        let zip = OPEN_ARCHIVES
+            .zips
+            .lock()
+            .unwrap()
+            .get_mut(&self.archive)
+            .and_then(|zips| zips.pop());
+        let mut zip = match zip {
+            Some(zip) => zip,
+            None => {
+                let file = File::open(&self.archive)
+                    .with_context(|| format!("failed to open {:?}", self.archive))?;
+                zip::ZipArchive::new(file)
+                    .with_context(|| format!("failed to read zip {:?}", self.archive))?
+            }
+        };
+        let r = f(&mut zip)?;
+        OPEN_ARCHIVES
+            .zips
+            .lock()
+            .unwrap()
+            .entry(self.archive.clone())
+            .or_default()
+            .push(zip);
+        Ok(r)
+    }
+
+    fn copy_from_tar<W: Write>(&self, w: &mut W) -> Result<u64> {
+        let index = tar_index(&self.archive)?;
+        let &(offset, size) = index
+            .get(&self.entry)
+            .ok_or_else(|| anyhow!("{} not found in {:?}", self.entry, self.archive))?;
+        // The cursor that is closest to the entry without having passed it. Starting over from
+        // the beginning of the archive is the expensive case.
+        let cursor = {
+            let mut tars = OPEN_ARCHIVES.tars.lock().unwrap();
+            let cursors = tars.entry(self.archive.clone()).or_default();
+            cursors
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.pos <= offset)
+                .max_by_key(|(_, c)| c.pos)
+                .map(|(i, _)| i)
+                .map(|i| cursors.swap_remove(i))
+        };
+        let mut cursor = match cursor {
+            Some(cursor) => cursor,
+            None => TarCursor::open(&self.archive)?,
+        };
+        let skip = offset - cursor.pos;
+        if std::io::copy(&mut (&mut cursor.stream).take(skip), &mut std::io::sink())? != skip {
+            return Err(anyhow!("{:?} is truncated", self.archive));
+        }
+        let n = std::io::copy(&mut (&mut cursor.stream).take(size), w)?;
+        if n != size {
+            return Err(anyhow!("{:?} is truncated", self.archive));
+        }
+        cursor.pos = offset + size;
+
+        let mut tars = OPEN_ARCHIVES.tars.lock().unwrap();
+        let cursors = tars.entry(self.archive.clone()).or_default();
+        cursors.push(cursor);
+        if cursors.len() > MAX_TAR_CURSORS {
+            let (i, _) = cursors
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, c)| c.pos)
+                .unwrap();
+            cursors.swap_remove(i);
+        }
+        Ok(n)
+    }
+}
+
+// Sort keys that put the given files in the order they are stored in their archives, for
+// copying them in that order. Blocking, may need to go through tgz archives to index them.
+pub fn read_order<K: Clone + Eq + std::hash::Hash>(
+    locations: &[(K, MediaLocation)],
+) -> Result<HashMap<K, (PathBuf, u64)>> {
+    let mut order = HashMap::new();
+    for (key, location) in locations {
+        let offset = if is_zip(&location.archive) {
+            0
+        } else {
+            tar_index(&location.archive)?
+                .get(&location.entry)
+                .map_or(0, |(offset, _)| *offset)
+        };
+        order.insert(key.clone(), (location.archive.clone(), offset));
+    }
+    Ok(order)
+}
+
+// Everything found in a set of Takeout archives, keyed the same way as the gphoto API scan.
+#[derive(Debug, Default)]
+pub struct TakeoutScan {
+    pub media_items: HashMap<GPhotoItemId, MediaItem>,
+    pub albums: HashMap<GPhotoAlbumId, Album>,
+    pub associations: HashMap<GPhotoAlbumId, HashSet<GPhotoItemId>>,
+    pub locations: HashMap<GPhotoItemId, MediaLocation>,
+    pub sidecars: HashMap<GPhotoItemId, Sidecar>,
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+}
+
+fn extension(name: &str) -> Option<String> {
+    let (_, ext) = name.rsplit_once('.')?;
+    Some(ext.to_lowercase())
+}
+
+fn mime_type(name: &str) -> Option<(&'static str, bool)> {
+    let ext = extension(name)?;
+    let mime = match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "webp" => "image/webp",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "3gp" => "video/3gpp",
+        e if IMAGE_EXTENSIONS.contains(&e) => "image/x-unknown",
+        e if VIDEO_EXTENSIONS.contains(&e) => "video/x-unknown",
+        _ => return None,
+    };
+    Some((mime, mime.starts_with("video/")))
+}
+
+// Splits a trailing "(n)" that Takeout adds to duplicate names. For media files it sits before
+// the extension (IMG(1).jpg), for sidecars after it (IMG.jpg(1).json).
+fn split_dup_index(name: &str) -> (String, Option<u32>) {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => (stem, Some(ext)),
+        None => (name, None),
+    };
+    let parse = |s: &str| -> Option<(String, u32)> {
+        let open = s.rfind('(')?;
+        let n = s.strip_suffix(')')?[open + 1..].parse().ok()?;
+        Some((s[..open].to_string(), n))
+    };
+    match parse(stem) {
+        Some((stem, n)) => (
+            match ext {
+                Some(ext) => format!("{stem}.{ext}"),
+                None => stem,
+            },
+            Some(n),
+        ),
+        None => match parse(name) {
+            Some((name, n)) => (name, Some(n)),
+            None => (name.to_string(), None),
+        },
+    }
+}
+
+// Key under which a sidecar is looked up by its media file name. Handles the
+// ".supplemental-metadata" infix of newer exports, including its truncated forms.
+fn sidecar_key(sidecar_name: &str) -> (String, Option<u32>) {
+    let name = sidecar_name.strip_suffix(".json").unwrap_or(sidecar_name);
+    let (name, n) = split_dup_index(name);
+    const SUPPLEMENTAL: &str = ".supplemental-metadata";
+    if let Some(pos) = name.rfind('.') {
+        let suffix = &name[pos..];
+        // Only strip if what remains still has an extension, i.e. "IMG.jpg.supp" but not
+        // "IMG.s".
+        if suffix.len() > 1 && SUPPLEMENTAL.starts_with(suffix) && name[..pos].contains('.') {
+            return (name[..pos].to_string(), n);
+        }
+    }
+    (name, n)
+}
+
+fn media_key(media_name: &str) -> (String, Option<u32>) {
+    split_dup_index(media_name)
+}
+
+fn split_path(entry: &str) -> (&str, &str) {
+    entry.rsplit_once('/').unwrap_or(("", entry))
+}
+
+fn is_year_folder(folder: &str) -> bool {
+    let name = split_path(folder).1;
+    name.strip_prefix("Photos from ")
+        .is_some_and(|y| y.len() == 4 && y.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[derive(Default)]
+struct RawIndex {
+    // folder -> (media name, location)
+    media: HashMap<String, Vec<(String, MediaLocation)>>,
+    // folder -> sidecar file name -> sidecar
+    sidecars: HashMap<String, HashMap<String, Sidecar>>,
+    // folder -> album metadata
+    album_metadata: HashMap<String, Sidecar>,
+}
+impl RawIndex {
+    fn add(&mut self, archive: &Path, entry: &str, contents: impl FnOnce() -> Result<Vec<u8>>) {
+        let (folder, name) = split_path(entry);
+        if name.to_lowercase().ends_with(".json") {
+            let parsed = contents().and_then(|c| Ok(serde_json::from_slice::<Sidecar>(&c)?));
+            match parsed {
+                Ok(sidecar) if name == "metadata.json" && sidecar.photo_taken_time.is_none() => {
+                    self.album_metadata.insert(folder.to_string(), sidecar);
+                }
+                Ok(sidecar) if sidecar.photo_taken_time.is_some() => {
+                    self.sidecars
+                        .entry(folder.to_string())
+                        .or_default()
+                        .insert(name.to_string(), sidecar);
+                }
+                Ok(_) => debug!("ignoring json {entry}"),
+                Err(e) => debug!("ignoring json {entry}: {e:?}"),
+            }
+        } else if mime_type(name).is_some() {
+            self.media.entry(folder.to_string()).or_default().push((
+                name.to_string(),
+                MediaLocation {
+                    archive: archive.to_path_buf(),
+                    entry: entry.to_string(),
+                },
+            ));
+        }
+    }
+
+    fn add_archive(&mut self, archive: &Path) -> Result<()> {
+        let file = File::open(archive).with_context(|| format!("failed to open {archive:?}"))?;
+        if is_zip(archive) {
+            let mut zip = zip::ZipArchive::new(file)
+                .with_context(|| format!("failed to read zip {archive:?}"))?;
+            for i in 0..zip.len() {
+                let mut f = zip.by_index(i)?;
+                if f.is_dir() {
+                    continue;
+                }
+                let name = f.name().to_string();
+                self.add(archive, &name, || {
+                    let mut buf = vec![];
+                    f.read_to_end(&mut buf)?;
+                    Ok(buf)
+                });
+            }
+        } else {
+            let mut tar_index = TarIndex::new();
+            let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+            for entry in tar
+                .entries()
+                .with_context(|| format!("failed to read tar {archive:?}"))?
+            {
+                let mut entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry.path()?.to_string_lossy().to_string();
+                if mime_type(&name).is_some() {
+                    tar_index.insert(name.clone(), (entry.raw_file_position(), entry.size()));
+                }
+                self.add(archive, &name, || {
+                    let mut buf = vec![];
+                    entry.read_to_end(&mut buf)?;
+                    Ok(buf)
+                });
+            }
+            // Saves going through the archive again to find the files when copying them.
+            OPEN_ARCHIVES
+                .tar_indexes
+                .lock()
+                .unwrap()
+                .insert(archive.to_path_buf(), Arc::new(tar_index));
+        }
+        Ok(())
+    }
+
+    // Finds the sidecar for a media file in `folder`. Tries the exact name first, then the
+    // title recorded in the sidecar, then a unique prefix match for names that Takeout
+    // truncated.
+    fn find_sidecar(&self, folder: &str, media_name: &str) -> Option<&Sidecar> {
+        let sidecars = self.sidecars.get(folder)?;
+        let key = media_key(media_name);
+        let by_key = sidecars
+            .iter()
+            .filter(|(name, _)| sidecar_key(name) == key)
+            .map(|(_, s)| s)
+            .collect::<Vec<_>>();
+        if by_key.len() == 1 {
+            return Some(by_key[0]);
+        }
+        if key.1.is_none() {
+            let by_title = sidecars
+                .values()
+                .filter(|s| s.title.as_deref() == Some(media_name))
+                .collect::<Vec<_>>();
+            if by_title.len() == 1 {
+                return Some(by_title[0]);
+            }
+        }
+        let stem = key.0.rsplit_once('.').map(|(s, _)| s).unwrap_or(&key.0);
+        let by_prefix = sidecars
+            .iter()
+            .filter(|(name, _)| {
+                let (sk, n) = sidecar_key(name);
+                n == key.1 && (sk.starts_with(stem) || stem.starts_with(sk.as_str()))
+            })
+            .map(|(_, s)| s)
+            .collect::<Vec<_>>();
+        if by_prefix.len() == 1 {
+            return Some(by_prefix[0]);
+        }
+        None
+    }
+}
+
+fn to_media_item(id: &GPhotoItemId, name: &str, sidecar: &Sidecar) -> Option<MediaItem> {
+    let (mime, is_video) = mime_type(name)?;
+    let media_metadata = MediaItemMediaMetadata {
+        creation_time: sidecar.taken_time_rfc3339(),
+        photo: if is_video {
+            None
+        } else {
+            Some(Box::new(MediaItemMediaMetadataPhoto::default()))
+        },
+        video: if is_video {
+            Some(Box::new(MediaItemMediaMetadataVideo::default()))
+        } else {
+            None
+        },
+        ..Default::default()
+    };
+    Some(MediaItem {
+        id: Some(id.0.clone()),
+        description: sidecar.description.clone().filter(|d| !d.is_empty()),
+        product_url: sidecar.url.clone(),
+        base_url: None,
+        mime_type: Some(mime.to_string()),
+        media_metadata: Some(Box::new(media_metadata)),
+        contributor_info: None,
+        filename: Some(sidecar.title.clone().unwrap_or(name.to_string())),
+    })
+}
+
+// Reads all the given Takeout archives. All parts of a split export should be passed together
+// since a media file and its sidecar may end up in different parts. Blocking.
+pub fn scan_archives(archives: &[PathBuf]) -> Result<TakeoutScan> {
+    let mut index = RawIndex::default();
+    for archive in archives {
+        info!("indexing takeout archive {archive:?}");
+        index.add_archive(archive)?;
+    }
+
+    let mut result = TakeoutScan::default();
+    let mut no_sidecar = 0;
+    for (folder, media) in &index.media {
+        let album_id = match index.album_metadata.get(folder) {
+            Some(album) if !is_year_folder(folder) => {
+                let title = album
+                    .title
+                    .clone()
+                    .unwrap_or(split_path(folder).1.to_string());
+                let album_id = GPhotoAlbumId(format!("takeout:{folder}"));
+                result.albums.insert(
+                    album_id.clone(),
+                    Album {
+                        id: Some(album_id.0.clone()),
+                        title: Some(title),
+                        ..Default::default()
+                    },
+                );
+                Some(album_id)
+            }
+            _ => None,
+        };
+
+        for (name, location) in media {
+            let Some(sidecar) = index.find_sidecar(folder, name) else {
+                // Edited versions share the sidecar with the original, which we prefer.
+                if !split_dup_index(name).0.contains("-edited.") {
+                    debug!("no sidecar found for {}", location.entry);
+                    no_sidecar += 1;
+                }
+                continue;
+            };
+            let Some(id) = sidecar.item_id() else {
+                no_sidecar += 1;
+                continue;
+            };
+            if let Some(album_id) = album_id.as_ref() {
+                result
+                    .associations
+                    .entry(album_id.clone())
+                    .or_default()
+                    .insert(id.clone());
+            }
+            // The same item shows up in the year folder and in every album it's in.
+            if result.media_items.contains_key(&id) {
+                continue;
+            }
+            let Some(media_item) = to_media_item(&id, name, sidecar) else {
+                continue;
+            };
+            result.media_items.insert(id.clone(), media_item);
+            result.locations.insert(id.clone(), location.clone());
+            result.sidecars.insert(id, sidecar.clone());
+        }
+    }
+    if no_sidecar > 0 {
+        warn!("skipped {no_sidecar} takeout media files without a usable json sidecar");
+    }
+    info!(
+        "takeout: {} media items, {} albums",
+        result.media_items.len(),
+        result.albums.len()
+    );
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_sidecar_key() {
+        assert_eq!(
+            sidecar_key("IMG_1234.jpg.json"),
+            ("IMG_1234.jpg".to_string(), None)
+        );
+        assert_eq!(
+            sidecar_key("IMG_1234.jpg(1).json"),
+            ("IMG_1234.jpg".to_string(), Some(1))
+        );
+        assert_eq!(
+            sidecar_key("IMG_1234.jpg.supplemental-metadata.json"),
+            ("IMG_1234.jpg".to_string(), None)
+        );
+        assert_eq!(
+            sidecar_key("IMG_1234.jpg.supplemental-met(2).json"),
+            ("IMG_1234.jpg".to_string(), Some(2))
+        );
+        assert_eq!(
+            media_key("IMG_1234(2).jpg"),
+            ("IMG_1234.jpg".to_string(), Some(2))
+        );
+        assert_eq!(
+            media_key("IMG_1234.jpg"),
+            ("IMG_1234.jpg".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn test_scan_zip() {
+        let path = std::env::temp_dir().join(format!("takeout-test-{}.zip", std::process::id()));
+        {
+            let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+            let opts = zip::write::SimpleFileOptions::default();
+            let sidecar = r#"{"title":"IMG_1.jpg","description":"","photoTakenTime":{"timestamp":"1720461810","formatted":""},"geoData":{"latitude":46.7,"longitude":10.1,"altitude":0.0},"url":"https://photos.google.com/photo/AF1"}"#;
+            for folder in [
+                "Takeout/Google Photos/Photos from 2024",
+                "Takeout/Google Photos/Trip",
+            ] {
+                zip.start_file(format!("{folder}/IMG_1.jpg"), opts).unwrap();
+                zip.write_all(b"jpeg bytes").unwrap();
+                zip.start_file(format!("{folder}/IMG_1.jpg.json"), opts)
+                    .unwrap();
+                zip.write_all(sidecar.as_bytes()).unwrap();
+            }
+            zip.start_file("Takeout/Google Photos/Trip/metadata.json", opts)
+                .unwrap();
+            zip.write_all(br#"{"title":"Trip to Zernez"}"#).unwrap();
+            zip.start_file("Takeout/Google Photos/Trip/orphan.mp4", opts)
+                .unwrap();
+            zip.write_all(b"no sidecar").unwrap();
+            zip.finish().unwrap();
+        }
+        let r = scan_archives(std::slice::from_ref(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let id = GPhotoItemId("takeout:https://photos.google.com/photo/AF1".to_string());
+        assert_eq!(r.media_items.len(), 1);
+        assert_eq!(r.albums.len(), 1);
+        let album = r.albums.values().next().unwrap();
+        assert_eq!(album.title.as_deref(), Some("Trip to Zernez"));
+        assert!(r.associations.values().next().unwrap().contains(&id));
+        let item = &r.media_items[&id];
+        assert_eq!(
+            item.media_metadata
+                .as_ref()
+                .unwrap()
+                .creation_time
+                .as_deref(),
+            Some("2024-07-08T18:03:30+00:00")
+        );
+        assert!(r.sidecars[&id].location().is_some());
+    }
+
+    #[test]
+    fn test_copy_from_tgz() {
+        let path = std::env::temp_dir().join(format!("takeout-test-{}.tgz", std::process::id()));
+        let files: Vec<_> = (0..5)
+            .map(|i| {
+                (
+                    format!("Takeout/Google Photos/Photos from 2024/IMG_{i}.jpg"),
+                    vec![i as u8; 1000 * i + 1],
+                )
+            })
+            .collect();
+        {
+            let gz = flate2::write::GzEncoder::new(
+                File::create(&path).unwrap(),
+                flate2::Compression::default(),
+            );
+            let mut tar = tar::Builder::new(gz);
+            for (name, data) in &files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                tar.append_data(&mut header, name, &data[..]).unwrap();
+            }
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+        scan_archives(std::slice::from_ref(&path)).unwrap();
+
+        let locations: Vec<_> = files
+            .iter()
+            .map(|(name, _)| {
+                (
+                    name.clone(),
+                    MediaLocation {
+                        archive: path.clone(),
+                        entry: name.clone(),
+                    },
+                )
+            })
+            .collect();
+        let order = read_order(&locations).unwrap();
+        assert!(order[&files[1].0] < order[&files[2].0]);
+        // In order, then backwards which needs new cursors.
+        for (name, data) in files.iter().chain(files.iter().rev()) {
+            let mut buf = vec![];
+            let location = &locations.iter().find(|(n, _)| n == name).unwrap().1;
+            assert_eq!(location.copy_to(&mut buf).unwrap(), data.len() as u64);
+            assert_eq!(location.size().unwrap(), data.len() as u64);
+            assert_eq!(&buf, data);
+        }
+        let missing = MediaLocation {
+            archive: path.clone(),
+            entry: "missing.jpg".to_string(),
+        };
+        assert!(missing.copy_to(&mut vec![]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+// Limits for one kind of work (e.g. downloads): how many can run at the same time, and optionally
+// how many bytes per second they may move together.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    concurrency: Arc<Semaphore>,
+    bandwidth: Option<Arc<Bandwidth>>,
+}
+
+impl Throttle {
+    pub fn new(concurrency: usize, bytes_per_sec: Option<u64>) -> Self {
+        Throttle {
+            concurrency: Arc::new(Semaphore::new(concurrency.max(1))),
+            bandwidth: bytes_per_sec.map(|r| Arc::new(Bandwidth::new(r))),
+        }
+    }
+    // Waits for a free slot, which is held until the permit is dropped.
+    pub async fn permit(&self) -> SemaphorePermit<'_> {
+        self.concurrency.acquire().await.unwrap()
+    }
+    // Waits until `n` more bytes may be moved.
+    pub async fn consume(&self, n: usize) {
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.consume(n).await;
+        }
+    }
+    // Passes the stream through the bandwidth limit.
+    pub fn limit<S>(&self, s: S) -> impl Stream<Item = std::io::Result<Bytes>>
+    where
+        S: Stream<Item = std::io::Result<Bytes>>,
+    {
+        let throttle = self.clone();
+        s.then(move |chunk| {
+            let throttle = throttle.clone();
+            async move {
+                if let Ok(chunk) = &chunk {
+                    throttle.consume(chunk.len()).await;
+                }
+                chunk
+            }
+        })
+    }
+}
+
+// Token bucket that holds up to one second worth of bytes. Callers that take more than is there
+// go into debt and wait until it is paid off.
+#[derive(Debug)]
+struct Bandwidth {
+    bytes_per_sec: f64,
+    // Bytes that can be moved right now (negative when in debt) and when that was computed.
+    available: Mutex<(f64, Instant)>,
+}
+
+impl Bandwidth {
+    fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Bandwidth {
+            bytes_per_sec,
+            available: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+    async fn consume(&self, n: usize) {
+        let wait = {
+            let mut available = self.available.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(available.1).as_secs_f64() * self.bytes_per_sec;
+            available.0 = (available.0 + refill).min(self.bytes_per_sec) - n as f64;
+            available.1 = now;
+            if available.0 < 0.0 {
+                Duration::from_secs_f64(-available.0 / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// Cuts `bytes` into chunks so that a limited stream doesn't send it all at once.
+pub fn chunked(bytes: Bytes, chunk_size: usize) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let chunk_size = chunk_size.max(1);
+    stream::iter(
+        (0..bytes.len())
+            .step_by(chunk_size)
+            .map(move |i| Ok(bytes.slice(i..(i + chunk_size).min(bytes.len())))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bandwidth() {
+        let throttle = Throttle::new(1, Some(10000));
+        let start = Instant::now();
+        // The first second worth of bytes goes right away.
+        throttle.consume(10000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // Then it's 10000 bytes per second.
+        let chunks: Vec<_> = throttle
+            .limit(chunked(Bytes::from(vec![0; 2000]), 500))
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 4);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::{anyhow, Result};
+use std::io::{Read, Seek, SeekFrom};
+
+// Just enough of an MP4 / QuickTime parser to tell what Google sent when downloading a video with
+// `=dv`: the container brand, duration, and the size and frame rate of the video track. Google
+// sends a transcode rather than the original, which shows as a different container, a lower
+// resolution or a different frame rate than the original had.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VideoInfo {
+    // Major brand from the ftyp box, "qt  " for QuickTime.
+    pub brand: Option<String>,
+    // Seconds.
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f64>,
+}
+
+struct BoxHeader {
+    kind: [u8; 4],
+    // Where the payload starts and the box ends.
+    start: u64,
+    end: u64,
+}
+
+fn read_u32<R: Read + ?Sized>(r: &mut R) -> Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read + ?Sized>(r: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+// Calls `f` for each box between `start` and `end`.
+fn for_each_box<R, F>(r: &mut R, start: u64, end: u64, mut f: F) -> Result<()>
+where
+    R: Read + Seek + ?Sized,
+    F: FnMut(&mut R, &BoxHeader) -> Result<()>,
+{
+    let mut pos = start;
+    while pos + 8 <= end {
+        r.seek(SeekFrom::Start(pos))?;
+        let size = read_u32(r)? as u64;
+        let mut kind = [0; 4];
+        r.read_exact(&mut kind)?;
+        let (header_len, size) = match size {
+            0 => (8, end - pos),
+            1 => (16, read_u64(r)?),
+            size => (8, size),
+        };
+        if size < header_len || pos + size > end {
+            return Err(anyhow!("bad {} box", String::from_utf8_lossy(&kind)));
+        }
+        let header = BoxHeader {
+            kind,
+            start: pos + header_len,
+            end: pos + size,
+        };
+        f(r, &header)?;
+        pos = header.end;
+    }
+    Ok(())
+}
+
+// Timescale and duration from an mvhd or mdhd box.
+fn read_time<R: Read + Seek + ?Sized>(r: &mut R, header: &BoxHeader) -> Result<(u32, u64)> {
+    r.seek(SeekFrom::Start(header.start))?;
+    let version = read_u32(r)? >> 24;
+    if version == 1 {
+        r.seek(SeekFrom::Current(16))?;
+        Ok((read_u32(r)?, read_u64(r)?))
+    } else {
+        r.seek(SeekFrom::Current(8))?;
+        Ok((read_u32(r)?, read_u32(r)? as u64))
+    }
+}
+
+#[derive(Default)]
+struct Track {
+    handler: Option<[u8; 4]>,
+    width: Option<u32>,
+    height: Option<u32>,
+    // Timescale of the media and the number of samples and their total duration, from stts.
+    timescale: Option<u32>,
+    samples: u64,
+    sample_time: u64,
+}
+
+fn read_track<R: Read + Seek + ?Sized>(r: &mut R, trak: &BoxHeader) -> Result<Track> {
+    let mut track = Track::default();
+    for_each_box(r, trak.start, trak.end, |r, b| {
+        match &b.kind {
+            b"tkhd" => {
+                // Width and height are the last two fields, 16.16 fixed point.
+                r.seek(SeekFrom::Start(b.end - 8))?;
+                track.width = Some(read_u32(r)? >> 16);
+                track.height = Some(read_u32(r)? >> 16);
+            }
+            b"mdia" => for_each_box(r, b.start, b.end, |r, b| {
+                match &b.kind {
+                    b"mdhd" => track.timescale = Some(read_time(r, b)?.0),
+                    b"hdlr" => {
+                        r.seek(SeekFrom::Start(b.start + 8))?;
+                        let mut handler = [0; 4];
+                        r.read_exact(&mut handler)?;
+                        track.handler = Some(handler);
+                    }
+                    b"minf" => for_each_box(r, b.start, b.end, |r, b| {
+                        if &b.kind != b"stbl" {
+                            return Ok(());
+                        }
+                        for_each_box(r, b.start, b.end, |r, b| {
+                            if &b.kind != b"stts" {
+                                return Ok(());
+                            }
+                            r.seek(SeekFrom::Start(b.start + 4))?;
+                            for _ in 0..read_u32(r)? {
+                                let count = read_u32(r)? as u64;
+                                let delta = read_u32(r)? as u64;
+                                track.samples += count;
+                                track.sample_time += count * delta;
+                            }
+                            Ok(())
+                        })
+                    })?,
+                    _ => {}
+                }
+                Ok(())
+            })?,
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(track)
+}
+
+pub fn read_video_info<R: Read + Seek + ?Sized>(r: &mut R) -> Result<VideoInfo> {
+    let end = r.seek(SeekFrom::End(0))?;
+    let mut info = VideoInfo::default();
+    for_each_box(r, 0, end, |r, b| {
+        match &b.kind {
+            b"ftyp" => {
+                r.seek(SeekFrom::Start(b.start))?;
+                let mut brand = [0; 4];
+                r.read_exact(&mut brand)?;
+                info.brand = Some(String::from_utf8_lossy(&brand).to_string());
+            }
+            b"moov" => for_each_box(r, b.start, b.end, |r, b| {
+                match &b.kind {
+                    b"mvhd" => {
+                        let (timescale, duration) = read_time(r, b)?;
+                        if timescale > 0 {
+                            info.duration = Some(duration as f64 / timescale as f64);
+                        }
+                    }
+                    b"trak" => {
+                        let track = read_track(r, b)?;
+                        if track.handler == Some(*b"vide") && info.width.is_none() {
+                            info.width = track.width;
+                            info.height = track.height;
+                            if let Some(timescale) = track.timescale {
+                                if track.sample_time > 0 {
+                                    info.fps = Some(
+                                        track.samples as f64 * timescale as f64
+                                            / track.sample_time as f64,
+                                    );
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                Ok(())
+            })?,
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(info)
+}
+
+// Why the downloaded video is not the original, if it looks like a transcode. `file_name` and
+// `metadata` describe the original.
+pub fn transcode_reason(
+    info: &VideoInfo,
+    file_name: &str,
+    metadata: &gphotos_api::models::MediaItemMediaMetadata,
+) -> Option<String> {
+    let mut reasons = vec![];
+
+    let ext = file_name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_lowercase())
+        .unwrap_or_default();
+    if let Some(brand) = &info.brand {
+        if (ext == "mov" || ext == "qt") && brand != "qt  " {
+            reasons.push(format!("{ext} original downloaded as {}", brand.trim()));
+        }
+    }
+
+    let original = metadata
+        .width
+        .as_ref()
+        .zip(metadata.height.as_ref())
+        .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)));
+    if let (Some((ow, oh)), Some(w), Some(h)) = (original, info.width, info.height) {
+        if w.max(h) < ow.max(oh) {
+            reasons.push(format!("downscaled from {ow}x{oh} to {w}x{h}"));
+        }
+    }
+
+    let original_fps = metadata.video.as_ref().and_then(|v| v.fps);
+    if let (Some(original_fps), Some(fps)) = (original_fps, info.fps) {
+        if (original_fps - fps).abs() > 1.0 {
+            reasons.push(format!(
+                "frame rate changed from {original_fps:.2} to {fps:.2}"
+            ));
+        }
+    }
+
+    if reasons.is_empty() {
+        None
+    } else {
+        Some(reasons.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut b = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(payload);
+        b
+    }
+
+    fn words(xs: &[u32]) -> Vec<u8> {
+        xs.iter().flat_map(|x| x.to_be_bytes()).collect()
+    }
+
+    // 10 seconds, 300 frames of 1280x720.
+    fn mp4(brand: &[u8; 4]) -> Vec<u8> {
+        let mut tkhd = vec![0; 76];
+        tkhd.extend(words(&[1280 << 16, 720 << 16]));
+        let stts = mp4_box(b"stts", &words(&[0, 1, 300, 1000]));
+        let mdia = [
+            mp4_box(b"mdhd", &words(&[0, 0, 0, 30000, 300000])),
+            mp4_box(b"hdlr", &[words(&[0, 0]), b"vide".to_vec()].concat()),
+            mp4_box(b"minf", &mp4_box(b"stbl", &stts)),
+        ]
+        .concat();
+        let trak = [mp4_box(b"tkhd", &tkhd), mp4_box(b"mdia", &mdia)].concat();
+        let moov = [
+            mp4_box(b"mvhd", &words(&[0, 0, 0, 1000, 10000])),
+            mp4_box(b"trak", &trak),
+        ]
+        .concat();
+        [
+            mp4_box(b"ftyp", &[brand.to_vec(), words(&[0])].concat()),
+            mp4_box(b"moov", &moov),
+            mp4_box(b"mdat", &[0; 16]),
+        ]
+        .concat()
+    }
+
+    fn metadata(
+        width: &str,
+        height: &str,
+        fps: f64,
+    ) -> gphotos_api::models::MediaItemMediaMetadata {
+        serde_json::from_value(serde_json::json!({
+            "width": width,
+            "height": height,
+            "video": {"fps": fps},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_read_video_info() {
+        let info = read_video_info(&mut Cursor::new(mp4(b"mp42"))).unwrap();
+        assert_eq!(
+            info,
+            VideoInfo {
+                brand: Some("mp42".to_string()),
+                duration: Some(10.0),
+                width: Some(1280),
+                height: Some(720),
+                fps: Some(30.0),
+            }
+        );
+        assert!(read_video_info(&mut Cursor::new(b"not a video at all".to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_transcode_reason() {
+        let info = read_video_info(&mut Cursor::new(mp4(b"mp42"))).unwrap();
+        assert_eq!(
+            transcode_reason(&info, "a.mp4", &metadata("1280", "720", 30.0)),
+            None
+        );
+        assert_eq!(
+            transcode_reason(&info, "IMG_1.MOV", &metadata("1920", "1080", 60.0)).unwrap(),
+            "mov original downloaded as mp42, downscaled from 1920x1080 to 1280x720, frame rate changed from 60.00 to 30.00"
+        );
+        let info = read_video_info(&mut Cursor::new(mp4(b"qt  "))).unwrap();
+        assert_eq!(
+            transcode_reason(&info, "IMG_1.MOV", &metadata("720", "1280", 30.0)),
+            None
+        );
+    }
+}